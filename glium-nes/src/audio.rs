@@ -1,3 +1,4 @@
+use nesemu::apu::queue::{self, BufferSize, FillLevel};
 use snafu::{ResultExt, Snafu};
 use std::path::Path;
 use tracing::info;
@@ -10,6 +11,9 @@ pub enum AudioError {
     #[snafu(display("Error while getting SDL Audio Subsystem = {}", msg))]
     CannotGetAudioSystem { msg: String },
 
+    #[snafu(display("Invalid audio buffer = {}", msg))]
+    InvalidBufferSize { msg: String },
+
     #[snafu(display("Cannot open SDL audio queue = {}", msg))]
     CannotOpenQueue { msg: String },
 
@@ -29,17 +33,26 @@ pub struct AudioSystem {
     /// Queue samples to play on the computer audio
     queue: sdl2::audio::AudioQueue<i16>,
 
+    /// Sample rate of the audio device.
+    sample_rate: u32,
+
+    /// Number of samples to keep in the queue.
+    fill_level: FillLevel,
+
     /// Add samples to save to the wav file.
     wav_writer: Option<hound::WavWriter<std::io::BufWriter<std::fs::File>>>,
 }
 
 impl AudioSystem {
     /// Will initialize the audio system as well as the wav recorder.
-    pub fn with_recording<P: AsRef<Path>>(recording_name: P) -> Result<Self, AudioError> {
-        let mut system = AudioSystem::init()?;
+    pub fn with_recording<P: AsRef<Path>>(
+        recording_name: P,
+        buffer_size: BufferSize,
+    ) -> Result<Self, AudioError> {
+        let mut system = AudioSystem::init(buffer_size)?;
         let specs = hound::WavSpec {
             channels: 1,
-            sample_rate: 44100,
+            sample_rate: system.sample_rate,
             bits_per_sample: 16,
             sample_format: hound::SampleFormat::Int,
         };
@@ -52,19 +65,22 @@ impl AudioSystem {
         Ok(system)
     }
 
-    /// Will initialize the audio system
-    pub fn init() -> Result<Self, AudioError> {
+    /// Will initialize the audio system. A bigger buffer adds latency but
+    /// the sound is less likely to crackle.
+    pub fn init(buffer_size: BufferSize) -> Result<Self, AudioError> {
         let context = sdl2::init().map_err(|msg| AudioError::CannotInitSdl2 { msg })?;
         let audio_subsystem = context
             .audio()
             .map_err(|msg| AudioError::CannotGetAudioSystem { msg })?;
 
         let freq: i32 = 44100;
-        let samples: u16 = 1024;
+        let samples = buffer_size
+            .samples(freq as u32)
+            .map_err(|msg| AudioError::InvalidBufferSize { msg })?;
         let channels: u8 = 1;
         let desired_specs = sdl2::audio::AudioSpecDesired {
             freq: Some(freq),
-            samples: Some(samples),
+            samples: Some(samples as u16),
             channels: Some(channels),
         };
 
@@ -72,25 +88,42 @@ impl AudioSystem {
             .open_queue::<i16, _>(None, &desired_specs)
             .map_err(|msg| AudioError::CannotOpenQueue { msg })?;
 
-        info!(msg = "Created SDL audio queue", freq = %freq, samples = %samples, channels = %channels);
+        // The device might not use the frequency we asked for.
+        let sample_rate = queue.spec().freq as u32;
+        info!(msg = "Created SDL audio queue", freq = %sample_rate, samples = %samples, channels = %channels);
 
         Ok(Self {
             _context: context,
             queue,
+            sample_rate,
+            fill_level: FillLevel::for_buffer(samples),
             wav_writer: None,
         })
     }
 
+    /// Sample rate of the audio device (Hz).
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Number of samples waiting to be played.
+    pub fn queued_samples(&self) -> u32 {
+        self.queue.size() / std::mem::size_of::<i16>() as u32
+    }
+
     /// Start playing.
     pub fn resume(&self) {
         self.queue.resume();
     }
 
-    /// Play (and record) the samples
+    /// Play (and record) the samples. A few samples can be dropped or
+    /// duplicated to keep the queue around its target size.
     pub fn process_samples(&mut self, samples: &[i16]) -> Result<(), AudioError> {
+        let correction = self.fill_level.correction(self.queued_samples());
+        let samples = queue::apply(samples, correction);
         self.queue.queue(&samples);
         if let Some(ref mut writer) = self.wav_writer.as_mut() {
-            for sample in samples {
+            for sample in &samples {
                 writer
                     .write_sample(*sample)
                     .context(CannotRecordSample {})?;
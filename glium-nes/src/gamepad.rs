@@ -0,0 +1,153 @@
+// Gamepad support with gilrs. Buttons and the left stick are mapped to the
+// controller of player 1, like the keyboard.
+use gilrs::{Axis, Button, EventType, Gilrs};
+use nesemu::{
+    graphic::EmulatorInput,
+    joypad::{InputAction, InputState, Player},
+};
+use std::collections::HashMap;
+
+/// Stick values between -threshold and threshold do not press any direction.
+pub const DEFAULT_AXIS_THRESHOLD: f32 = 0.5;
+
+/// Mapping from the gamepad to the NES controller.
+pub struct GamepadConfig {
+    pub buttons: HashMap<Button, InputAction>,
+    pub axis_threshold: f32,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        let mut buttons = HashMap::new();
+        buttons.insert(Button::DPadUp, InputAction::UP);
+        buttons.insert(Button::DPadDown, InputAction::DOWN);
+        buttons.insert(Button::DPadLeft, InputAction::LEFT);
+        buttons.insert(Button::DPadRight, InputAction::RIGHT);
+        buttons.insert(Button::Start, InputAction::START);
+        buttons.insert(Button::Select, InputAction::SELECT);
+        // Same position as the NES controller: B on the left, A on the right.
+        buttons.insert(Button::East, InputAction::A);
+        buttons.insert(Button::South, InputAction::B);
+
+        Self {
+            buttons,
+            axis_threshold: DEFAULT_AXIS_THRESHOLD,
+        }
+    }
+}
+
+/// Direction pressed for a value of the left stick. None in the dead zone or
+/// for other axes.
+pub fn axis_to_action(axis: Axis, value: f32, threshold: f32) -> Option<InputAction> {
+    match axis {
+        Axis::LeftStickX if value <= -threshold => Some(InputAction::LEFT),
+        Axis::LeftStickX if value >= threshold => Some(InputAction::RIGHT),
+        // Y axis is positive when the stick is pushed up.
+        Axis::LeftStickY if value >= threshold => Some(InputAction::UP),
+        Axis::LeftStickY if value <= -threshold => Some(InputAction::DOWN),
+        _ => None,
+    }
+}
+
+fn input(action: InputAction, state: InputState) -> EmulatorInput {
+    EmulatorInput::INPUT(Player::One, action, state)
+}
+
+pub struct Gamepad {
+    gilrs: Gilrs,
+    config: GamepadConfig,
+    // Direction currently pressed with the stick on each axis.
+    stick_x: Option<InputAction>,
+    stick_y: Option<InputAction>,
+}
+
+impl Gamepad {
+    pub fn init(config: GamepadConfig) -> Result<Gamepad, String> {
+        let gilrs = Gilrs::new().map_err(|err| err.to_string())?;
+        Ok(Gamepad {
+            gilrs,
+            config,
+            stick_x: None,
+            stick_y: None,
+        })
+    }
+
+    /// Add the inputs of all the gamepads since last call to `emu_events`.
+    pub fn poll_events(&mut self, emu_events: &mut Vec<EmulatorInput>) {
+        while let Some(event) = self.gilrs.next_event() {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    if let Some(action) = self.config.buttons.get(&button) {
+                        emu_events.push(input(*action, InputState::Pressed));
+                    }
+                }
+                EventType::ButtonReleased(button, _) => {
+                    if let Some(action) = self.config.buttons.get(&button) {
+                        emu_events.push(input(*action, InputState::Released));
+                    }
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    let action = axis_to_action(axis, value, self.config.axis_threshold);
+                    let current = match axis {
+                        Axis::LeftStickX => &mut self.stick_x,
+                        Axis::LeftStickY => &mut self.stick_y,
+                        _ => continue,
+                    };
+
+                    if *current != action {
+                        if let Some(previous) = current.take() {
+                            emu_events.push(input(previous, InputState::Released));
+                        }
+                        if let Some(action) = action {
+                            emu_events.push(input(action, InputState::Pressed));
+                        }
+                        *current = action;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stick_past_threshold() {
+        assert_eq!(
+            Some(InputAction::LEFT),
+            axis_to_action(Axis::LeftStickX, -0.8, DEFAULT_AXIS_THRESHOLD)
+        );
+        assert_eq!(
+            Some(InputAction::RIGHT),
+            axis_to_action(Axis::LeftStickX, 0.8, DEFAULT_AXIS_THRESHOLD)
+        );
+        assert_eq!(
+            Some(InputAction::UP),
+            axis_to_action(Axis::LeftStickY, 1.0, DEFAULT_AXIS_THRESHOLD)
+        );
+        assert_eq!(
+            Some(InputAction::DOWN),
+            axis_to_action(Axis::LeftStickY, -0.5, DEFAULT_AXIS_THRESHOLD)
+        );
+    }
+
+    #[test]
+    fn stick_in_dead_zone() {
+        assert_eq!(
+            None,
+            axis_to_action(Axis::LeftStickX, -0.2, DEFAULT_AXIS_THRESHOLD)
+        );
+        assert_eq!(
+            None,
+            axis_to_action(Axis::LeftStickY, 0.49, DEFAULT_AXIS_THRESHOLD)
+        );
+        // Right stick is not used.
+        assert_eq!(
+            None,
+            axis_to_action(Axis::RightStickX, -1.0, DEFAULT_AXIS_THRESHOLD)
+        );
+    }
+}
@@ -9,18 +9,13 @@ use glium::glutin;
 use glium::Surface;
 use snafu::{ResultExt, Snafu};
 use std::time::Instant;
-use tracing::{error, info};
+use tracing::info;
 
 use imgui::{Context, FontConfig, FontGlyphRanges, FontSource, Ui};
 use imgui_glium_renderer::Renderer;
 use imgui_winit_support::{HiDpiMode, WinitPlatform};
-use nesemu::{
-    graphic::Color,
-    nes::Nes,
-    ppu::palette::{build_default_colors, load_palette},
-};
+use nesemu::nes::Nes;
 use std::io::Cursor;
-use std::path::Path;
 
 #[derive(Copy, Clone)]
 struct Vertex {
@@ -65,7 +60,6 @@ pub enum GraphicError {
 
 /// Will hold all the data related to glium and imgui.
 pub struct GraphicSystem {
-    colors: [Color; 64],
     display: glium::Display,
 
     pub imgui: Context,
@@ -81,10 +75,7 @@ pub struct GraphicSystem {
 }
 
 impl GraphicSystem {
-    pub fn init<P: AsRef<Path>>(
-        palette: Option<P>,
-        events_loop: &glutin::EventsLoop,
-    ) -> Result<Self, GraphicError> {
+    pub fn init(events_loop: &glutin::EventsLoop) -> Result<Self, GraphicError> {
         info!("Initialize GraphicSystem");
 
         info!("Initialize glium display");
@@ -205,24 +196,7 @@ uniform sampler2D tex;
                 .context(CannotCreateProgram {})?;
         let last_frame = Instant::now();
 
-        let colors = if let Some(path) = palette {
-            let path = path.as_ref();
-            match load_palette(&path) {
-                Ok(p) => {
-                    info!("Will use palette {}", path.display());
-                    p
-                }
-                Err(e) => {
-                    error!("Error loading {} = {}", path.display(), e);
-                    build_default_colors()
-                }
-            }
-        } else {
-            build_default_colors()
-        };
-
         Ok(Self {
-            colors,
             last_frame,
             display,
             platform,
@@ -237,19 +211,12 @@ uniform sampler2D tex;
 
     /// Will render the full NES frame to a texture
     pub fn render_nes_frame(&mut self, nes: &Nes) -> Result<(), GraphicError> {
-        let mut frame = image::ImageBuffer::new(256, 240);
-        let dimensions = frame.dimensions();
-        for x in 0..256u32 {
-            for y in 0..240u32 {
-                let pixel = nes.get_pixel(y as usize, x as usize) as usize;
-                let color = self.colors[pixel];
-                let pixel = frame.get_pixel_mut(x, y);
-                *pixel = image::Rgb([color.r, color.g, color.b]);
-            }
-        }
+        let dimensions = (nes.width() as u32, nes.height() as u32);
+        let mut frame = vec![0; nes.width() * nes.height() * 3];
+        // Buffer has the correct size so this cannot fail.
+        nes.frame_rgb(&mut frame).unwrap();
 
-        let image =
-            glium::texture::RawImage2d::from_raw_rgb_reversed(&frame.into_raw(), dimensions);
+        let image = glium::texture::RawImage2d::from_raw_rgb_reversed(&frame, dimensions);
         self.texture =
             glium::texture::Texture2d::new(&self.display, image).context(CannotCreateTexture {})?;
 
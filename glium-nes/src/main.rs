@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
-use tracing::{error, info, info_span, trace};
+use tracing::{error, info, info_span, trace, warn};
 #[macro_use]
 extern crate glium;
 use glium::glutin;
@@ -9,15 +9,18 @@ use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
 use nesemu::{
+    apu::queue,
+    config::Config,
     graphic::EmulatorInput,
     joypad::{InputAction, InputState, Player},
     nes::Nes,
-    rom,
 };
 mod graphics;
 mod ui;
 use ui::{Application, UiEvent};
 mod audio;
+mod gamepad;
+mod stats;
 
 fn build_default_input_p1() -> HashMap<VirtualKeyCode, InputAction> {
     let mut m = HashMap::new();
@@ -34,6 +37,8 @@ fn build_default_input_p1() -> HashMap<VirtualKeyCode, InputAction> {
     m
 }
 
+// Keys of player 2 must not be in the player 1 map, otherwise a key press is
+// sent to both controllers. Only map a key to both players on purpose.
 fn build_default_input_p2() -> HashMap<VirtualKeyCode, InputAction> {
     let mut m = HashMap::new();
     m.insert(VirtualKeyCode::I, InputAction::UP);
@@ -48,8 +53,100 @@ fn build_default_input_p2() -> HashMap<VirtualKeyCode, InputAction> {
     m
 }
 
+// Keys that can be used in the config file, by their winit name.
+const NAMED_KEYS: &[VirtualKeyCode] = &[
+    VirtualKeyCode::A,
+    VirtualKeyCode::B,
+    VirtualKeyCode::C,
+    VirtualKeyCode::D,
+    VirtualKeyCode::E,
+    VirtualKeyCode::F,
+    VirtualKeyCode::G,
+    VirtualKeyCode::H,
+    VirtualKeyCode::I,
+    VirtualKeyCode::J,
+    VirtualKeyCode::K,
+    VirtualKeyCode::L,
+    VirtualKeyCode::M,
+    VirtualKeyCode::N,
+    VirtualKeyCode::O,
+    VirtualKeyCode::P,
+    VirtualKeyCode::Q,
+    VirtualKeyCode::R,
+    VirtualKeyCode::S,
+    VirtualKeyCode::T,
+    VirtualKeyCode::U,
+    VirtualKeyCode::V,
+    VirtualKeyCode::W,
+    VirtualKeyCode::X,
+    VirtualKeyCode::Y,
+    VirtualKeyCode::Z,
+    VirtualKeyCode::Key0,
+    VirtualKeyCode::Key1,
+    VirtualKeyCode::Key2,
+    VirtualKeyCode::Key3,
+    VirtualKeyCode::Key4,
+    VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6,
+    VirtualKeyCode::Key7,
+    VirtualKeyCode::Key8,
+    VirtualKeyCode::Key9,
+    VirtualKeyCode::Up,
+    VirtualKeyCode::Down,
+    VirtualKeyCode::Left,
+    VirtualKeyCode::Right,
+    VirtualKeyCode::Space,
+    VirtualKeyCode::Return,
+    VirtualKeyCode::Back,
+    VirtualKeyCode::Tab,
+    VirtualKeyCode::LShift,
+    VirtualKeyCode::RShift,
+    VirtualKeyCode::LControl,
+    VirtualKeyCode::RControl,
+    VirtualKeyCode::LAlt,
+    VirtualKeyCode::RAlt,
+];
+
+/// Key map from the config file. Keep the default mapping if the config
+/// does not have one.
+fn key_map(
+    keys: Option<&HashMap<String, InputAction>>,
+    default: HashMap<VirtualKeyCode, InputAction>,
+) -> HashMap<VirtualKeyCode, InputAction> {
+    match keys {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|(name, action)| {
+                let key = NAMED_KEYS.iter().find(|key| format!("{:?}", key) == *name);
+                if key.is_none() {
+                    warn!("Unknown key {} in config", name);
+                }
+                key.map(|key| (*key, *action))
+            })
+            .collect(),
+        None => default,
+    }
+}
+
 const CPU_CYCLES_PER_FRAME: u64 = 29_780;
 
+/// Show/hide the FPS overlay.
+const OVERLAY_KEY: VirtualKeyCode = VirtualKeyCode::F3;
+
+/// Save the current frame to screenshots/.
+const SCREENSHOT_KEY: VirtualKeyCode = VirtualKeyCode::F12;
+
+/// Use the palette from the command line if any. Default palette is kept on
+/// error.
+fn apply_palette(nes: &mut Nes, palette: &Option<PathBuf>) {
+    if let Some(path) = palette {
+        match nes.load_palette(path) {
+            Ok(_) => info!("Will use palette {}", path.display()),
+            Err(e) => error!("Error loading {} = {}", path.display(), e),
+        }
+    }
+}
+
 macro_rules! timed_block {
     ($content:expr, $e:expr) => {
         {
@@ -69,6 +166,10 @@ macro_rules! timed_block {
 #[derive(Debug, StructOpt)]
 #[structopt(name = "NES emulator (glium version)", about = "NES emulator with GUI")]
 struct Opt {
+    /// JSON config file. Options given on the command line win over it.
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
     /// Can provide the rom from the CLI
     #[structopt(parse(from_os_str))]
     input: Option<PathBuf>,
@@ -81,9 +182,23 @@ struct Opt {
     #[structopt(long = "no-sound")]
     no_sound: bool,
 
+    /// Size of the audio buffer, in samples (1024) or in milliseconds
+    /// (20ms). Bigger is more stable but adds latency. Default is 1024.
+    #[structopt(long = "audio-buffer")]
+    audio_buffer: Option<String>,
+
     /// Choose the palette file. Will use default palette if absent.
-    #[structopt(long = "palette", parse(from_os_str))]
-    palette: Option<PathBuf>,
+    #[structopt(long = "palette")]
+    palette: Option<String>,
+
+    /// Playback speed. 0.25 for slow motion, 2 for fast forward. Default
+    /// is 1.0.
+    #[structopt(long = "speed")]
+    speed: Option<f64>,
+
+    /// Read the first player inputs from gamepads as well.
+    #[structopt(long = "gamepad")]
+    gamepad: bool,
 }
 
 fn main() {
@@ -95,27 +210,63 @@ fn main() {
     let opt = Opt::from_args();
     info!("Will start with {:?}", opt);
 
+    let file_config = opt
+        .config
+        .as_ref()
+        .map(|path| Config::load(path).expect("Cannot read config file"))
+        .unwrap_or_default();
+    let cli_config = Config {
+        palette: opt.palette,
+        no_sound: if opt.no_sound { Some(true) } else { None },
+        audio_buffer: opt.audio_buffer,
+        speed: opt.speed,
+        ..Config::default()
+    };
+    let config = file_config.merge(cli_config);
+    if config.scale.is_some() {
+        warn!("scale is not supported by the glium front-end, ignored");
+    }
+    let audio_buffer = config
+        .buffer_size()
+        .expect("Audio buffer should be like 1024 or 20ms");
+
     // 1. INITIALIZE BASIC SYSTEMS (AUDIO + GRAPHICS)
     // ----------------------------------------------------------
     let mut audio = if let Some(recording_name) = opt.recording_name {
-        audio::AudioSystem::with_recording(recording_name)
+        audio::AudioSystem::with_recording(recording_name, audio_buffer)
     } else {
-        audio::AudioSystem::init()
+        audio::AudioSystem::init(audio_buffer)
     }
     .expect("Cannot initialize audio system");
 
-    if !opt.no_sound {
+    if !config.no_sound.unwrap_or(false) {
         audio.resume();
     }
 
+    let mut gamepad = if opt.gamepad {
+        match gamepad::Gamepad::init(gamepad::GamepadConfig::default()) {
+            Ok(gamepad) => Some(gamepad),
+            Err(e) => {
+                error!("Cannot initialize gamepad = {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let mut events_loop = glutin::EventsLoop::new();
-    let mut graphic_system = graphics::GraphicSystem::init(opt.palette, &events_loop)
-        .expect("Cannot initialize graphic system");
+    let mut graphic_system =
+        graphics::GraphicSystem::init(&events_loop).expect("Cannot initialize graphic system");
 
     // 2. INITIALIZE APPLICATION STATE
     // ------------------------------------------------------------
-    let input_map_p1 = build_default_input_p1();
-    let input_map_p2 = build_default_input_p2();
+    let palette = config.palette.map(PathBuf::from);
+    let speed = config.speed.unwrap_or(1.0);
+    let region = config.region;
+    let sample_rate = audio.sample_rate();
+    let input_map_p1 = key_map(config.keys_p1.as_ref(), build_default_input_p1());
+    let input_map_p2 = key_map(config.keys_p2.as_ref(), build_default_input_p2());
     let mut application = Application::default();
     let fixed_time_stamp = Duration::new(0, 16666667);
 
@@ -123,29 +274,47 @@ fn main() {
     // ------------------------------------------------------------
     // now load the nes emulator.
     let mut nes = if let Some(rom) = opt.input {
-        let ines = rom::read(rom).unwrap();
-        let nes = Nes::new(ines).unwrap();
-        application.is_game_running = true;
-        nes
+        match Nes::from_rom_file(&rom) {
+            Ok(nes) => {
+                application.is_game_running = true;
+                nes
+            }
+            Err(e) => {
+                let msg = format!("Cannot load {}: {}", rom.display(), e);
+                error!("{}", msg);
+                application.show_error(msg);
+                Nes::empty()
+            }
+        }
     } else {
         Nes::empty()
     };
+    apply_palette(&mut nes, &palette);
+    if let Some(region) = region {
+        nes.set_region(region);
+    }
+    nes.set_sample_rate(sample_rate);
+    nes.set_speed(speed);
 
     // 4. MAIN LOOP
     // -----------------------------------------------------------
+    let mut previous_frame = Instant::now();
     while application.should_run() {
         let now = Instant::now();
+        application.stats.push_frame_time(now - previous_frame);
+        previous_frame = now;
 
         // ONE NES FRAME
         // -------------------------------------------------
+        let (nb_frames, audio_scaling) = nes.frames_to_run();
         timed_block!("NES frame", {
             if application.is_game_running {
-                let mut total_cycles = CPU_CYCLES_PER_FRAME;
-                while total_cycles > 0 {
-                    total_cycles = total_cycles.saturating_sub(nes.tick(false).unwrap());
+                for _ in 0..nb_frames {
+                    nes.run_cycles(CPU_CYCLES_PER_FRAME).unwrap();
                 }
             }
         });
+        application.stats.push_emulation_time(Instant::now() - now);
         // DISPLAY
         // --------------------------------------------------
         timed_block!("Display", {
@@ -161,10 +330,28 @@ fn main() {
                 Some(UiEvent::LoadRom) => {
                     // If can find a rom, load it. Otherwise, restore state before
                     // opening the file explorer.
-                    if let Some(rom) = application.rom_name() {
-                        let ines = rom::read(rom).unwrap();
-                        nes = Nes::new(ines).unwrap();
-                        application.is_game_running = true;
+                    // The current game keeps running if the ROM is invalid.
+                    if let Some(rom) = application.rom_name().cloned() {
+                        match Nes::from_rom_file(&rom) {
+                            Ok(new_nes) => {
+                                nes = new_nes;
+                                apply_palette(&mut nes, &palette);
+                                if let Some(region) = region {
+                                    nes.set_region(region);
+                                }
+                                nes.set_speed(speed);
+                                nes.apply_new_sound_config(
+                                    application.sound_levels.to_apu_levels(),
+                                );
+                                nes.set_sample_rate(sample_rate);
+                                application.is_game_running = true;
+                            }
+                            Err(e) => {
+                                let msg = format!("Cannot load {}: {}", rom.display(), e);
+                                error!("{}", msg);
+                                application.show_error(msg);
+                            }
+                        }
                     }
                 }
                 Some(UiEvent::SaveState) => {
@@ -175,6 +362,11 @@ fn main() {
                 Some(UiEvent::LoadState) => {
                     if let Ok(new_nes) = Nes::load_state(nes.get_save_name()) {
                         nes = new_nes;
+                        apply_palette(&mut nes, &palette);
+                        nes.set_speed(speed);
+                        // levels are not part of the save state.
+                        nes.apply_new_sound_config(application.sound_levels.to_apu_levels());
+                        nes.set_sample_rate(sample_rate);
                     } else {
                         println!("Could not load {}", nes.get_save_name());
                     }
@@ -191,10 +383,11 @@ fn main() {
         // AUDIO
         // --------------------------------------------------------
         timed_block!("Process audio", {
-            let samples = nes.audio_samples();
+            let samples = queue::stretch(&nes.audio_samples(), audio_scaling);
             if let Err(e) = audio.process_samples(&samples) {
                 error!("something happened when processing audio samples = {}", e);
             }
+            application.stats.audio_queued_samples = audio.queued_samples();
         });
 
         // EVENT HANDLING
@@ -211,6 +404,14 @@ fn main() {
                         glutin::WindowEvent::KeyboardInput { input, .. } => {
                             if let Some(key) = input.virtual_keycode {
                                 if ElementState::Pressed == input.state {
+                                    if key == OVERLAY_KEY {
+                                        application.toggle_overlay();
+                                    }
+
+                                    if key == SCREENSHOT_KEY {
+                                        emu_events.push(EmulatorInput::SCREENSHOT);
+                                    }
+
                                     if let Some(action) = input_map_p1.get(&key) {
                                         emu_events.push(EmulatorInput::INPUT(
                                             Player::One,
@@ -249,6 +450,9 @@ fn main() {
                     _ => (),
                 }
             });
+            if let Some(gamepad) = gamepad.as_mut() {
+                gamepad.poll_events(&mut emu_events);
+            }
             nes.handle_events(emu_events);
         });
 
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of frames used to compute the averages (one second at 60 FPS).
+const WINDOW_SIZE: usize = 60;
+
+/// Average of the last `capacity` durations.
+#[derive(Debug)]
+pub struct MovingAverage {
+    durations: VecDeque<Duration>,
+    capacity: usize,
+    total: Duration,
+}
+
+impl MovingAverage {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            durations: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            total: Duration::new(0, 0),
+        }
+    }
+
+    pub fn push(&mut self, duration: Duration) {
+        if self.durations.len() == self.capacity {
+            if let Some(oldest) = self.durations.pop_front() {
+                self.total -= oldest;
+            }
+        }
+        self.durations.push_back(duration);
+        self.total += duration;
+    }
+
+    /// None if no duration was pushed yet.
+    pub fn average(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            None
+        } else {
+            Some(self.total / self.durations.len() as u32)
+        }
+    }
+}
+
+/// Timings displayed in the overlay.
+#[derive(Debug)]
+pub struct FrameStats {
+    /// Time between the start of two frames of the main loop.
+    frame_time: MovingAverage,
+    /// Time spent emulating the NES for one frame.
+    emulation_time: MovingAverage,
+    /// Number of samples waiting in the audio queue.
+    pub audio_queued_samples: u32,
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self {
+            frame_time: MovingAverage::new(WINDOW_SIZE),
+            emulation_time: MovingAverage::new(WINDOW_SIZE),
+            audio_queued_samples: 0,
+        }
+    }
+}
+
+impl FrameStats {
+    pub fn push_frame_time(&mut self, duration: Duration) {
+        self.frame_time.push(duration);
+    }
+
+    pub fn push_emulation_time(&mut self, duration: Duration) {
+        self.emulation_time.push(duration);
+    }
+
+    /// Frames per second computed from the average frame time.
+    pub fn fps(&self) -> f64 {
+        match self.frame_time.average() {
+            Some(avg) if avg > Duration::new(0, 0) => 1.0 / as_secs(avg),
+            _ => 0.0,
+        }
+    }
+
+    /// Average emulation time for one frame in milliseconds.
+    pub fn emulation_ms(&self) -> f64 {
+        self.emulation_time
+            .average()
+            .map(|avg| as_secs(avg) * 1000.0)
+            .unwrap_or(0.0)
+    }
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moving_average_drops_old_values() {
+        let mut avg = MovingAverage::new(2);
+        assert_eq!(None, avg.average());
+        avg.push(Duration::from_millis(10));
+        assert_eq!(Some(Duration::from_millis(10)), avg.average());
+        avg.push(Duration::from_millis(20));
+        assert_eq!(Some(Duration::from_millis(15)), avg.average());
+        avg.push(Duration::from_millis(40));
+        assert_eq!(Some(Duration::from_millis(30)), avg.average());
+    }
+
+    #[test]
+    fn fps_from_frame_times() {
+        let mut stats = FrameStats::default();
+        assert_eq!(0.0, stats.fps());
+
+        for _ in 0..WINDOW_SIZE {
+            stats.push_frame_time(Duration::from_millis(20));
+        }
+        assert!((stats.fps() - 50.0).abs() < 1e-6);
+
+        // Older frames are forgotten.
+        for _ in 0..WINDOW_SIZE {
+            stats.push_frame_time(Duration::from_micros(16_667));
+        }
+        assert!((stats.fps() - 60.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn emulation_time_in_ms() {
+        let mut stats = FrameStats::default();
+        stats.push_emulation_time(Duration::from_millis(4));
+        stats.push_emulation_time(Duration::from_millis(6));
+        assert!((stats.emulation_ms() - 5.0).abs() < 1e-6);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::stats::FrameStats;
 use imgui::*;
 use nesemu::apu::ApuLevels;
 use std::borrow::Cow;
@@ -20,6 +21,27 @@ pub struct Application {
     pub sound_levels: Levels,
     // the one being modified.
     dirty_sound_levels: Levels,
+
+    // FPS and timings, displayed in an overlay.
+    show_overlay: bool,
+    pub stats: FrameStats,
+
+    // Shown in a window until the user closes it.
+    error_message: Option<String>,
+}
+
+/// Maximum value of the sliders in the audio config window.
+const SLIDER_MAX: i32 = 100;
+/// Range of the master level expected by the APU.
+const MASTER_LEVEL_MAX: f64 = 10_000.0;
+/// Range of the channel levels expected by the APU.
+const CHANNEL_LEVEL_MAX: f64 = 1.0;
+
+/// Map a slider value (0..=100) to the range [0, max]. Out of range values
+/// are clamped.
+fn slider_to_level(value: i32, max: f64) -> f64 {
+    let value = value.max(0).min(SLIDER_MAX);
+    value as f64 / SLIDER_MAX as f64 * max
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -33,10 +55,10 @@ pub struct Levels {
 impl Levels {
     pub fn to_apu_levels(&self) -> ApuLevels {
         let mut levels = ApuLevels::default();
-        levels.set_master_level(self.master as f64 * 100.0);
-        levels.set_pulse1_level(self.pulse_1 as f64 / 100.0);
-        levels.set_pulse2_level(self.pulse_2 as f64 / 100.0);
-        levels.set_triangle_level(self.triangle as f64 / 100.0);
+        levels.set_master_level(slider_to_level(self.master, MASTER_LEVEL_MAX));
+        levels.set_pulse1_level(slider_to_level(self.pulse_1, CHANNEL_LEVEL_MAX));
+        levels.set_pulse2_level(slider_to_level(self.pulse_2, CHANNEL_LEVEL_MAX));
+        levels.set_triangle_level(slider_to_level(self.triangle, CHANNEL_LEVEL_MAX));
         levels
     }
 }
@@ -44,10 +66,10 @@ impl Levels {
 impl Default for Levels {
     fn default() -> Self {
         Self {
-            master: 100,
-            pulse_1: 100,
-            pulse_2: 100,
-            triangle: 100,
+            master: SLIDER_MAX,
+            pulse_1: SLIDER_MAX,
+            pulse_2: SLIDER_MAX,
+            triangle: SLIDER_MAX,
         }
     }
 }
@@ -63,6 +85,11 @@ impl Default for Application {
             sound_config_opened: false,
             sound_levels: Levels::default(),
             dirty_sound_levels: Levels::default(),
+
+            show_overlay: false,
+            stats: FrameStats::default(),
+
+            error_message: None,
         }
     }
 }
@@ -81,6 +108,17 @@ impl Application {
     pub fn exit(&mut self) {
         self.is_running = false;
     }
+
+    /// Show or hide the FPS overlay.
+    pub fn toggle_overlay(&mut self) {
+        self.show_overlay = !self.show_overlay;
+    }
+
+    /// Display an error to the user, for example a ROM that cannot be
+    /// loaded.
+    pub fn show_error(&mut self, message: String) {
+        self.error_message = Some(message);
+    }
 }
 #[derive(Default)]
 pub struct FileExplorer {
@@ -200,17 +238,28 @@ pub fn run_ui(ui: &Ui, application: &mut Application) -> Option<UiEvent> {
             });
     }
 
+    if let Some(message) = application.error_message.clone() {
+        Window::new(im_str!("Error"))
+            .always_auto_resize(true)
+            .build(&ui, || {
+                ui.text(im_str!("{}", message));
+                if ui.button(im_str!("Ok"), [0.0, 0.0]) {
+                    application.error_message = None;
+                }
+            });
+    }
+
     if application.sound_config_opened {
         Window::new(im_str!("Audio config"))
             .size([600.0, 400.0], Condition::FirstUseEver)
             .build(&ui, || {
-                Slider::new(im_str!("Master"), 0..=100)
+                Slider::new(im_str!("Master"), 0..=SLIDER_MAX)
                     .build(ui, &mut application.dirty_sound_levels.master);
-                Slider::new(im_str!("Pulse 1"), 0..=100)
+                Slider::new(im_str!("Pulse 1"), 0..=SLIDER_MAX)
                     .build(ui, &mut application.dirty_sound_levels.pulse_1);
-                Slider::new(im_str!("Pulse 2"), 0..=100)
+                Slider::new(im_str!("Pulse 2"), 0..=SLIDER_MAX)
                     .build(ui, &mut application.dirty_sound_levels.pulse_2);
-                Slider::new(im_str!("Triangle"), 0..=100)
+                Slider::new(im_str!("Triangle"), 0..=SLIDER_MAX)
                     .build(ui, &mut application.dirty_sound_levels.triangle);
                 ui.separator();
                 if ui.button(im_str!("Ok"), [0.0, 0.0]) {
@@ -231,5 +280,43 @@ pub fn run_ui(ui: &Ui, application: &mut Application) -> Option<UiEvent> {
             });
     }
 
+    if application.show_overlay {
+        let stats = &application.stats;
+        Window::new(im_str!("Stats"))
+            .position([10.0, 30.0], Condition::FirstUseEver)
+            .always_auto_resize(true)
+            .build(&ui, || {
+                ui.text(im_str!("FPS: {:.1}", stats.fps()));
+                ui.text(im_str!("Emulation: {:.2} ms", stats.emulation_ms()));
+                ui.text(im_str!("Audio queue: {} samples", stats.audio_queued_samples));
+            });
+    }
+
     event
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slider_to_master_level() {
+        assert_eq!(0.0, slider_to_level(0, MASTER_LEVEL_MAX));
+        assert_eq!(5_000.0, slider_to_level(50, MASTER_LEVEL_MAX));
+        assert_eq!(10_000.0, slider_to_level(100, MASTER_LEVEL_MAX));
+    }
+
+    #[test]
+    fn slider_to_channel_level() {
+        assert_eq!(0.0, slider_to_level(0, CHANNEL_LEVEL_MAX));
+        assert_eq!(0.25, slider_to_level(25, CHANNEL_LEVEL_MAX));
+        assert_eq!(1.0, slider_to_level(100, CHANNEL_LEVEL_MAX));
+    }
+
+    #[test]
+    fn slider_out_of_range_is_clamped() {
+        assert_eq!(0.0, slider_to_level(-10, MASTER_LEVEL_MAX));
+        assert_eq!(10_000.0, slider_to_level(200, MASTER_LEVEL_MAX));
+        assert_eq!(1.0, slider_to_level(101, CHANNEL_LEVEL_MAX));
+    }
+}
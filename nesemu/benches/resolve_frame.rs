@@ -0,0 +1,25 @@
+// Compare the RGB resolution of a frame, byte per byte and packed.
+// Run with `cargo bench --bench resolve_frame`.
+use nesemu::nes::Nes;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1000;
+
+fn main() {
+    let mut nes = Nes::empty();
+    nes.run_frame().unwrap();
+
+    let mut rgb = vec![0; nes.width() * nes.height() * 3];
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        nes.frame_rgb(&mut rgb).unwrap();
+    }
+    println!("frame_rgb:        {:?} per frame", start.elapsed() / ITERATIONS);
+
+    let mut packed = vec![0; nes.width() * nes.height()];
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        nes.frame_rgb_packed(&mut packed).unwrap();
+    }
+    println!("frame_rgb_packed: {:?} per frame", start.elapsed() / ITERATIONS);
+}
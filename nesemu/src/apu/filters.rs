@@ -1,22 +1,46 @@
-//! This is quite dirty. I got the equations from blargg's comment on
-//! NES forum: http://forums.nesdev.com/viewtopic.php?p=44255#p44255
+//! Filters applied to the mixed output, to get closer to what the NES analog
+//! output sounds like. From https://wiki.nesdev.com/w/index.php/APU_Mixer, the
+//! NES has:
+//! - a first-order high-pass filter at 90Hz
+//! - another first-order high-pass filter at 440Hz
+//! - a first-order low-pass filter at 14kHz
 //!
-//! Other emulators are using different equations for some reason.
-//!
-//! TODO something better :') or at least without magic numbers.
+//! They are implemented as RC filters, so the coefficients depend on the
+//! sample rate.
 use std::default::Default;
 use std::f64::consts::PI;
 
+pub const HIGH_PASS_1_CUTOFF: f64 = 90.0;
+pub const HIGH_PASS_2_CUTOFF: f64 = 440.0;
+pub const LOW_PASS_CUTOFF: f64 = 14000.0;
+
+const DEFAULT_SAMPLE_RATE: f64 = 44100.0;
+
+/// Time constant of a RC filter with the given cutoff frequency.
+fn rc(cutoff: f64) -> f64 {
+    1.0 / (2.0 * PI * cutoff)
+}
+
 #[derive(Debug)]
 struct HighPass {
     prev_filter: f64,
     prev_sample: f64,
-    k: f64,
+    alpha: f64,
 }
 
 impl HighPass {
+    fn new(cutoff: f64, sample_rate: f64) -> Self {
+        let rc = rc(cutoff);
+        let dt = 1.0 / sample_rate;
+        Self {
+            prev_filter: 0.0,
+            prev_sample: 0.0,
+            alpha: rc / (rc + dt),
+        }
+    }
+
     fn tick(&mut self, obs: f64) -> f64 {
-        let new_filtered = self.k * self.prev_filter + obs - self.prev_sample;
+        let new_filtered = self.alpha * (self.prev_filter + obs - self.prev_sample);
         self.prev_filter = new_filtered;
         self.prev_sample = obs;
         new_filtered
@@ -26,11 +50,21 @@ impl HighPass {
 #[derive(Debug)]
 struct LowPass {
     prev_filter: f64,
+    alpha: f64,
 }
 
 impl LowPass {
+    fn new(cutoff: f64, sample_rate: f64) -> Self {
+        let rc = rc(cutoff);
+        let dt = 1.0 / sample_rate;
+        Self {
+            prev_filter: 0.0,
+            alpha: dt / (rc + dt),
+        }
+    }
+
     fn tick(&mut self, obs: f64) -> f64 {
-        let new_filter = (obs - self.prev_filter) * 0.815686;
+        let new_filter = self.prev_filter + self.alpha * (obs - self.prev_filter);
         self.prev_filter = new_filter;
         new_filter
     }
@@ -45,26 +79,73 @@ pub struct FilterChain {
 
 impl Default for FilterChain {
     fn default() -> Self {
-        Self {
-            high_pass_1: HighPass {
-                prev_filter: 0.0,
-                prev_sample: 0.0,
-                k: 0.996039,
-            },
-            high_pass_2: HighPass {
-                prev_filter: 0.0,
-                prev_sample: 0.0,
-                k: 0.999835,
-            },
-            low_pass: LowPass { prev_filter: 0.0 },
-        }
+        Self::new(DEFAULT_SAMPLE_RATE)
     }
 }
 
 impl FilterChain {
+    /// NES filters for the given sample rate (Hz).
+    pub fn new(sample_rate: f64) -> Self {
+        Self::with_cutoffs(
+            sample_rate,
+            HIGH_PASS_1_CUTOFF,
+            HIGH_PASS_2_CUTOFF,
+            LOW_PASS_CUTOFF,
+        )
+    }
+
+    /// Same as `new` but with custom cutoff frequencies (Hz).
+    pub fn with_cutoffs(
+        sample_rate: f64,
+        high_pass_1: f64,
+        high_pass_2: f64,
+        low_pass: f64,
+    ) -> Self {
+        Self {
+            high_pass_1: HighPass::new(high_pass_1, sample_rate),
+            high_pass_2: HighPass::new(high_pass_2, sample_rate),
+            low_pass: LowPass::new(low_pass, sample_rate),
+        }
+    }
+
     pub fn tick(&mut self, mut obs: f64) -> f64 {
         obs = self.high_pass_1.tick(obs);
         obs = self.high_pass_2.tick(obs);
         self.low_pass.tick(obs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dc_is_removed() {
+        let mut filters = FilterChain::new(48000.0);
+        let mut out = 0.0;
+        // one second of constant signal.
+        for _ in 0..48000 {
+            out = filters.tick(1.0);
+        }
+        assert!(out.abs() < 1e-3, "DC not removed: {}", out);
+    }
+
+    #[test]
+    fn high_frequency_is_attenuated() {
+        let mut low_pass = LowPass::new(LOW_PASS_CUTOFF, 44100.0);
+        // Impulse
+        let out = low_pass.tick(1.0);
+        assert!(out < 0.7);
+
+        // Signal at Nyquist frequency (22050Hz).
+        let mut max_out: f64 = 0.0;
+        for i in 0..1000 {
+            let obs = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let out = low_pass.tick(obs);
+            if i > 100 {
+                max_out = max_out.max(out.abs());
+            }
+        }
+        assert!(max_out < 0.6, "Not attenuated: {}", max_out);
+    }
+}
@@ -4,13 +4,17 @@ use serde_derive::{Deserialize, Serialize};
 use std::default::Default;
 use tracing::{debug, info, trace};
 mod filters;
+pub mod queue;
+pub mod write_log;
 use filters::FilterChain;
+use write_log::WriteLog;
 
 // Same as CPU (one frame is 60Hz)
 const TICK_PER_FRAME: f64 = 29780.0;
-// Computer audio is 44100Hz. 60 frames per second. 44100/60
-const SAMPLES_PER_FRAME: f64 = 735.0;
-const SAMPLE_TIMER_RATE: f64 = TICK_PER_FRAME / SAMPLES_PER_FRAME;
+// CPU ticks in one second of emulation (60 frames).
+const TICK_PER_SECOND: u64 = 29780 * 60;
+// Default computer audio is 44100Hz.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
 const FRAME_COUNTER_RATE: f64 = TICK_PER_FRAME / 4.0;
 
 const DUTY_VALUES: [[u8; 8]; 4] = [
@@ -28,6 +32,14 @@ const LENGTH_COUNTER_LOOKUP: [u8; 0x20] = [
     192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
+/// Period of the DMC timer in CPU cycles (NTSC). The index is written to $4010.
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// CPU cycles stolen by the DMC to fetch one byte of sample.
+pub const DMC_FETCH_STALL: u64 = 4;
+
 const TRIANGLE_WAVE: [f64; 32] = [
     15.0, 14.0, 13.0, 12.0, 11.0, 10.0, 9.0, 8.0, 7.0, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0, 0.0, 0.0, 1.0,
     2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0, 13.0, 14.0, 15.0,
@@ -75,18 +87,32 @@ pub struct ApuMemory {
     /// --------------------------------------------
     pub pulse_1_reg1: u8,
     pub pulse_1_reg2: u8,
+    /// 0x4004 to 0x4007 (included)
+    /// Pulse 2 - Same as pulse 1.
+    /// --------------------------------------------
+    pub pulse_2_reg1: u8,
+    pub pulse_2_reg2: u8,
     pulse_1: Pulse,
     pulse_2: Pulse,
     triangle: Triangle,
+    #[serde(default)]
+    dmc: Dmc,
 
     frame_counter: FrameCounter,
     /// True if something has changed since last write/read
     pub dirty: bool,
+
+    /// Register writes are recorded here when set.
+    #[serde(skip)]
+    pub write_log: Option<WriteLog>,
 }
 
 impl ApuMemory {
     pub fn write(&mut self, addr: usize, value: u8) {
         self.dirty = true;
+        if let Some(log) = self.write_log.as_mut() {
+            log.record(addr, value);
+        }
         match addr {
             // PULSE 1
             // ---------------------------------
@@ -136,18 +162,19 @@ impl ApuMemory {
             // PULSE 2
             // ---------------------------------------
             0x4004 => {
+                self.pulse_2_reg1 = value;
                 self.pulse_2.duty_cycle = value >> 6;
                 self.pulse_2.envelope.period = value & 0b1111;
                 self.pulse_2.envelope.do_loop = value & 0b00100000 == 0b00100000;
                 self.pulse_2.envelope.enabled = value & 0b00010000 == 0;
                 self.pulse_2.length_counter.halt_flag_set = value & 0b00100000 == 0b00100000;
             }
-            0x4005 => self.pulse_1_reg2 = value,
+            0x4005 => self.pulse_2_reg2 = value,
 
-            // Timer for the first pulse channel. Set via 0x4002 and 0x4003
+            // Timer for the second pulse channel. Set via 0x4006 and 0x4007
             // HHH.LLLL.LLLL
-            // 0x4002 = LLLL.LLLL
-            // 0x4003 = xxxx.xHHH
+            // 0x4006 = LLLL.LLLL
+            // 0x4007 = xxxx.xHHH
             0x4006 => {
                 //self.pulse_2.timer = self.pulse_2.timer & 0b11100000000 | (value as u16);
                 self.pulse_2.timer.set_low(value);
@@ -185,16 +212,35 @@ impl ApuMemory {
                 self.triangle.linear_counter.reload_flag = true;
             }
 
+            // DMC
+            // -----------------------------------------------
+            0x4010 => {
+                self.dmc.irq_enabled = value & 0b1000_0000 == 0b1000_0000;
+                if !self.dmc.irq_enabled {
+                    self.dmc.irq = false;
+                }
+                self.dmc.do_loop = value & 0b0100_0000 == 0b0100_0000;
+                self.dmc.timer_period = DMC_RATES[(value & 0xF) as usize];
+            }
+            0x4011 => self.dmc.output_level = value & 0x7F,
+            0x4012 => self.dmc.sample_address = 0xC000 + (value as u16) * 64,
+            0x4013 => self.dmc.sample_length = (value as u16) * 16 + 1,
+
             // ----------------------------------------------------
             0x4015 => {
                 self.pulse_1.set_enabled(value & 0b1 == 0b1);
                 self.pulse_2.set_enabled(value & 0b10 == 0b10);
                 self.triangle.set_enabled(value & 0b100 == 0b100);
+                self.dmc.set_enabled(value & 0b1_0000 == 0b1_0000);
             }
 
             0x4017 => {
                 let mode = value & 0b1000_0000;
                 self.frame_counter.mode = mode; // won't be 1 but it's ok, the condition is on 0.
+                self.frame_counter.irq_inhibit = value & 0b0100_0000 == 0b0100_0000;
+                if self.frame_counter.irq_inhibit {
+                    self.frame_counter.irq = false;
+                }
                 if mode > 0 {
                     self.tick_envelopes_and_linear_counter();
                     self.tick_length_counters();
@@ -204,7 +250,16 @@ impl ApuMemory {
         }
     }
 
+    /// Status register ($4015). Reading acknowledges the frame IRQ.
     pub fn read(&mut self) -> u8 {
+        let res = self.peek();
+        self.frame_counter.irq = false;
+        res
+    }
+
+    /// Status register ($4015) without side effects. There is no noise
+    /// channel so bit 3 is always 0.
+    pub fn peek(&self) -> u8 {
         let mut res = 0;
         if self.pulse_1.length_counter.value > 0 {
             res |= 0b1;
@@ -212,9 +267,38 @@ impl ApuMemory {
         if self.pulse_2.length_counter.value > 0 {
             res |= 0b10;
         }
+        if self.triangle.length_counter.value > 0 {
+            res |= 0b100;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            res |= 0b1_0000;
+        }
+        if self.frame_counter.irq {
+            res |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            res |= 0b1000_0000;
+        }
         res
     }
 
+    /// The frame counter reached its last step in 4-step mode with the IRQ
+    /// not inhibited.
+    pub fn frame_irq(&self) -> bool {
+        self.frame_counter.irq
+    }
+
+    /// The DMC finished playing a sample with its IRQ enabled.
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+
+    /// Silence all channels and reset the frame counter. Used by soft reset.
+    pub fn reset(&mut self) {
+        self.write(0x4015, 0);
+        self.frame_counter.reset();
+    }
+
     fn tick_envelopes_and_linear_counter(&mut self) {
         self.pulse_1.envelope.tick();
         self.pulse_2.envelope.tick();
@@ -234,6 +318,10 @@ impl ApuMemory {
 struct FrameCounter {
     mode: u8,
     current_count: u64,
+    // Bit 6 of $4017.
+    irq_inhibit: bool,
+    // Cleared by reading $4015 or by inhibiting the IRQ.
+    irq: bool,
 }
 
 impl FrameCounter {
@@ -277,9 +365,9 @@ impl FrameCounter {
         }
     }
 
-    /// TODO Implement that
+    /// Should raise the frame IRQ. Only in 4-step mode.
     pub fn is_interrupt(&self) -> bool {
-        false
+        self.mode == 0 && !self.irq_inhibit && self.current_count == 29829
     }
 }
 
@@ -308,9 +396,15 @@ pub struct Apu {
     /// Keep track how many cycles since the beginning.
     pub cycles: u64,
 
-    // Rate at which we take a sample
+    // Rate at which we take a sample. A sample is taken every
+    // sample_timer_rate + sample_timer_remainder / sample_rate CPU ticks. The
+    // fractional part is accumulated in `extra`.
     sample_timer: u64,
     sample_timer_rate: u64,
+    #[serde(default)]
+    sample_timer_remainder: u64,
+    #[serde(default = "default_sample_rate")]
+    sample_rate: u32,
     samples: Vec<i16>,
     extra: u64,
 
@@ -319,6 +413,10 @@ pub struct Apu {
 
     #[serde(skip)]
     pub levels: ApuLevels,
+
+    // CPU cycles stolen by the DMC since the last call to `take_dmc_stall`.
+    #[serde(skip)]
+    dmc_stall: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -375,6 +473,11 @@ struct Triangle {
 impl Triangle {
     /// tick every CPU clock.
     pub fn tick(&mut self) {
+        // Ultrasonic frequencies (period < 2) would just produce a pop
+        // so hold the current output instead of stepping the sequencer.
+        if self.timer.timer < 2 {
+            return;
+        }
         if self.timer.tick() {
             // clock the sequencer :D
             //self.seq_index = (self.seq_index.wrapping_sub(1)) % 7;
@@ -556,21 +659,174 @@ impl Pulse {
     }
 }
 
+// --------------------------------------------------------------------------------------
+
+/// Delta modulation channel. Plays 1-bit delta samples read from the
+/// cartridge. Each byte read steals a few CPU cycles.
+#[derive(Debug, Serialize, Deserialize)]
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    do_loop: bool,
+
+    timer_period: u16,
+    timer: u16,
+
+    /// 7 bits DAC.
+    output_level: u8,
+
+    // Memory reader
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self {
+            irq_enabled: false,
+            irq: false,
+            do_loop: false,
+            timer_period: DMC_RATES[0],
+            timer: DMC_RATES[0],
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+}
+
+impl Dmc {
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Address of the next byte to fetch, if the sample buffer is empty.
+    fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn load_sample(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // Address wraps to $8000 after $FFFF.
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.do_loop {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Every CPU tick.
+    fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift_register = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn sample(&self) -> f64 {
+        self.output_level as f64
+    }
+}
+
+fn default_sample_rate() -> u32 {
+    DEFAULT_SAMPLE_RATE
+}
+
 impl Apu {
     pub fn new() -> Self {
-        // shoganai
-        let sample_timer_rate = 40; //SAMPLE_TIMER_RATE.round() as u64;
-        let sample_timer = sample_timer_rate;
         let samples = Vec::with_capacity(1024);
-        Self {
+        let mut apu = Self {
             cycles: 0,
-            sample_timer,
-            sample_timer_rate,
+            sample_timer: 0,
+            sample_timer_rate: 0,
+            sample_timer_remainder: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
             samples,
             extra: 0,
             filters: FilterChain::default(),
             levels: ApuLevels::default(),
-        }
+            dmc_stall: 0,
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    /// Set the output sample rate (in Hz). Around hz/60 samples will be
+    /// produced per frame.
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        let hz = hz.max(1);
+        self.sample_rate = hz;
+        self.sample_timer_rate = TICK_PER_SECOND / hz as u64;
+        self.sample_timer_remainder = TICK_PER_SECOND % hz as u64;
+        self.sample_timer = self.sample_timer_rate;
+        self.extra = 0;
+        self.filters = FilterChain::new(hz as f64);
+    }
+
+    /// The filters are not part of the state. Build them again for the
+    /// sample rate of a loaded state.
+    pub fn restore_filters(&mut self) {
+        self.filters = FilterChain::new(self.sample_rate as f64);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 
     pub fn next(&mut self, cpu_ticks: u64, mem: &mut Memory) {
@@ -583,12 +839,18 @@ impl Apu {
                 // clock pulse
                 mem.apu_mem.pulse_1.tick();
                 mem.apu_mem.pulse_2.tick();
-
-                // Frame counter timer.
-                mem.apu_mem.frame_counter.tick();
             }
             mem.apu_mem.triangle.tick();
 
+            // The DMC reads the next byte of the sample as soon as its
+            // buffer is empty. The CPU is stalled during the read.
+            if let Some(addr) = mem.apu_mem.dmc.fetch_address() {
+                let value = mem.peek(addr as usize);
+                mem.apu_mem.dmc.load_sample(value);
+                self.dmc_stall += DMC_FETCH_STALL;
+            }
+            mem.apu_mem.dmc.tick();
+
             // Length counter and envelopes update.
             if mem.apu_mem.frame_counter.is_1st_quarter() {
                 mem.apu_mem.tick_envelopes_and_linear_counter();
@@ -601,24 +863,36 @@ impl Apu {
                 mem.apu_mem.tick_envelopes_and_linear_counter();
                 mem.apu_mem.tick_length_counters();
             }
+            if mem.apu_mem.frame_counter.is_interrupt() {
+                mem.apu_mem.frame_counter.irq = true;
+            }
+            // Frame counter timer. Its steps are in CPU cycles, so step n
+            // happens at the CPU cycle n + 1.
+            mem.apu_mem.frame_counter.tick();
 
             // Instead of taking a lot of samples (Frequency of APU is > 1 Mhz). let's just sample at
-            // 44100Hz.
+            // the computer sample rate.
             // Should we take a sample?
             if self.sample_timer == 0 {
                 // take a sample and reset timer.
-                self.sample_timer = self.sample_timer_rate + self.extra;
-                self.extra = (self.extra + 1) % 2;
+                self.sample_timer = self.sample_timer_rate;
+                self.extra += self.sample_timer_remainder;
+                if self.extra >= self.sample_rate as u64 {
+                    self.extra -= self.sample_rate as u64;
+                    self.sample_timer += 1;
+                }
 
                 let pulse_1_sample = self.levels.pulse_1 * mem.apu_mem.pulse_1.sample();
                 let pulse_2_sample = self.levels.pulse_2 * mem.apu_mem.pulse_2.sample();
                 let triangle_sample = self.levels.triangle * mem.apu_mem.triangle.sample();
+                let dmc_sample = mem.apu_mem.dmc.sample();
 
                 // at first linear approximation
                 // pulse_out = 0.00752 * (pulse1 + pulse2)
                 // tnd_out = 0.00851 * triangle + 0.00494 * noise + 0.00335 * dmc
-                let mut mixed =
-                    0.00752 * (pulse_1_sample + pulse_2_sample) + 0.00851 * triangle_sample;
+                let mut mixed = 0.00752 * (pulse_1_sample + pulse_2_sample)
+                    + 0.00851 * triangle_sample
+                    + 0.00335 * dmc_sample;
                 debug!(msg = "sample", sample = %mixed);
                 mixed = self.filters.tick(mixed);
 
@@ -626,6 +900,16 @@ impl Apu {
             }
             self.sample_timer -= 1;
         }
+
+        if let Some(log) = mem.apu_mem.write_log.as_mut() {
+            log.set_cycle(self.cycles);
+        }
+    }
+
+    /// CPU cycles stolen by the DMC since the last call. The caller should
+    /// stall the CPU for that many cycles.
+    pub fn take_dmc_stall(&mut self) -> u64 {
+        std::mem::replace(&mut self.dmc_stall, 0)
     }
 
     /// Will drain all our samples to send to the audio queue.
@@ -635,3 +919,152 @@ impl Apu {
         self.samples.drain(..).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulse_sweep_registers() {
+        let mut apu_mem = ApuMemory::default();
+        apu_mem.write(0x4001, 0x11);
+        apu_mem.write(0x4005, 0x22);
+        assert_eq!(0x11, apu_mem.pulse_1_reg2);
+        assert_eq!(0x22, apu_mem.pulse_2_reg2);
+
+        apu_mem.write(0x4000, 0xBF);
+        apu_mem.write(0x4004, 0x7F);
+        assert_eq!(0xBF, apu_mem.pulse_1_reg1);
+        assert_eq!(2, apu_mem.pulse_1.duty_cycle);
+        assert_eq!(0x7F, apu_mem.pulse_2_reg1);
+        assert_eq!(1, apu_mem.pulse_2.duty_cycle);
+    }
+
+    #[test]
+    fn dmc_reads_sample_and_raises_irq() {
+        let mut apu = Apu::new();
+        let mut memory = Memory::default();
+        // IRQ enabled, sample of 1 byte at $C000.
+        memory.set(0x4010, 0x80);
+        memory.set(0x4012, 0);
+        memory.set(0x4013, 0);
+        memory.set(0x4015, 0x10);
+        assert_eq!(0x10, memory.apu_mem.peek());
+
+        apu.next(1, &mut memory);
+        assert_eq!(DMC_FETCH_STALL, apu.take_dmc_stall());
+        assert_eq!(0, apu.take_dmc_stall());
+        assert_eq!(0x80, memory.apu_mem.peek());
+        assert!(memory.apu_mem.dmc_irq());
+
+        // Acknowledged by writing to $4015.
+        memory.set(0x4015, 0);
+        assert!(!memory.apu_mem.dmc_irq());
+    }
+
+    #[test]
+    fn status_register() {
+        let mut apu_mem = ApuMemory::default();
+        apu_mem.pulse_2.length_counter.value = 3;
+        apu_mem.triangle.length_counter.value = 1;
+        apu_mem.frame_counter.irq = true;
+        apu_mem.dmc.irq = true;
+        assert_eq!(0b1100_0110, apu_mem.peek());
+
+        // Reading acknowledges the frame IRQ only.
+        assert_eq!(0b1100_0110, apu_mem.read());
+        assert_eq!(0b1000_0110, apu_mem.read());
+        assert!(!apu_mem.frame_irq());
+        assert!(apu_mem.dmc_irq());
+    }
+
+    #[test]
+    fn frame_irq_in_4_step_mode() {
+        let mut apu = Apu::new();
+        let mut memory = Memory::default();
+        // Raised at the last CPU cycle of the frame.
+        apu.next(29829, &mut memory);
+        assert!(!memory.apu_mem.frame_irq());
+        apu.next(1, &mut memory);
+        assert!(memory.apu_mem.frame_irq());
+        assert!(memory.irq());
+        assert_eq!(0x40, memory.get(0x4015));
+        assert!(!memory.irq());
+
+        // Inhibited
+        memory.set(0x4017, 0x40);
+        apu.next(29830, &mut memory);
+        assert!(!memory.apu_mem.frame_irq());
+    }
+
+    #[test]
+    fn frame_and_dmc_share_the_irq_line() {
+        let mut apu = Apu::new();
+        let mut memory = Memory::default();
+        // DMC IRQ after a sample of 1 byte, frame IRQ at the end of the
+        // frame.
+        memory.set(0x4010, 0x80);
+        memory.set(0x4013, 0);
+        memory.set(0x4015, 0x10);
+        apu.next(29829, &mut memory);
+        assert!(memory.apu_mem.dmc_irq());
+        assert!(!memory.apu_mem.frame_irq());
+        apu.next(1, &mut memory);
+        assert!(memory.apu_mem.frame_irq());
+
+        // Reading $4015 acknowledges the frame IRQ only.
+        assert_eq!(0xC0, memory.get(0x4015));
+        assert!(!memory.apu_mem.frame_irq());
+        assert!(memory.irq());
+
+        // Writing $4017 without the inhibit flag keeps the DMC IRQ.
+        memory.set(0x4017, 0);
+        assert!(memory.irq());
+
+        // Writing $4015 acknowledges the DMC IRQ.
+        memory.set(0x4015, 0);
+        assert!(!memory.irq());
+    }
+
+    #[test]
+    fn sample_rate_48000() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48000);
+        let mut memory = Memory::default();
+
+        let mut nb_samples = 0;
+        for _ in 0..60 {
+            apu.next(TICK_PER_FRAME as u64, &mut memory);
+            nb_samples += apu.samples().len() as i64;
+        }
+
+        assert!((nb_samples - 48000).abs() <= 1, "got {} samples", nb_samples);
+    }
+
+    #[test]
+    fn triangle_ultrasonic_is_silenced() {
+        let mut triangle = Triangle::default();
+        triangle.enabled = true;
+        triangle.length_counter.value = 10;
+        triangle.linear_counter.current_value = 10;
+        triangle.seq_index = 4;
+        triangle.timer.set_low(1);
+
+        let sample = triangle.sample();
+        for _ in 0..100 {
+            triangle.tick();
+            assert_eq!(4, triangle.seq_index);
+            assert_eq!(sample, triangle.sample());
+        }
+    }
+
+    #[test]
+    fn triangle_steps_with_normal_period() {
+        let mut triangle = Triangle::default();
+        triangle.timer.set_low(2);
+        for _ in 0..3 {
+            triangle.tick();
+        }
+        assert_eq!(1, triangle.seq_index);
+    }
+}
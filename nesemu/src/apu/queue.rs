@@ -0,0 +1,261 @@
+//! Keep the audio queue of the front-ends around a target fill level.
+//!
+//! The emulator does not run at exactly the speed of the audio device, so
+//! the queue slowly drains (crackles) or grows (latency). A few samples are
+//! dropped or duplicated every frame to compensate. The correction is small
+//! enough to not be heard.
+//!
+//! The size of the device buffer (`--audio-buffer`) trades latency for
+//! stability, and the target fill level follows it.
+
+use std::str::FromStr;
+
+/// Samples waiting in the queue we aim for (~46ms at 44100Hz).
+pub const DEFAULT_TARGET: u32 = 2048;
+/// No correction while the queue is within target +/- tolerance.
+pub const DEFAULT_TOLERANCE: u32 = 512;
+/// At most this number of samples is added or removed per frame (~1% of a
+/// frame at 44100Hz).
+pub const DEFAULT_MAX_CORRECTION: usize = 8;
+
+/// Samples in the buffer of the audio device (~23ms at 44100Hz).
+pub const DEFAULT_BUFFER: u32 = 1024;
+/// Smaller buffers underrun all the time.
+pub const MIN_BUFFER: u32 = 256;
+/// Bigger buffers add more than 180ms of latency at 44100Hz.
+pub const MAX_BUFFER: u32 = 8192;
+
+/// Number of samples played in `ms` milliseconds, rounded to the nearest.
+pub fn ms_to_samples(ms: u32, sample_rate: u32) -> u32 {
+    ((u64::from(ms) * u64::from(sample_rate) + 500) / 1000) as u32
+}
+
+/// Size of the audio device buffer, either in samples (`1024`) or in
+/// milliseconds (`20ms`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferSize {
+    Samples(u32),
+    Millis(u32),
+}
+
+impl Default for BufferSize {
+    fn default() -> Self {
+        BufferSize::Samples(DEFAULT_BUFFER)
+    }
+}
+
+impl FromStr for BufferSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let millis = trimmed.ends_with("ms");
+        let value = if millis {
+            &trimmed[..trimmed.len() - 2]
+        } else {
+            trimmed
+        };
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid audio buffer size {}", s))?;
+        if millis {
+            Ok(BufferSize::Millis(value))
+        } else {
+            Ok(BufferSize::Samples(value))
+        }
+    }
+}
+
+impl BufferSize {
+    /// Number of samples at `sample_rate`. Error if it is not between
+    /// `MIN_BUFFER` and `MAX_BUFFER`.
+    pub fn samples(&self, sample_rate: u32) -> Result<u32, String> {
+        let samples = match *self {
+            BufferSize::Samples(samples) => samples,
+            BufferSize::Millis(ms) => ms_to_samples(ms, sample_rate),
+        };
+        if !(MIN_BUFFER..=MAX_BUFFER).contains(&samples) {
+            return Err(format!(
+                "Audio buffer of {} samples, should be between {} and {}",
+                samples, MIN_BUFFER, MAX_BUFFER
+            ));
+        }
+        Ok(samples)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    None,
+    /// Remove this number of samples.
+    Drop(usize),
+    /// Duplicate this number of samples.
+    Pad(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FillLevel {
+    pub target: u32,
+    pub tolerance: u32,
+    pub max_correction: usize,
+}
+
+impl Default for FillLevel {
+    fn default() -> Self {
+        Self {
+            target: DEFAULT_TARGET,
+            tolerance: DEFAULT_TOLERANCE,
+            max_correction: DEFAULT_MAX_CORRECTION,
+        }
+    }
+}
+
+impl FillLevel {
+    /// Fill level for a device buffer of `samples` samples: two buffers
+    /// queued, so that the device never waits for the emulator.
+    pub fn for_buffer(samples: u32) -> Self {
+        Self {
+            target: samples * 2,
+            tolerance: samples / 2,
+            max_correction: DEFAULT_MAX_CORRECTION,
+        }
+    }
+
+    /// What to do with the next samples when `queued` samples are waiting.
+    pub fn correction(&self, queued: u32) -> Correction {
+        if queued > self.target + self.tolerance {
+            let excess = (queued - self.target - self.tolerance) as usize;
+            Correction::Drop(excess.min(self.max_correction))
+        } else if queued + self.tolerance < self.target {
+            let missing = (self.target - self.tolerance - queued) as usize;
+            Correction::Pad(missing.min(self.max_correction))
+        } else {
+            Correction::None
+        }
+    }
+}
+
+/// Drop or duplicate samples, spread over the whole buffer.
+pub fn apply(samples: &[i16], correction: Correction) -> Vec<i16> {
+    match correction {
+        Correction::None => samples.to_vec(),
+        Correction::Drop(n) => {
+            let n = n.min(samples.len());
+            if n == 0 {
+                return samples.to_vec();
+            }
+            let step = samples.len() / n;
+            samples
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| (i + 1) % step != 0 || (i + 1) / step > n)
+                .map(|(_, s)| *s)
+                .collect()
+        }
+        Correction::Pad(n) => {
+            if n == 0 || samples.is_empty() {
+                return samples.to_vec();
+            }
+            let step = (samples.len() / n).max(1);
+            let mut out = Vec::with_capacity(samples.len() + n);
+            let mut added = 0;
+            for (i, s) in samples.iter().enumerate() {
+                out.push(*s);
+                if added < n && (i + 1) % step == 0 {
+                    out.push(*s);
+                    added += 1;
+                }
+            }
+            // Less samples than padding needed.
+            while added < n {
+                out.push(samples[samples.len() - 1]);
+                added += 1;
+            }
+            out
+        }
+    }
+}
+
+/// Resample to `factor` times the number of samples (nearest sample). Used
+/// to stretch the audio in slow motion and to shrink it in fast forward.
+pub fn stretch(samples: &[i16], factor: f64) -> Vec<i16> {
+    let len = (samples.len() as f64 * factor).round() as usize;
+    (0..len)
+        .map(|i| {
+            let src = ((i as f64 / factor) as usize).min(samples.len() - 1);
+            samples[src]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_samples() {
+        let level = FillLevel::default();
+        assert_eq!(Correction::Drop(8), level.correction(10000));
+        assert_eq!(
+            Correction::Drop(3),
+            level.correction(DEFAULT_TARGET + DEFAULT_TOLERANCE + 3)
+        );
+
+        let samples: Vec<i16> = (0..735).collect();
+        assert_eq!(727, apply(&samples, Correction::Drop(8)).len());
+    }
+
+    #[test]
+    fn empty_queue_pads_samples() {
+        let level = FillLevel::default();
+        assert_eq!(Correction::Pad(8), level.correction(0));
+
+        let samples: Vec<i16> = (0..735).collect();
+        assert_eq!(743, apply(&samples, Correction::Pad(8)).len());
+        assert_eq!(vec![1, 1, 1], apply(&[1], Correction::Pad(2)));
+    }
+
+    #[test]
+    fn buffer_size_in_ms() {
+        assert_eq!(441, ms_to_samples(10, 44100));
+        assert_eq!(960, ms_to_samples(20, 48000));
+        // 22.05 samples per ms
+        assert_eq!(441, ms_to_samples(20, 22050));
+
+        let size: BufferSize = "40ms".parse().unwrap();
+        assert_eq!(BufferSize::Millis(40), size);
+        assert_eq!(Ok(1764), size.samples(44100));
+        assert_eq!(Ok(1920), size.samples(48000));
+        assert!(BufferSize::Millis(1).samples(44100).is_err());
+    }
+
+    #[test]
+    fn buffer_size_in_samples() {
+        let size: BufferSize = "2048".parse().unwrap();
+        assert_eq!(Ok(2048), size.samples(48000));
+        assert!(BufferSize::Samples(100_000).samples(44100).is_err());
+        assert!("fast".parse::<BufferSize>().is_err());
+
+        let level = FillLevel::for_buffer(DEFAULT_BUFFER);
+        assert_eq!(DEFAULT_TARGET, level.target);
+        assert_eq!(DEFAULT_TOLERANCE, level.tolerance);
+    }
+
+    #[test]
+    fn stretch_samples() {
+        assert_eq!(vec![1, 1, 2, 2, 3, 3], stretch(&[1, 2, 3], 2.0));
+        assert_eq!(vec![1, 3], stretch(&[1, 2, 3, 4], 0.5));
+        assert!(stretch(&[], 2.0).is_empty());
+    }
+
+    #[test]
+    fn no_correction_near_target() {
+        let level = FillLevel::default();
+        assert_eq!(Correction::None, level.correction(DEFAULT_TARGET));
+        assert_eq!(
+            Correction::None,
+            level.correction(DEFAULT_TARGET - DEFAULT_TOLERANCE)
+        );
+    }
+}
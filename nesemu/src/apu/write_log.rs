@@ -0,0 +1,49 @@
+//! Log of the APU register writes, for music analysis or conversion to VGM.
+//!
+//! One CSV line per write: `cycle,addr,value`. The cycle is the number of
+//! CPU cycles run by the APU before the instruction that did the write.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct WriteLog {
+    out: BufWriter<File>,
+    cycle: u64,
+    // First error while writing. Returned by `finish`.
+    error: Option<io::Error>,
+}
+
+impl WriteLog {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WriteLog> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "cycle,addr,value")?;
+        Ok(WriteLog {
+            out,
+            cycle: 0,
+            error: None,
+        })
+    }
+
+    pub fn set_cycle(&mut self, cycle: u64) {
+        self.cycle = cycle;
+    }
+
+    pub fn record(&mut self, addr: usize, value: u8) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = writeln!(self.out, "{},{:04X},{:02X}", self.cycle, addr, value) {
+            self.error = Some(err);
+        }
+    }
+
+    /// Flush the file. Error if any write failed.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.flush()
+    }
+}
@@ -0,0 +1,115 @@
+// Game Genie codes. The Game Genie sits between the cartridge and the console
+// and replaces the value read from PRG ROM at a given address.
+// A code is 6 or 8 letters. Each letter is a 4 bits value, and the bits are
+// scrambled to get the address, the new value and (8 letters only) the compare
+// value. With a compare value, the substitution only happens when the ROM
+// contains this value at the address (useful for bank switched ROMs).
+//
+// https://wiki.nesdev.com/w/index.php/Game_Genie
+use serde_derive::{Deserialize, Serialize};
+
+const LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl Cheat {
+    /// Decode a 6 or 8 letters Game Genie code.
+    pub fn from_game_genie(code: &str) -> Result<Cheat, String> {
+        let n = code
+            .chars()
+            .map(|c| {
+                LETTERS
+                    .find(c.to_ascii_uppercase())
+                    .map(|idx| idx as u16)
+                    .ok_or(format!("Invalid Game Genie letter {}", c))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!(
+                "Game Genie code should have 6 or 8 letters, got {}",
+                n.len()
+            ));
+        }
+
+        let address = 0x8000
+            + (((n[3] & 7) << 12)
+                | ((n[5] & 7) << 8)
+                | ((n[4] & 8) << 8)
+                | ((n[2] & 7) << 4)
+                | ((n[1] & 8) << 4)
+                | (n[4] & 7)
+                | (n[3] & 8));
+
+        let (value, compare) = if n.len() == 6 {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[5] & 8);
+            (value, None)
+        } else {
+            let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (n[7] & 8);
+            let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            (value, Some(compare as u8))
+        };
+
+        Ok(Cheat {
+            address,
+            value: value as u8,
+            compare,
+        })
+    }
+
+    /// Return the value to use when reading `rom_value` at `address`.
+    pub fn apply(&self, address: u16, rom_value: u8) -> u8 {
+        if address != self.address {
+            return rom_value;
+        }
+
+        match self.compare {
+            Some(compare) if compare != rom_value => rom_value,
+            _ => self.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_6_letters() {
+        let cheat = Cheat::from_game_genie("SXIOPO").unwrap();
+        assert_eq!(0x91D9, cheat.address);
+        assert_eq!(0xAD, cheat.value);
+        assert_eq!(None, cheat.compare);
+
+        let cheat = Cheat::from_game_genie("GOSSIP").unwrap();
+        assert_eq!(0xD1DD, cheat.address);
+        assert_eq!(0x14, cheat.value);
+    }
+
+    #[test]
+    fn decode_8_letters() {
+        let cheat = Cheat::from_game_genie("ZEXPYGLA").unwrap();
+        assert_eq!(0x94A7, cheat.address);
+        assert_eq!(0x02, cheat.value);
+        assert_eq!(Some(0x03), cheat.compare);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(Cheat::from_game_genie("SXIOP").is_err());
+        assert!(Cheat::from_game_genie("SXIOPB").is_err());
+    }
+
+    #[test]
+    fn apply_with_compare() {
+        let cheat = Cheat::from_game_genie("ZEXPYGLA").unwrap();
+        assert_eq!(0x02, cheat.apply(0x94A7, 0x03));
+        assert_eq!(0x04, cheat.apply(0x94A7, 0x04));
+        assert_eq!(0x03, cheat.apply(0x94A8, 0x03));
+    }
+}
@@ -0,0 +1,103 @@
+//! Settings of the front-ends, read at startup from a JSON file given with
+//! `--config`. Every value is optional. The command line wins over the file
+//! and the front-end uses its usual default when neither sets a value.
+//!
+//! ```json
+//! {
+//!     "palette": "palettes/smooth.pal",
+//!     "scale": 2,
+//!     "audio_buffer": "50ms",
+//!     "region": "Pal",
+//!     "keys_p1": { "Z": "A", "X": "B", "Up": "UP" }
+//! }
+//! ```
+use crate::apu::queue::BufferSize;
+use crate::error::NesError;
+use crate::joypad::InputAction;
+use crate::rom::Region;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Path of a .pal file.
+    pub palette: Option<String>,
+    pub scale: Option<u32>,
+    pub no_sound: Option<bool>,
+    /// Same syntax as the `--audio-buffer` option: samples or "50ms".
+    pub audio_buffer: Option<String>,
+    pub speed: Option<f64>,
+    pub region: Option<Region>,
+    /// Key name to button. The names are the ones of the front-end's
+    /// windowing library.
+    pub keys_p1: Option<HashMap<String, InputAction>>,
+    pub keys_p2: Option<HashMap<String, InputAction>>,
+}
+
+impl Config {
+    pub fn from_json(json: &str) -> Result<Config, NesError> {
+        serde_json::from_str(json).map_err(|e| NesError::Config(e.to_string()))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, NesError> {
+        let json = fs::read_to_string(path)?;
+        Config::from_json(&json)
+    }
+
+    /// Values set in `overrides` (usually the command line) replace the
+    /// ones of `self`.
+    pub fn merge(self, overrides: Config) -> Config {
+        Config {
+            palette: overrides.palette.or(self.palette),
+            scale: overrides.scale.or(self.scale),
+            no_sound: overrides.no_sound.or(self.no_sound),
+            audio_buffer: overrides.audio_buffer.or(self.audio_buffer),
+            speed: overrides.speed.or(self.speed),
+            region: overrides.region.or(self.region),
+            keys_p1: overrides.keys_p1.or(self.keys_p1),
+            keys_p2: overrides.keys_p2.or(self.keys_p2),
+        }
+    }
+
+    /// Parsed audio buffer size. Default if not set.
+    pub fn buffer_size(&self) -> Result<BufferSize, String> {
+        match self.audio_buffer {
+            Some(ref size) => size.parse(),
+            None => Ok(BufferSize::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file() {
+        let config =
+            Config::from_json(r#"{ "scale": 2, "region": "Pal", "keys_p1": { "Z": "A" } }"#)
+                .unwrap();
+        assert_eq!(Some(2), config.scale);
+        assert_eq!(Some(Region::Pal), config.region);
+        assert_eq!(None, config.palette);
+        assert_eq!(Some(&InputAction::A), config.keys_p1.unwrap().get("Z"));
+
+        assert_eq!(Config::default(), Config::from_json("{}").unwrap());
+        assert!(Config::from_json(r#"{ "scale": "big" }"#).is_err());
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let file = Config::from_json(r#"{ "scale": 2, "palette": "a.pal" }"#).unwrap();
+        let cli = Config {
+            scale: Some(4),
+            ..Config::default()
+        };
+        let config = file.merge(cli);
+        assert_eq!(Some(4), config.scale);
+        assert_eq!(Some("a.pal".to_string()), config.palette);
+    }
+}
@@ -50,53 +50,58 @@ pub enum MySavior {
 }
 
 impl MySavior {
-    pub fn new(addressing_type: AddressingModeType, nes: &mut Cpu, memory: &mut Memory) -> Self {
+    // The operands are read with `read`, see `Instruction::decode`.
+    pub fn new<F: FnMut(u16) -> u8>(
+        addressing_type: AddressingModeType,
+        nes: &mut Cpu,
+        read: &mut F,
+    ) -> Self {
         match addressing_type {
             AddressingModeType::Accumulator => {
                 MySavior::Accumulator(AccumulatorAddressing::new(&nes))
             }
             AddressingModeType::Implied => MySavior::Implied(ImpliedAddressing::new()),
             AddressingModeType::Immediate => {
-                MySavior::Immediate(ImmediateAddressing::new(nes.advance(memory)))
+                MySavior::Immediate(ImmediateAddressing::new(nes.advance_with(read)))
             }
             AddressingModeType::ZeroPage => {
-                MySavior::ZeroPage(ZeroPageAddressing::new(nes.advance(memory)))
+                MySavior::ZeroPage(ZeroPageAddressing::new(nes.advance_with(read)))
             }
             AddressingModeType::ZeroPageX => MySavior::IndexedZeroPage(
-                IndexedZeroPageAddressing::new(nes.advance(memory), nes.get_regx()),
+                IndexedZeroPageAddressing::new(nes.advance_with(read), nes.get_regx()),
             ),
             AddressingModeType::ZeroPageY => MySavior::IndexedZeroPage(
-                IndexedZeroPageAddressing::new(nes.advance(memory), nes.get_regy()),
+                IndexedZeroPageAddressing::new(nes.advance_with(read), nes.get_regy()),
             ),
             AddressingModeType::Relative => {
-                MySavior::Relative(RelativeAddressing::new(nes.advance(memory)))
+                MySavior::Relative(RelativeAddressing::new(nes.advance_with(read)))
             }
             AddressingModeType::Absolute => {
-                let op1 = nes.advance(memory);
-                let op2 = nes.advance(memory);
+                let op1 = nes.advance_with(read);
+                let op2 = nes.advance_with(read);
                 MySavior::Absolute(AbsoluteAddressing::new(op1, op2))
             }
             AddressingModeType::AbsoluteX => {
-                let op1 = nes.advance(memory);
-                let op2 = nes.advance(memory);
+                let op1 = nes.advance_with(read);
+                let op2 = nes.advance_with(read);
                 MySavior::IndexedAbsolute(IndexedAbsoluteAddressing::new(op1, op2, nes.get_regx()))
             }
             AddressingModeType::AbsoluteY => {
-                let op1 = nes.advance(memory);
-                let op2 = nes.advance(memory);
+                let op1 = nes.advance_with(read);
+                let op2 = nes.advance_with(read);
                 MySavior::IndexedAbsolute(IndexedAbsoluteAddressing::new(op1, op2, nes.get_regy()))
             }
             AddressingModeType::Indirect => {
-                let op1 = nes.advance(memory);
-                let op2 = nes.advance(memory);
+                let op1 = nes.advance_with(read);
+                let op2 = nes.advance_with(read);
                 MySavior::Indirect(IndirectAddressing::new(op1, op2))
             }
             AddressingModeType::PreIndexedIndirect => {
-                let op = nes.advance(memory);
+                let op = nes.advance_with(read);
                 MySavior::PreIndexedIndirect(PreIndexedIndirectAddressing::new(op, nes.get_regx()))
             }
             AddressingModeType::PostIndexedIndirect => {
-                let op = nes.advance(memory);
+                let op = nes.advance_with(read);
                 MySavior::PostIndexedIndirect(PostIndexedIndirectAddressing::new(
                     op,
                     nes.get_regy(),
@@ -542,19 +547,15 @@ impl IndirectAddressing {
     }
 
     fn fetch16(&self, mem: &mut Memory) -> u16 {
-        let lsb = mem.get(self.lsb_location as usize);
-        let mut next_loc = self.lsb_location + 1;
-        if (self.lsb_location & 0xFF) as u8 == 0xFF {
-            next_loc = self.lsb_location & 0xFF00;
-        }
-        let msb = mem.get(next_loc as usize);
-        let address = ((msb as u16) << 8) + (lsb as u16);
-        address
+        self.address(mem)
     }
 
+    // The 6502 does not carry to the high byte of the pointer: with the
+    // pointer at $xxFF, the MSB is read from $xx00.
     fn address(&self, mem: &mut Memory) -> u16 {
         let lsb = mem.get(self.lsb_location as usize);
-        let msb = mem.get((self.lsb_location + 1) as usize);
+        let next_loc = (self.lsb_location & 0xFF00) | (self.lsb_location.wrapping_add(1) & 0xFF);
+        let msb = mem.get(next_loc as usize);
         ((msb as u16) << 8) + (lsb as u16)
     }
 
@@ -771,4 +772,15 @@ mod tests {
         assert_eq!(3, addressing.fetch(&mut memory));
     }
 
+    #[test]
+    fn test_indirect_page_boundary_bug() {
+        let mut memory: Memory = Default::default();
+        memory.set(0x02FF, 0x34);
+        memory.set(0x0300, 0x56);
+        memory.set(0x0200, 0x12);
+        let addressing = IndirectAddressing::new(0xFF, 0x02);
+        assert_eq!(0x1234, addressing.address(&mut memory));
+        assert_eq!(0x1234, addressing.fetch16(&mut memory));
+    }
+
 }
@@ -3,6 +3,27 @@ use super::instructions::Instruction;
 use super::memory::Memory;
 use serde_derive::{Deserialize, Serialize};
 
+// KIL (also called JAM) opcodes stop the 6502 until the next reset.
+const KIL_OPCODES: [u8; 12] = [
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+];
+
+/// What to do when the CPU meets an opcode that is not implemented or a KIL
+/// opcode.
+///
+/// Unstable unofficial opcodes (SHY, SHX, TAS, AHX, XAA, LXA and LAS) are
+/// implemented, so the policy does not apply to them. They always run with
+/// their commonly documented behaviour, even if some chips differ.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IllegalOpcodePolicy {
+    /// `next` returns an error. PC stays on the opcode.
+    Halt,
+    /// Log the opcode and continue. KIL opcodes jam the CPU like on the
+    /// hardware: PC stays on the opcode.
+    #[default]
+    Continue,
+}
+
 #[allow(non_snake_case)] // PC, SP ... are names in the specs.
 #[derive(Serialize, Deserialize)]
 pub struct Cpu {
@@ -29,6 +50,23 @@ pub struct Cpu {
     N: u8, // negative
 
     cycles: u64, // current number of cycles executed by the cpu.
+
+    // Address and opcode of the last executed instruction. Useful for debugging.
+    #[serde(default)]
+    last_instruction: (u16, u8),
+
+    // Level of the NMI line at the last poll. NMI is edge triggered so the
+    // handler runs only when the line goes from low to high.
+    #[serde(default)]
+    nmi_line: bool,
+
+    // Set by a KIL opcode until the next reset, so the jam is logged once.
+    #[serde(skip)]
+    jammed: bool,
+
+    // Emulator setting, not part of the state.
+    #[serde(skip)]
+    illegal_opcode_policy: IllegalOpcodePolicy,
 }
 
 impl std::fmt::Debug for Cpu {
@@ -42,8 +80,8 @@ impl std::fmt::Debug for Cpu {
             self.Y,
             p,
             self.SP,
-            (3 * self.cycles) % 341
-        ) // 3*cycles to get the APU cycles
+            self.ppu_dot()
+        )
     }
 }
 
@@ -63,9 +101,17 @@ impl Cpu {
             V: 0,
             N: 0,
             cycles: 0,
+            last_instruction: (0, 0),
+            nmi_line: false,
+            jammed: false,
+            illegal_opcode_policy: IllegalOpcodePolicy::default(),
         }
     }
 
+    pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+        self.illegal_opcode_policy = policy;
+    }
+
     pub fn get_acc(&self) -> u8 {
         self.A
     }
@@ -82,10 +128,46 @@ impl Cpu {
         self.PC
     }
 
+    pub fn get_sp(&self) -> u8 {
+        self.SP
+    }
+
+    /// Address and opcode of the last instruction executed by `next`.
+    pub fn last_instruction(&self) -> (u16, u8) {
+        self.last_instruction
+    }
+
+    /// The CPU does nothing for `cycles` cycles (DMC fetch).
+    pub fn stall(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// Number of cycles executed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// PPU dot in the scanline (3 dots per CPU cycle), the CYC column of
+    /// nestest.log. It assumes the PPU started at dot 0 with the CPU.
+    pub fn ppu_dot(&self) -> u64 {
+        (3 * self.cycles) % 341
+    }
+
     pub fn set_pc(&mut self, pc: u16) {
         self.PC = pc;
     }
 
+    /// Soft reset. PC is loaded from the reset vector ($FFFC-$FFFD), SP is
+    /// decremented by 3 and interrupts are disabled. Registers are untouched.
+    pub fn reset(&mut self, memory: &mut Memory) {
+        let lsb = u16::from(memory.get(0xFFFC));
+        let msb = u16::from(memory.get(0xFFFD));
+        self.PC = lsb + (msb << 8);
+        self.SP = self.SP.wrapping_sub(3);
+        self.I = 1;
+        self.jammed = false;
+    }
+
     fn push(&mut self, memory: &mut Memory, value: u8) {
         let addr = 0x0100 + u16::from(self.SP);
         memory.set(addr as usize, value);
@@ -140,9 +222,15 @@ impl Cpu {
         // 1. reset
         // 2. NMI
         // 3. BRK/IRQ
-        if memory.nmi() {
-            // Turn off nmi so that we don't do again :D
-            memory.ppu_mem.consume_nmi();
+        // The lines are polled between two instructions, so the latency
+        // includes the instruction that was running when they changed.
+        let nmi_line = memory.nmi();
+        let nmi_edge = nmi_line && !self.nmi_line;
+        self.nmi_line = nmi_line;
+        if nmi_edge {
+            // The PPU keeps its NMI until acknowledged.
+            memory.consume_nmi();
+            self.nmi_line = memory.nmi();
 
             // push pc and flags to the stack.
             let pc = self.PC;
@@ -180,8 +268,10 @@ impl Cpu {
         0
     }
 
-    pub fn decompile(&mut self, memory: &mut Memory) {
-        let instruction = Instruction::decode(self, memory);
+    // Print the instruction at PC and move PC after it. Memory is read with
+    // peek so that registers are not modified.
+    pub fn decompile(&mut self, memory: &Memory) {
+        let instruction = Instruction::decode_peek(self, memory);
         println!("{:?}\t{: <100?}", instruction, &self);
     }
 
@@ -189,6 +279,8 @@ impl Cpu {
         // Hey, do we have an interrupt?
         let interrupt_cycles = self.process_interrupt(memory);
 
+        self.last_instruction = (self.PC, memory.peek(self.PC as usize));
+        memory.start_instruction(self.PC);
         let instruction = Instruction::decode(self, memory);
         //
         info!("{:?}\t{: <100?}", instruction, &self);
@@ -243,13 +335,14 @@ impl Cpu {
                 self.A = result;
             }
             Instruction::ASL(_, addressing, _length) => {
-                let shifted = u16::from(addressing.fetch(memory)) << 1;
+                let operand = addressing.fetch(memory);
+                let shifted = u16::from(operand) << 1;
                 let result = (shifted & 0xFF) as u8;
                 self.C = (shifted >> 8) as u8;
 
                 match &addressing.mode_type() {
                     AddressingModeType::Accumulator => self.A = result,
-                    _ => addressing.set(memory, result),
+                    _ => Cpu::write_back(memory, addressing, operand, result),
                 }
                 self.set_result_flags(result);
             }
@@ -259,18 +352,19 @@ impl Cpu {
                 let result = operand >> 1;
                 match &addressing.mode_type() {
                     AddressingModeType::Accumulator => self.A = result,
-                    _ => addressing.set(memory, result),
+                    _ => Cpu::write_back(memory, addressing, operand, result),
                 }
                 self.set_result_flags(result);
             }
             Instruction::ROL(_, addressing, _) => {
-                let shifted = u16::from(addressing.fetch(memory)) << 1;
+                let operand = addressing.fetch(memory);
+                let shifted = u16::from(operand) << 1;
                 let result = (shifted & 0xFF) as u8 | (self.C & 1);
                 self.C = (shifted >> 8) as u8;
 
                 match &addressing.mode_type() {
                     AddressingModeType::Accumulator => self.A = result,
-                    _ => addressing.set(memory, result),
+                    _ => Cpu::write_back(memory, addressing, operand, result),
                 }
                 self.set_result_flags(result);
             }
@@ -280,7 +374,7 @@ impl Cpu {
                 self.C = operand & 1;
                 match &addressing.mode_type() {
                     AddressingModeType::Accumulator => self.A = result,
-                    _ => addressing.set(memory, result),
+                    _ => Cpu::write_back(memory, addressing, operand, result),
                 }
                 self.set_result_flags(result);
             }
@@ -387,9 +481,10 @@ impl Cpu {
 
             // INCREMENTS AND DECREMENTS
             Instruction::INC(_, addressing, _cycles) => {
-                let result = addressing.fetch(memory).wrapping_add(1);
+                let operand = addressing.fetch(memory);
+                let result = operand.wrapping_add(1);
                 self.set_result_flags(result);
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
             }
             Instruction::INX(_, _addressing, _cycles) => {
                 // Wrapping add?
@@ -403,9 +498,10 @@ impl Cpu {
                 self.Y = result;
             }
             Instruction::DEC(_, addressing, _cycles) => {
-                let result = addressing.fetch(memory).wrapping_sub(1);
+                let operand = addressing.fetch(memory);
+                let result = operand.wrapping_sub(1);
                 self.set_result_flags(result);
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
             }
             Instruction::DEX(_, _addressing, _cycles) => {
                 let result = self.X.wrapping_sub(1);
@@ -540,16 +636,10 @@ impl Cpu {
                 self.Y = result;
                 self.set_result_flags(result);
             }
+            // A write to $4014 starts the OAM DMA. The CPU is stalled by the
+            // caller, see `Memory::take_oam_dma`.
             Instruction::STA(_, addressing, _length) => {
                 addressing.set(memory, self.A);
-
-                // TODO should only be STA that store in this register...
-                if addressing.address(memory) == 0x4014 {
-                    // DMA writing is actually loading a bunch of sprites in OAM
-                    // instead of looping and writing to OAM directly, but it still
-                    // takes quite some time.
-                    again_extra_cycles += 513; // TODO +1 if on odd cpu cycle
-                }
             }
             Instruction::STX(_, addressing, _length) => {
                 addressing.set(memory, self.X);
@@ -679,6 +769,13 @@ impl Cpu {
                 self.A = result;
                 self.set_result_flags(result);
             }
+            Instruction::AXS(_, addressing, _) => {
+                let operand = addressing.fetch(memory);
+                let (result, overflow) = (self.A & self.X).overflowing_sub(operand);
+                self.C = if overflow { 0 } else { 1 };
+                self.X = result;
+                self.set_result_flags(result);
+            }
             Instruction::LAX(_, addressing, _) => {
                 let operand = addressing.fetch(memory);
                 self.X = operand;
@@ -694,7 +791,7 @@ impl Cpu {
             Instruction::DCP(_, addressing, _) => {
                 let operand = addressing.fetch(memory);
                 let result = operand.wrapping_sub(1);
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
                 let (test_result, overflow) = self.A.overflowing_sub(result);
                 if overflow {
                     self.C = 0;
@@ -705,18 +802,20 @@ impl Cpu {
             }
             Instruction::ISC(_, addressing, _) => {
                 // INC
-                let result = addressing.fetch(memory).wrapping_add(1);
+                let operand = addressing.fetch(memory);
+                let result = operand.wrapping_add(1);
                 self.set_result_flags(result);
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
 
                 // SBC
                 self.adc(!result);
             }
             Instruction::RLA(_, addressing, _) => {
-                let shifted = u16::from(addressing.fetch(memory)) << 1;
+                let operand = addressing.fetch(memory);
+                let shifted = u16::from(operand) << 1;
                 let result = (shifted & 0xFF) as u8 | (self.C & 1);
                 self.C = (shifted >> 8) as u8;
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
 
                 let and_result = self.A & result;
                 self.set_result_flags(and_result);
@@ -727,7 +826,7 @@ impl Cpu {
                 let operand = addressing.fetch(memory);
                 let result = operand >> 1 | (self.C << 7);
                 self.C = operand & 1;
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
                 self.set_result_flags(result);
 
                 // max value is 0x1FF. There is carry if > 0xFF.
@@ -735,10 +834,11 @@ impl Cpu {
             }
             Instruction::SLO(_, addressing, _) => {
                 // shift left one bit in memory
-                let shifted = u16::from(addressing.fetch(memory)) << 1;
+                let operand = addressing.fetch(memory);
+                let shifted = u16::from(operand) << 1;
                 let result = (shifted & 0xFF) as u8;
                 self.C = (shifted >> 8) as u8;
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
 
                 // OR With A.
                 let or_result = self.A | result;
@@ -750,14 +850,75 @@ impl Cpu {
                 let operand = addressing.fetch(memory);
                 self.C = operand & 1;
                 let result = operand >> 1;
-                addressing.set(memory, result);
+                Cpu::write_back(memory, addressing, operand, result);
 
                 // EOR With A
                 let eor_result = self.A ^ result;
                 self.set_result_flags(eor_result);
                 self.A = eor_result;
             }
-            Instruction::UNKNOWN(_, _) => {}
+            // Unstable opcodes. Always executed, whatever the illegal opcode
+            // policy.
+            Instruction::SHY(_, addressing, _) => {
+                let y = self.Y;
+                self.store_and_high_byte(memory, addressing, self.X, y);
+            }
+            Instruction::SHX(_, addressing, _) => {
+                let x = self.X;
+                self.store_and_high_byte(memory, addressing, self.Y, x);
+            }
+            Instruction::TAS(_, addressing, _) => {
+                self.SP = self.A & self.X;
+                let sp = self.SP;
+                self.store_and_high_byte(memory, addressing, self.Y, sp);
+            }
+            Instruction::AHX(_, addressing, _) => {
+                let value = self.A & self.X;
+                self.store_and_high_byte(memory, addressing, self.Y, value);
+            }
+            Instruction::XAA(_, addressing, _) => {
+                let result = (self.A | 0xEE) & self.X & addressing.fetch(memory);
+                self.A = result;
+                self.set_result_flags(result);
+            }
+            Instruction::LXA(_, addressing, _) => {
+                let result = (self.A | 0xEE) & addressing.fetch(memory);
+                self.A = result;
+                self.X = result;
+                self.set_result_flags(result);
+            }
+            Instruction::LAS(_, addressing, _) => {
+                let result = addressing.fetch(memory) & self.SP;
+                self.A = result;
+                self.X = result;
+                self.SP = result;
+                self.set_result_flags(result);
+            }
+            Instruction::UNKNOWN(line, opcode) => {
+                let is_kil = KIL_OPCODES.contains(opcode);
+                match self.illegal_opcode_policy {
+                    IllegalOpcodePolicy::Halt => {
+                        self.PC = *line;
+                        return Err(if is_kil {
+                            "CPU jammed by a KIL opcode"
+                        } else {
+                            "Unknown opcode"
+                        });
+                    }
+                    IllegalOpcodePolicy::Continue if is_kil => {
+                        if !self.jammed {
+                            warn!("CPU jammed by opcode 0x{:x} at 0x{:x}", opcode, line);
+                            self.jammed = true;
+                        }
+                        self.PC = *line;
+                        // Time still passes for the rest of the console.
+                        again_extra_cycles = 2;
+                    }
+                    IllegalOpcodePolicy::Continue => {
+                        warn!("Unknown opcode 0x{:x} at 0x{:x}", opcode, line);
+                    }
+                }
+            }
         };
 
         let total_cycles =
@@ -779,9 +940,46 @@ impl Cpu {
         self.N = result >> 7;
     }
 
+    // Read-modify-write instructions write the unmodified value back before
+    // writing the result on the next cycle. PPU registers see both writes,
+    // MMC1 ignores the second one.
+    fn write_back(memory: &mut Memory, addressing: &MySavior, original: u8, result: u8) {
+        let address = addressing.address(memory) as usize;
+        memory.set(address, original);
+        memory.set_consecutive(address, result);
+    }
+
+    // Store for the SH* family. The value is ANDed with the high byte of the
+    // base address + 1. When crossing a page, the result also replaces the
+    // high byte of the target address.
+    fn store_and_high_byte(
+        &mut self,
+        memory: &mut Memory,
+        addressing: &MySavior,
+        index: u8,
+        value: u8,
+    ) {
+        let address = addressing.address(memory);
+        let base = address.wrapping_sub(u16::from(index));
+        let high_byte = (base >> 8) as u8;
+        let result = value & high_byte.wrapping_add(1);
+
+        let target = if (base & 0xFF00) != (address & 0xFF00) {
+            (u16::from(result) << 8) | (address & 0xFF)
+        } else {
+            address
+        };
+        memory.set(target as usize, result);
+    }
+
     // Get next instruction and increment PC
     pub fn advance(&mut self, memory: &mut Memory) -> u8 {
-        let code = memory.get(self.PC as usize);
+        self.advance_with(&mut |addr| memory.get(addr as usize))
+    }
+
+    /// Same as `advance`, the byte is read with `read`.
+    pub fn advance_with<F: FnMut(u16) -> u8>(&mut self, read: &mut F) -> u8 {
+        let code = read(self.PC);
         self.PC += 1;
         code
     }
@@ -815,6 +1013,7 @@ mod tests {
     // get names from outer scope.
     use super::*;
     use crate::rom::INesFile;
+    use crate::apu::write_log::WriteLog;
 
     fn new_memory(rom: Vec<u8>) -> Memory {
         let mut prg_rom = vec![0; 0x4000];
@@ -837,6 +1036,109 @@ mod tests {
         Memory::new(&ines).unwrap()
     }
 
+    #[test]
+    fn test_LAS() {
+        // LAS $0300,Y
+        let code = vec![0xBB, 0x00, 0x03];
+
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+        nes.Y = 0x05;
+        nes.SP = 0xF3;
+        memory.set(0x0305, 0x9E);
+        let cycles = nes.next(&mut memory).unwrap();
+
+        assert_eq!(4, cycles);
+        assert_eq!(0x92, nes.A);
+        assert_eq!(0x92, nes.X);
+        assert_eq!(0x92, nes.SP);
+        assert_eq!(1, nes.N);
+        assert_eq!(0, nes.Z);
+    }
+
+    #[test]
+    fn test_LAS_zero() {
+        let code = vec![0xBB, 0x00, 0x03];
+
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+        nes.SP = 0xF0;
+        memory.set(0x0300, 0x0F);
+        nes.next(&mut memory).unwrap();
+
+        assert_eq!(0, nes.A);
+        assert_eq!(0, nes.SP);
+        assert_eq!(0, nes.N);
+        assert_eq!(1, nes.Z);
+    }
+
+    #[test]
+    fn test_LXA() {
+        let code = vec![0xAB, 0x3C];
+
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+        nes.A = 0x01;
+        nes.next(&mut memory).unwrap();
+
+        // (0x01 | 0xEE) & 0x3C
+        assert_eq!(0x2C, nes.A);
+        assert_eq!(0x2C, nes.X);
+        assert_eq!(0, nes.N);
+        assert_eq!(0, nes.Z);
+    }
+
+    #[test]
+    fn test_AXS() {
+        let code = vec![0xCB, 0x05, 0xCB, 0x10];
+
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+        nes.A = 0x0F;
+        nes.X = 0x3C;
+        nes.next(&mut memory).unwrap();
+        // (0x0F & 0x3C) - 5
+        assert_eq!(0x07, nes.X);
+        assert_eq!(1, nes.C);
+
+        nes.next(&mut memory).unwrap();
+        // 0x07 - 0x10 borrows
+        assert_eq!(0xF7, nes.X);
+        assert_eq!(0, nes.C);
+        assert_eq!(1, nes.N);
+    }
+
+    #[test]
+    fn kil_opcode_with_halt_policy() {
+        let code = vec![0x02];
+
+        let mut nes = Cpu::new();
+        nes.set_illegal_opcode_policy(IllegalOpcodePolicy::Halt);
+        let mut memory = new_memory(code);
+
+        assert!(nes.next(&mut memory).is_err());
+        assert_eq!(0x8000, nes.PC);
+    }
+
+    #[test]
+    fn kil_opcode_jams_cpu() {
+        let code = vec![0xB2, 0xA9, 0x36];
+
+        let mut nes = Cpu::new();
+        nes.set_illegal_opcode_policy(IllegalOpcodePolicy::Continue);
+        let mut memory = new_memory(code);
+
+        for _ in 0..3 {
+            assert!(nes.next(&mut memory).unwrap() > 0);
+            assert_eq!(0x8000, nes.PC);
+            assert!(nes.jammed);
+        }
+        assert_eq!(0, nes.A);
+
+        nes.reset(&mut memory);
+        assert!(!nes.jammed);
+    }
+
     #[test]
     fn test_LDA_immediate_no_flag() {
         // Load accumulator. Immediate addressing
@@ -954,6 +1256,59 @@ mod tests {
         assert_eq!(1, nes.V);
     }
 
+    // Reference SBC from the 6502 spec: A - M - (1 - C) on signed values.
+    fn reference_sbc(a: u8, m: u8, c: u8) -> (u8, u8, u8) {
+        let unsigned = i16::from(a) - i16::from(m) - i16::from(1 - c);
+        let signed = i16::from(a as i8) - i16::from(m as i8) - i16::from(1 - c);
+        let carry = (unsigned >= 0) as u8;
+        let overflow = !(-128..=127).contains(&signed) as u8;
+        (unsigned as u8, carry, overflow)
+    }
+
+    #[test]
+    fn test_SBC_overflow_edge_cases() {
+        // SBC #$B0
+        let code = vec![0xE9, 0xB0];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        // 0x50 - 0xB0 = 80 - (-80) = 160: overflow.
+        nes.A = 0x50;
+        nes.C = 1;
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0xA0, nes.A);
+        assert_eq!(0, nes.C);
+        assert_eq!(1, nes.V);
+    }
+
+    #[test]
+    fn test_SBC_flags_for_all_operands() {
+        // SBC $00
+        let code = vec![0xE5, 0x00];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        for a in 0..=255u8 {
+            for m in 0..=255u8 {
+                for c in 0..=1 {
+                    nes.PC = 0x8000;
+                    nes.A = a;
+                    nes.C = c;
+                    memory.set(0x00, m);
+                    nes.next(&mut memory).unwrap();
+                    assert_eq!(
+                        reference_sbc(a, m, c),
+                        (nes.A, nes.C, nes.V),
+                        "{:02X} - {:02X} with C={}",
+                        a,
+                        m,
+                        c
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_AND() {
         let code = vec![0xA9, 0x64, 0x29, 0xA0];
@@ -1690,4 +2045,259 @@ mod tests {
         assert_eq!(0x42, memory.get(0xD1));
     }
 
+    #[test]
+    fn test_reset() {
+        let mut code = vec![0; 0x4000];
+        // reset vector -> $C123
+        code[0x3FFC] = 0x23;
+        code[0x3FFD] = 0xC1;
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        nes.PC = 0x8456;
+        nes.I = 0;
+        nes.reset(&mut memory);
+        assert_eq!(0xC123, nes.PC);
+        assert_eq!(0xFA, nes.SP);
+        assert_eq!(1, nes.I);
+    }
+
+    #[test]
+    fn test_inc_writes_unmodified_value_first() {
+        // INC $2007
+        let code = vec![0xEE, 0x07, 0x20];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        // $10 at $2100, then fill the read buffer with it.
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0x00);
+        memory.set(0x2007, 0x10);
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0x00);
+        memory.get(0x2007);
+
+        // Each write to $2007 increments the VRAM address so both writes
+        // are visible.
+        nes.next(&mut memory).unwrap();
+        let ppu_mem = &memory.ppu_mem;
+        assert_eq!(0x10, ppu_mem.read_vram_at(0x2102, &memory.mapper));
+        assert_eq!(0x11, ppu_mem.read_vram_at(0x2103, &memory.mapper));
+    }
+
+    #[test]
+    fn test_inc_write_order() {
+        // INC $4000
+        let code = vec![0xEE, 0x00, 0x40];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+        let path = std::env::temp_dir().join("nesemu_test_inc_write_order.csv");
+        memory.apu_mem.write_log = Some(WriteLog::create(&path).unwrap());
+
+        // $4000 is write-only, the INC reads 0.
+        nes.next(&mut memory).unwrap();
+        memory.apu_mem.write_log.take().unwrap().finish().unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let writes: Vec<&str> = content.lines().skip(1).collect();
+        assert_eq!(vec!["0,4000,00", "0,4000,01"], writes);
+    }
+
+    #[test]
+    fn test_rmw_resets_mmc1_once() {
+        // INC $8010, then select the PRG bank 1.
+        let mut prg_rom = vec![0xFF; 0x8000];
+        prg_rom[..3].copy_from_slice(&[0xEE, 0x10, 0x80]);
+        let ines = INesFile::new(
+            prg_rom,
+            2,
+            vec![0; 0x2000],
+            1,
+            0,
+            0x10,
+            0,
+            0,
+            0,
+            String::from("mmc1"),
+        );
+        let mut memory = Memory::new(&ines).unwrap();
+        let mut nes = Cpu::new();
+
+        // Half loaded shift register.
+        memory.set(0xE000, 0);
+        memory.set(0xE000, 0);
+
+        // Reads $FF, writes $FF (reset) then $00, which is ignored.
+        nes.next(&mut memory).unwrap();
+        for bit in &[1, 0, 0, 0, 0] {
+            memory.set(0xE000, *bit);
+        }
+        assert_eq!(1, memory.mapper.current_prg_banks()[0]);
+    }
+
+    #[test]
+    fn test_shy() {
+        // SHY $0500,X
+        let code = vec![0x9C, 0x00, 0x05];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        nes.X = 0x10;
+        nes.Y = 0xFF;
+        nes.next(&mut memory).unwrap();
+        // Y & (0x05 + 1)
+        assert_eq!(0x06, memory.get(0x0510));
+    }
+
+    #[test]
+    fn test_shx() {
+        // SHX $0600,Y
+        let code = vec![0x9E, 0x00, 0x06];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        nes.Y = 0x20;
+        nes.X = 0xFF;
+        nes.next(&mut memory).unwrap();
+        // X & (0x06 + 1)
+        assert_eq!(0x07, memory.get(0x0620));
+    }
+
+    #[test]
+    fn test_tas() {
+        // TAS $0600,Y
+        let code = vec![0x9B, 0x00, 0x06];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        nes.A = 0xF3;
+        nes.X = 0x3F;
+        nes.Y = 0x01;
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x33, nes.SP);
+        assert_eq!(0x03, memory.get(0x0601));
+    }
+
+    #[test]
+    fn test_xaa() {
+        let code = vec![0x8B, 0x0F];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        nes.A = 0x00;
+        nes.X = 0xFF;
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x0E, nes.A);
+        assert_eq!(0, nes.Z);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_boundary() {
+        // JMP ($02FF)
+        let code = vec![0x6C, 0xFF, 0x02];
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        memory.set(0x02FF, 0x00);
+        memory.set(0x0300, 0x90);
+        // The MSB is read from $0200, not $0300.
+        memory.set(0x0200, 0xC0);
+        let cycles = nes.next(&mut memory).unwrap();
+        assert_eq!(5, cycles);
+        assert_eq!(0xC000, nes.PC);
+    }
+
+    #[test]
+    fn test_request_irq() {
+        // NOP at $8000 and at the IRQ handler ($9234)
+        let mut code = vec![0; 0x4000];
+        code[0x0000] = 0xEA;
+        code[0x1234] = 0xEA;
+        code[0x1235] = 0xEA;
+        code[0x3FFE] = 0x34;
+        code[0x3FFF] = 0x92;
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        // Ignored while I is set.
+        memory.request_irq();
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x8001, nes.PC);
+
+        nes.PC = 0x8000;
+        nes.I = 0;
+        let cycles = nes.next(&mut memory).unwrap();
+        assert_eq!(7 + 2, cycles);
+        assert_eq!(0x9235, nes.PC);
+        assert_eq!(1, nes.I);
+        // Return address on the stack.
+        assert_eq!(0x80, memory.get(0x100 + nes.SP as usize + 3));
+        assert_eq!(0x00, memory.get(0x100 + nes.SP as usize + 2));
+
+        memory.clear_irq();
+        nes.I = 0;
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x9236, nes.PC);
+    }
+
+    #[test]
+    fn test_request_nmi() {
+        let mut code = vec![0xEA; 0x4000];
+        code[0x3FFA] = 0x00;
+        code[0x3FFB] = 0x90;
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        // Handled once, even with I set and the line still high.
+        memory.request_nmi();
+        assert!(memory.nmi());
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x9001, nes.PC);
+        assert!(memory.nmi());
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x9002, nes.PC);
+
+        // New edge
+        memory.clear_nmi();
+        nes.next(&mut memory).unwrap();
+        memory.request_nmi();
+        nes.PC = 0x8000;
+        nes.next(&mut memory).unwrap();
+        assert_eq!(0x9001, nes.PC);
+    }
+
+    #[test]
+    fn test_decompile_does_not_clear_vblank() {
+        use crate::ppu::memory::RegisterType;
+        let mut memory = Memory::default();
+        memory.ppu_mem.update(RegisterType::PPUSTATUS, 0x90);
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x2002);
+
+        // $90 is BCC with the operand at $2003.
+        cpu.decompile(&memory);
+        assert_eq!(0x2004, cpu.get_pc());
+        assert_eq!(0x90, memory.peek(0x2002));
+        assert_eq!(0x90, memory.get(0x2002));
+    }
+
+    #[test]
+    fn test_held_nmi_line_enters_handler_once() {
+        // The handler at $9000 is INX; RTI
+        let mut code = vec![0xEA; 0x4000];
+        code[0x1000] = 0xE8;
+        code[0x1001] = 0x40;
+        code[0x3FFA] = 0x00;
+        code[0x3FFB] = 0x90;
+        let mut nes = Cpu::new();
+        let mut memory = new_memory(code);
+
+        memory.request_nmi();
+        for _ in 0..20 {
+            nes.next(&mut memory).unwrap();
+        }
+        assert_eq!(1, nes.X);
+        assert!(nes.PC > 0x8000 && nes.PC < 0x9000);
+    }
 }
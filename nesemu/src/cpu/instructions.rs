@@ -30,13 +30,24 @@ macro_rules! instructions {
 
         impl Instruction {
             pub fn decode(nes: &mut Cpu, memory: &mut Memory) -> Instruction {
+                Instruction::decode_with(nes, &mut |addr| memory.get(addr as usize))
+            }
+
+            /// Same as `decode` but the bytes are read with `Memory::peek`,
+            /// so disassembling does not touch the registers. PC still
+            /// moves to the next instruction.
+            pub fn decode_peek(nes: &mut Cpu, memory: &Memory) -> Instruction {
+                Instruction::decode_with(nes, &mut |addr| memory.peek(addr as usize))
+            }
+
+            fn decode_with<F: FnMut(u16) -> u8>(nes: &mut Cpu, read: &mut F) -> Instruction {
                 let line = nes.get_pc();
-                let opcode = nes.advance(memory);
+                let opcode = nes.advance_with(read);
                 match opcode {
                 $(
                     $(
                         $code => Instruction::$name(line,
-                                                    MySavior::new($other, nes, memory),
+                                                    MySavior::new($other, nes, read),
                                                     $cost)
                     ),+
                 ),+
@@ -596,6 +607,13 @@ instructions! {
         0x4B => (Immediate, 2)
     },
 
+    // AXS
+    // AND X register with accumulator then subtract byte without borrow.
+    // X = (A & X) - byte. Carry is set like CMP. Stable on all chips.
+    AXS => {
+        0xCB => (Immediate, 2)
+    },
+
     // LAX
     // Load accumulator and X register with memory.
     // LDA then TAX
@@ -696,6 +714,51 @@ instructions! {
         0x53 => (PostIndexedIndirect, 8)
     },
 
+    // UNSTABLE INSTRUCTIONS - Behaviour depends on the chip (and even
+    // temperature...). The commonly documented behaviour is implemented.
+    // They are not affected by the illegal opcode policy of the CPU.
+
+    // SHY - AND Y register with the high byte of the target address + 1 and
+    // store the result in memory.
+    SHY => {
+        0x9C => (AbsoluteX, 5)
+    },
+
+    // SHX - AND X register with the high byte of the target address + 1 and
+    // store the result in memory.
+    SHX => {
+        0x9E => (AbsoluteY, 5)
+    },
+
+    // TAS - AND X register with accumulator and store the result in SP. Then
+    // AND SP with the high byte of the target address + 1 and store in memory.
+    TAS => {
+        0x9B => (AbsoluteY, 5)
+    },
+
+    // AHX - AND X register with accumulator then AND with the high byte of
+    // the target address + 1 and store in memory.
+    AHX => {
+        0x9F => (AbsoluteY, 5),
+        0x93 => (PostIndexedIndirect, 6)
+    },
+
+    // XAA - A = (A | magic) & X & immediate. Magic constant varies between
+    // chips. 0xEE is used here.
+    XAA => {
+        0x8B => (Immediate, 2)
+    },
+
+    // LXA - A = X = (A | magic) & immediate. Same magic constant as XAA.
+    LXA => {
+        0xAB => (Immediate, 2)
+    },
+
+    // LAS - AND memory with SP and store the result in A, X and SP.
+    LAS => {
+        0xBB => (AbsoluteY, 4)
+    },
+
     // Triple nop. Read a value and od nothing.
     TOP => {
         0x0C => (Absolute, 4),
@@ -1,4 +1,7 @@
 use crate::apu::ApuMemory;
+use crate::cheat::Cheat;
+use crate::cpu::watch::{AccessKind, Watches};
+use crate::error::NesError;
 use crate::joypad::Joypad;
 use crate::mapper;
 use crate::ppu::memory::{PpuMemory, RegisterType};
@@ -29,6 +32,10 @@ pub struct Memory {
     // $4020-$FFFF  $BFE0   Cartridge space: PRG ROM, PRG RAM, and mapper registers (See Note)
     pub mem: Vec<u8>, // 0x10000,
 
+    // PRG-RAM is stored at $6000 in `mem`. Smaller RAMs are mirrored in
+    // $6000-$7FFF.
+    prg_ram_size: usize,
+
     // Memory of PPU
     // -------------
     pub ppu_mem: PpuMemory,
@@ -43,8 +50,57 @@ pub struct Memory {
     pub joypad_p2: Joypad,
 
     pub mapper: mapper::MapperType,
+
+    // Active Game Genie codes. Applied when reading PRG ROM.
+    #[serde(default)]
+    pub cheats: Vec<Cheat>,
+
+    // Debugger watches, not part of the state.
+    #[serde(skip)]
+    pub watches: Watches,
+
+    // Joypad register read by the current instruction, if any.
+    #[serde(skip)]
+    pub last_joypad_read: Option<u16>,
+
+    // $4014 was written by the current instruction. The copy to OAM is
+    // done immediately but the CPU must be stalled during the transfer.
+    #[serde(skip)]
+    oam_dma: bool,
+
+    // Last value read or written on the CPU data bus. Returned when reading
+    // an address that no device drives.
+    open_bus: u8,
+
+    // Interrupt lines raised with request_irq and request_nmi, for devices
+    // that are not wired to the CPU (expansion audio, tests...).
+    external_irq: bool,
+    external_nmi: bool,
 }
 
+/// Memory areas shown by the debugger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemRegion {
+    /// 2KB of CPU RAM ($0000-$07FF).
+    InternalRam,
+    /// 256 bytes of sprite data.
+    Oam,
+    /// 32 bytes of palette RAM ($3F00-$3F1F).
+    Palette,
+    /// Logical nametable 0 to 3 ($2000, $2400, $2800, $2C00), after
+    /// mirroring. Includes the attribute table.
+    Nametable(u8),
+    /// Pattern table 0 ($0000) or 1 ($1000).
+    PatternTable(usize),
+}
+
+// Upper bits of $4016/$4017 are open bus. They keep the last value on the data
+// bus which is the high byte of the address.
+const JOYPAD_OPEN_BUS: u8 = 0x40;
+
+// Size of the $6000-$7FFF window.
+const PRG_RAM_WINDOW: usize = 0x2000;
+
 fn new_empty_mapper() -> mapper::MapperType {
     mapper::MapperType::Nrom(mapper::nrom::Nrom::new())
 }
@@ -53,17 +109,25 @@ impl Default for Memory {
     fn default() -> Memory {
         Memory {
             mem: vec![0; 0x10000],
+            prg_ram_size: PRG_RAM_WINDOW,
             apu_mem: ApuMemory::default(),
             ppu_mem: PpuMemory::new(),
             joypad_p1: Joypad::new(),
             joypad_p2: Joypad::new(),
             mapper: new_empty_mapper(),
+            cheats: vec![],
+            watches: Watches::default(),
+            last_joypad_read: None,
+            oam_dma: false,
+            open_bus: 0,
+            external_irq: false,
+            external_nmi: false,
         }
     }
 }
 
 impl Memory {
-    pub fn new(ines: &rom::INesFile) -> Result<Memory, String> {
+    pub fn new(ines: &rom::INesFile) -> Result<Memory, NesError> {
         let mut mem = vec![0; 0x10000];
 
         let mapper = mapper::create_mapper(ines)?;
@@ -71,33 +135,67 @@ impl Memory {
         let page_nb = ines.get_prg_rom_pages();
 
         if page_nb == 1 {
-            let page = ines.get_prg_rom(1)?;
+            let page = ines.get_prg_rom(0).map_err(NesError::RomParse)?;
             for (i, b) in page.iter().enumerate() {
                 mem[0x8000 + i] = *b;
                 mem[0xC000 + i] = *b;
             }
         } else {
-            let page = ines.get_prg_rom(1)?;
+            let page = ines.get_prg_rom(0).map_err(NesError::RomParse)?;
             for (i, b) in page.iter().enumerate() {
                 mem[0x8000 + i] = *b;
             }
-            let page2 = ines.get_prg_rom(2)?;
+            let page2 = ines.get_prg_rom(1).map_err(NesError::RomParse)?;
             for (i, b) in page2.iter().enumerate() {
                 mem[0xC000 + i] = *b;
             }
         }
 
+        // Banked PRG-RAM bigger than the window is not supported.
+        let mut prg_ram_size = ines.prg_ram_bytes().min(PRG_RAM_WINDOW);
+
+        // The trainer is in PRG-RAM at $7000-$71FF, so the whole window
+        // must be RAM.
+        if let Some(trainer) = ines.trainer() {
+            prg_ram_size = PRG_RAM_WINDOW;
+            mem[0x7000..0x7200].copy_from_slice(trainer);
+        }
+
         // Now the PPU ROM and init
         let ppu_mem = PpuMemory::new();
         Ok(Memory {
             mem,
+            prg_ram_size,
             ppu_mem,
             mapper,
             ..Default::default()
         })
     }
 
+    /// Memory around a cartridge that was not loaded from an iNES file.
+    pub fn with_mapper(mapper: mapper::MapperType) -> Memory {
+        Memory {
+            mapper,
+            ..Default::default()
+        }
+    }
+
     pub fn set(&mut self, address: usize, value: u8) {
+        self.write(address, value, false);
+    }
+
+    /// Write on the cycle right after another write. Only the mapper
+    /// behaves differently (see `MapperType::write_prg_consecutive`).
+    pub fn set_consecutive(&mut self, address: usize, value: u8) {
+        self.write(address, value, true);
+    }
+
+    fn write(&mut self, address: usize, value: u8, consecutive: bool) {
+        if !self.watches.is_empty() {
+            self.watches.check(address as u16, AccessKind::Write, value);
+        }
+        self.open_bus = value;
+
         match address {
             0x00..=0x1FFF => self.mem[address & 0x7FFF] = value,
             // These are the PPU registers
@@ -120,14 +218,24 @@ impl Memory {
             // PPU
             0x4014 => {
                 self.ppu_mem.write_oamdma(&self.mem, value);
+                self.oam_dma = true;
             }
             0x4016 => {
                 self.joypad_p1.write(value);
                 self.joypad_p2.write(value);
             }
+            // APU and I/O test registers, disabled on retail consoles.
+            0x4018..=0x401F => (),
             //0x4017 => {
             //    self.joypad_p2.write(value);
             //},
+            0x6000..=0x7FFF => {
+                let address = self.prg_ram_address(address);
+                self.mem[address] = value;
+            }
+            0x8000..=0xFFFF if consecutive => {
+                self.mapper.write_prg_consecutive(address, value);
+            }
             0x8000..=0xFFFF => {
                 self.mapper.write_prg(address, value);
             }
@@ -136,6 +244,24 @@ impl Memory {
     }
 
     pub fn get(&mut self, address: usize) -> u8 {
+        let value = self.read(address);
+        self.open_bus = value;
+        if !self.watches.is_empty() {
+            self.watches.check(address as u16, AccessKind::Read, value);
+        }
+        value
+    }
+
+    /// Called by the CPU before fetching the opcode at `pc`.
+    pub fn start_instruction(&mut self, pc: u16) {
+        if !self.watches.is_empty() {
+            self.watches.set_pc(pc);
+            let opcode = self.peek(pc as usize);
+            self.watches.check(pc, AccessKind::Execute, opcode);
+        }
+    }
+
+    fn read(&mut self, address: usize) -> u8 {
         match address {
             0..=0x1FFF => {
                 // RAM with mirrors
@@ -155,13 +281,33 @@ impl Memory {
             }
             0x4014 => self.ppu_mem.read(RegisterType::OAMDMA, &self.mapper),
             0x4015 => self.apu_mem.read(),
-            0x4016 => self.joypad_p1.read(),
-            0x4017 => self.joypad_p2.read(),
-            0x8000..=0xFFFF => self.mapper.read_prg(address),
+            0x4016 => {
+                self.last_joypad_read = Some(0x4016);
+                JOYPAD_OPEN_BUS | self.joypad_p1.read()
+            }
+            0x4017 => {
+                self.last_joypad_read = Some(0x4017);
+                JOYPAD_OPEN_BUS | self.joypad_p2.read()
+            }
+            0x4018..=0x401F => self.open_bus,
+            0x6000..=0x7FFF => self.mem[self.prg_ram_address(address)],
+            0x8000..=0xFFFF => self.read_prg(address),
             _ => self.mem[address],
         }
     }
 
+    // Address in `mem` of the PRG-RAM byte mapped at `address`.
+    fn prg_ram_address(&self, address: usize) -> usize {
+        0x6000 + (address - 0x6000) % self.prg_ram_size
+    }
+
+    fn read_prg(&self, address: usize) -> u8 {
+        let value = self.mapper.read_prg(address);
+        self.cheats
+            .iter()
+            .fold(value, |value, cheat| cheat.apply(address as u16, value))
+    }
+
     pub fn read_vram_at(&self, addr: usize) -> u8 {
         self.ppu_mem.read_vram_at(addr, &self.mapper)
     }
@@ -174,25 +320,100 @@ impl Memory {
         &self.ppu_mem.get_logical_table(nb, &self.mapper)
     }
 
+    /// Content of a memory area, without side effect.
+    pub fn region(&self, region: MemRegion) -> &[u8] {
+        match region {
+            MemRegion::InternalRam => &self.mem[..0x800],
+            MemRegion::Oam => &self.ppu_mem.oam,
+            MemRegion::Palette => &self.ppu_mem.palettes,
+            MemRegion::Nametable(nb) => self.get_logical_table(nb & 0b11),
+            MemRegion::PatternTable(half) => self.get_pattern_table(half & 1),
+        }
+    }
+
     pub fn nmi(&self) -> bool {
-        self.ppu_mem.get_nmi_occured()
+        self.external_nmi || self.ppu_mem.get_nmi_occured()
+    }
+
+    /// Called when the CPU handles the NMI. Acknowledges the PPU NMI.
+    pub fn consume_nmi(&mut self) {
+        self.ppu_mem.consume_nmi();
+    }
+
+    /// Pull the NMI line until `clear_nmi`. NMI is edge triggered: the CPU
+    /// handles it once after the current instruction, even if the line
+    /// stays asserted.
+    pub fn request_nmi(&mut self) {
+        self.external_nmi = true;
+    }
+
+    pub fn clear_nmi(&mut self) {
+        self.external_nmi = false;
+    }
+
+    /// True once after a write to $4014. The caller should stall the CPU
+    /// for the duration of the OAM DMA.
+    pub fn take_oam_dma(&mut self) -> bool {
+        std::mem::replace(&mut self.oam_dma, false)
+    }
+
+    /// IRQ is level triggered. It stays asserted until `clear_irq`, and the
+    /// CPU handles it every time the I flag is clear.
+    pub fn request_irq(&mut self) {
+        self.external_irq = true;
+    }
+
+    pub fn clear_irq(&mut self) {
+        self.external_irq = false;
     }
 
     pub fn irq(&self) -> bool {
+        if self.external_irq {
+            return true;
+        }
+
         if let mapper::MapperType::Mmc3(ref x) = self.mapper {
-            return x.irq;
+            if x.irq {
+                return true;
+            }
         }
 
-        false
+        self.apu_mem.dmc_irq() || self.apu_mem.frame_irq()
     }
 
     // Will read without modifying the value. For example, a read to $2002 is supposed
     // to change a flag. Peek will not. This is used for debugging
     pub fn peek(&self, address: usize) -> u8 {
-        if address < 0x2000 {
-            self.mem[address & 0x7FF]
-        } else {
-            self.mem[address]
+        match address {
+            0..=0x1FFF => self.mem[address & 0x7FF],
+            0x2000..=0x3FFF => {
+                let register_type = RegisterType::lookup(0x2000 + (address & 0x7))
+                    .expect("Issue with RegisterType::lookup");
+                self.ppu_mem.peek_read(register_type, &self.mapper)
+            }
+            0x4015 => self.apu_mem.peek(),
+            0x4016 => JOYPAD_OPEN_BUS | self.joypad_p1.peek(),
+            0x4017 => JOYPAD_OPEN_BUS | self.joypad_p2.peek(),
+            0x4018..=0x401F => self.open_bus,
+            0x6000..=0x7FFF => self.mem[self.prg_ram_address(address)],
+            0x8000..=0xFFFF => self.read_prg(address),
+            _ => self.mem[address],
+        }
+    }
+
+    // Counterpart of peek. Write to RAM, PRG-RAM or the PRG-ROM bank mapped at
+    // the address, without mapper or register side effects. I/O registers
+    // ($2000-$5FFF) have no backing store so the write is ignored.
+    pub fn poke(&mut self, address: usize, value: u8) {
+        match address {
+            0..=0x1FFF => self.mem[address & 0x7FF] = value,
+            0x2000..=0x5FFF => (),
+            0x6000..=0x7FFF => {
+                let address = self.prg_ram_address(address);
+                self.mem[address] = value;
+            }
+            0x8000..=0xFFFF => self.mapper.poke_prg(address, value),
+            _ => (),
         }
     }
 
@@ -217,6 +438,165 @@ mod tests {
         assert_eq!(0x10, memory.ppu_mem.peek(RegisterType::PPUSTATUS));
     }
 
+    #[test]
+    fn test_peek_ppustatus_no_side_effect() {
+        let mut memory: Memory = Default::default();
+        memory.ppu_mem.update(RegisterType::PPUSTATUS, 0x90);
+
+        assert_eq!(0x90, memory.peek(0x2002));
+        assert_eq!(0x90, memory.peek(0x2002));
+        assert_eq!(0x90, memory.peek(0x200A));
+        assert_eq!(0x90, memory.get(0x2002));
+        assert_eq!(0x10, memory.peek(0x2002));
+    }
+
+    #[test]
+    fn test_trainer_loaded_at_7000() {
+        // Trainer bit set, 1 PRG-ROM page, CHR-RAM.
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend((0..512).map(|i| (i % 251) as u8 + 1));
+        bytes.extend(vec![0; 0x4000]);
+        let ines = rom::from_bytes(String::from("trainer"), bytes).unwrap();
+        assert_eq!(Some(512), ines.trainer().map(|t| t.len()));
+
+        let mut memory = Memory::new(&ines).unwrap();
+        assert_eq!(1, memory.get(0x7000));
+        assert_eq!(2, memory.get(0x7001));
+        assert_eq!(0, memory.get(0x7200));
+        // PRG-ROM starts after the trainer.
+        assert_eq!(0, memory.get(0x8000));
+    }
+
+    #[test]
+    fn test_trainer_with_small_prg_ram() {
+        // NES 2.0 header with a trainer and 2KB of PRG-RAM.
+        let mut bytes = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x04, 0x08, 0, 0, 0x05, 0, 0, 0, 0, 0,
+        ];
+        bytes.extend((0..512).map(|i| (i % 251) as u8 + 1));
+        bytes.extend(vec![0; 0x4000]);
+        let ines = rom::from_bytes(String::from("trainer"), bytes).unwrap();
+        assert_eq!(0x800, ines.prg_ram_bytes());
+
+        let mut memory = Memory::new(&ines).unwrap();
+        assert_eq!(1, memory.get(0x7000));
+        assert_eq!(2, memory.peek(0x7001));
+        assert_eq!(0, memory.get(0x6000));
+    }
+
+    #[test]
+    fn test_peek_joypad_no_side_effect() {
+        let mut memory: Memory = Default::default();
+        memory.joypad_p1.button_down(&crate::joypad::InputAction::A);
+        memory.set(0x4016, 1);
+        memory.set(0x4016, 0);
+
+        assert_eq!(0x41, memory.peek(0x4016));
+        assert_eq!(0x41, memory.peek(0x4016));
+        assert_eq!(0x41, memory.get(0x4016));
+        assert_eq!(0x40, memory.peek(0x4016));
+    }
+
+    #[test]
+    fn test_cheat_compare() {
+        let mut memory: Memory = Default::default();
+        memory.mapper.write_prg(0x94A7, 0x03);
+        memory.mapper.write_prg(0x94A8, 0x03);
+        memory.cheats.push(Cheat::from_game_genie("ZEXPYGLA").unwrap());
+
+        assert_eq!(0x02, memory.get(0x94A7));
+        assert_eq!(0x03, memory.get(0x94A8));
+
+        // compare does not match anymore
+        memory.mapper.write_prg(0x94A7, 0x05);
+        assert_eq!(0x05, memory.get(0x94A7));
+    }
+
+    #[test]
+    fn test_joypad_read_open_bus() {
+        let mut memory: Memory = Default::default();
+        memory.joypad_p1.button_down(&crate::joypad::InputAction::A);
+        memory.set(0x4016, 1);
+        memory.set(0x4016, 0);
+
+        assert_eq!(0x41, memory.get(0x4016));
+        assert_eq!(0x40, memory.get(0x4016));
+        assert_eq!(0x40, memory.get(0x4017));
+    }
+
+    #[test]
+    fn test_small_prg_ram_is_mirrored() {
+        // NES 2.0 header with 2KB of PRG-RAM (64 << 5).
+        let ines = rom::INesFile::new(
+            vec![0; 0x4000],
+            1,
+            vec![0; 0x2000],
+            1,
+            0,
+            0,
+            0x08,
+            0,
+            0x05,
+            String::from("test"),
+        );
+        let mut memory = Memory::new(&ines).unwrap();
+        memory.set(0x6001, 0x42);
+        assert_eq!(0x42, memory.get(0x6801));
+        assert_eq!(0x42, memory.get(0x7001));
+        assert_eq!(0x42, memory.peek(0x7801));
+
+        memory.set(0x7FFF, 0x24);
+        assert_eq!(0x24, memory.get(0x67FF));
+        assert_eq!(0, memory.get(0x6800));
+    }
+
+    #[test]
+    fn test_joypads_shift_independently() {
+        use crate::joypad::{InputAction, JoypadState};
+        let mut memory: Memory = Default::default();
+        memory
+            .joypad_p1
+            .set_state(JoypadState::default().with(InputAction::A).with(InputAction::START));
+        memory
+            .joypad_p2
+            .set_state(JoypadState::default().with(InputAction::B).with(InputAction::RIGHT));
+        // $4016 strobes both controllers.
+        memory.set(0x4016, 1);
+        memory.set(0x4016, 0);
+
+        // Two reads of $4017 for one of $4016.
+        let mut p1 = vec![];
+        let mut p2 = vec![];
+        for _ in 0..4 {
+            p1.push(memory.get(0x4016) & 1);
+            p2.push(memory.get(0x4017) & 1);
+            p2.push(memory.get(0x4017) & 1);
+        }
+        for _ in 0..4 {
+            p1.push(memory.get(0x4016) & 1);
+        }
+        assert_eq!(vec![1, 0, 0, 1, 0, 0, 0, 0], p1);
+        assert_eq!(vec![0, 1, 0, 0, 0, 0, 0, 1], p2);
+    }
+
+    #[test]
+    fn test_apu_test_registers_open_bus() {
+        let mut memory: Memory = Default::default();
+        memory.set(0x4000, 0xBF);
+        for addr in 0x4018..=0x401F {
+            memory.set(addr, 0x1F);
+        }
+        assert_eq!(0xBF, memory.apu_mem.pulse_1_reg1);
+        assert_eq!(0, memory.apu_mem.peek());
+        assert!(memory.mem[0x4018..0x4020].iter().all(|b| *b == 0));
+
+        // Last value on the bus.
+        memory.mem[0x0010] = 0x42;
+        memory.get(0x0010);
+        assert_eq!(0x42, memory.get(0x4018));
+        assert_eq!(0x42, memory.peek(0x401F));
+    }
+
     #[test]
     fn test_set_nmi_status_then_ctrl() {
         let mut memory: Memory = Default::default();
@@ -224,6 +604,11 @@ mod tests {
         memory.ppu_mem.update(RegisterType::PPUSTATUS, 0x80);
         assert_eq!(false, memory.nmi());
         memory.set(0x2000, 0x90);
+        // Raised one CPU cycle after the write.
+        assert_eq!(false, memory.nmi());
+        for _ in 0..3 {
+            memory.ppu_mem.nmi_delay_tick();
+        }
         assert_eq!(true, memory.nmi());
     }
 
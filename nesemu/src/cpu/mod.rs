@@ -2,3 +2,5 @@ pub mod cpu;
 pub mod instructions;
 pub mod memory;
 pub mod addressing;
+pub mod trace;
+pub mod watch;
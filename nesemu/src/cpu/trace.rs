@@ -0,0 +1,88 @@
+// Trace of the last instructions executed by the CPU. Kept in memory so that
+// front-ends without a console (wasm) can show it. Disabled by default.
+use super::cpu::Cpu;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// State of the CPU before executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    /// CPU cycles since power on.
+    pub cycles: u64,
+    /// PPU dot in the scanline, the CYC column of nestest.log.
+    pub ppu_dot: u64,
+}
+
+impl TraceEntry {
+    pub fn new(cpu: &Cpu, opcode: u8) -> TraceEntry {
+        TraceEntry {
+            pc: cpu.get_pc(),
+            opcode,
+            a: cpu.get_acc(),
+            x: cpu.get_regx(),
+            y: cpu.get_regy(),
+            p: cpu.flags_to_u8_debug(),
+            sp: cpu.get_sp(),
+            cycles: cpu.cycles(),
+            ppu_dot: cpu.ppu_dot(),
+        }
+    }
+}
+
+// Same columns as the nestest log, without the disassembly. `{:#}` adds the
+// CYC column.
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.pc, self.opcode, self.a, self.x, self.y, self.p, self.sp
+        )?;
+        if f.alternate() {
+            write!(f, " CYC:{:>3}", self.ppu_dot)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep the last `capacity` entries.
+#[derive(Debug, Default)]
+pub struct Trace {
+    capacity: usize,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl Trace {
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Oldest entry first.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+}
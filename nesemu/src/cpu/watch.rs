@@ -0,0 +1,115 @@
+// Memory watches for debugging. The memory reports every CPU access to the
+// watch list, and the first access to a watched address is kept until the
+// front-end takes it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// The CPU fetched an opcode at the address.
+    Execute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub kind: AccessKind,
+    /// Address of the instruction that did the access.
+    pub pc: u16,
+    /// Value read, written or fetched.
+    pub value: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct Watches {
+    watches: Vec<(u16, AccessKind)>,
+    // Instruction being executed.
+    pc: u16,
+    hit: Option<WatchHit>,
+}
+
+// The internal RAM is mirrored up to $1FFF.
+fn normalize(addr: u16) -> u16 {
+    if addr < 0x2000 {
+        addr & 0x7FF
+    } else {
+        addr
+    }
+}
+
+impl Watches {
+    pub fn add(&mut self, addr: u16, kind: AccessKind) {
+        let watch = (normalize(addr), kind);
+        if !self.watches.contains(&watch) {
+            self.watches.push(watch);
+        }
+    }
+
+    pub fn remove(&mut self, addr: u16, kind: AccessKind) {
+        let watch = (normalize(addr), kind);
+        self.watches.retain(|w| *w != watch);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Set the address of the instruction being executed.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Record the access if the address is watched and no hit is pending.
+    pub fn check(&mut self, addr: u16, kind: AccessKind, value: u8) {
+        if self.hit.is_some() {
+            return;
+        }
+
+        let addr = normalize(addr);
+        if self.watches.contains(&(addr, kind)) {
+            self.hit = Some(WatchHit {
+                addr,
+                kind,
+                pc: self.pc,
+                value,
+            });
+        }
+    }
+
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_hit_is_kept() {
+        let mut watches = Watches::default();
+        watches.add(0x0300, AccessKind::Read);
+
+        watches.set_pc(0x8000);
+        watches.check(0x0300, AccessKind::Write, 1);
+        assert_eq!(None, watches.take_hit());
+
+        // Mirror of $0300.
+        watches.check(0x0B00, AccessKind::Read, 2);
+        watches.set_pc(0x8003);
+        watches.check(0x0300, AccessKind::Read, 3);
+        assert_eq!(
+            Some(WatchHit {
+                addr: 0x0300,
+                kind: AccessKind::Read,
+                pc: 0x8000,
+                value: 2
+            }),
+            watches.take_hit()
+        );
+        assert_eq!(None, watches.take_hit());
+
+        watches.remove(0x0300, AccessKind::Read);
+        assert!(watches.is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+// Errors returned when creating the console from a ROM or restoring a save.
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum NesError {
+    /// The iNES file is invalid (bad header, truncated...).
+    RomParse(String),
+    /// The ROM uses a mapper that is not implemented. Contains the iNES
+    /// mapper number.
+    UnsupportedMapper(u8),
+    /// The save state cannot be restored.
+    BadState(String),
+    Io(io::Error),
+    /// Any other error during emulation.
+    Emulation(String),
+    /// `Nes::run_frame_bounded` ran out of instructions before the end of
+    /// the frame.
+    WatchdogTripped,
+    /// The configuration file of the front-ends is invalid.
+    Config(String),
+}
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NesError::RomParse(msg) => write!(f, "invalid ROM: {}", msg),
+            NesError::UnsupportedMapper(id) => write!(f, "mapper {} is not supported", id),
+            NesError::BadState(msg) => write!(f, "invalid save state: {}", msg),
+            NesError::Io(err) => write!(f, "{}", err),
+            NesError::Emulation(msg) => write!(f, "{}", msg),
+            NesError::WatchdogTripped => {
+                write!(f, "instruction limit reached before the end of the frame")
+            }
+            NesError::Config(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl Error for NesError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NesError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NesError {
+    fn from(err: io::Error) -> Self {
+        NesError::Io(err)
+    }
+}
+
+impl From<png::EncodingError> for NesError {
+    fn from(err: png::EncodingError) -> Self {
+        match err {
+            png::EncodingError::IoError(err) => NesError::Io(err),
+            err => NesError::Emulation(err.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for NesError {
+    fn from(err: serde_json::Error) -> Self {
+        NesError::BadState(err.to_string())
+    }
+}
+
+impl From<bincode::Error> for NesError {
+    fn from(err: bincode::Error) -> Self {
+        NesError::BadState(err.to_string())
+    }
+}
+
+// Errors of the CPU/PPU and of the older functions are plain strings.
+impl From<String> for NesError {
+    fn from(err: String) -> Self {
+        NesError::Emulation(err)
+    }
+}
+
+impl From<&'static str> for NesError {
+    fn from(err: &'static str) -> Self {
+        NesError::Emulation(String::from(err))
+    }
+}
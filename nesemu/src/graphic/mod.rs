@@ -7,6 +7,8 @@ pub enum EmulatorInput {
     QUIT,
     DEBUG,
     SAVE,
+    SCREENSHOT,
+    RESET,
     INPUT(Player, InputAction, InputState),
 }
 
@@ -26,3 +28,90 @@ impl Color {
         Self { r, g, b }
     }
 }
+
+/// Shape of the NES pixels in screenshots.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PixelAspect {
+    /// One NES pixel is a square (256x240).
+    #[default]
+    Square,
+    /// NTSC TVs display pixels 8/7 wider than tall (292x240).
+    Ntsc,
+}
+
+impl PixelAspect {
+    /// Width of an image `width` NES pixels wide.
+    pub fn width(self, width: usize) -> usize {
+        match self {
+            PixelAspect::Square => width,
+            PixelAspect::Ntsc => width * 8 / 7,
+        }
+    }
+}
+
+/// Resize each row of a RGB image (3 bytes per pixel) to `new_width`
+/// pixels with linear interpolation. The first and last pixels of a row
+/// keep their color.
+pub fn resample_rows(rgb: &[u8], width: usize, new_width: usize) -> Vec<u8> {
+    if width == 0 || new_width == 0 {
+        return Vec::new();
+    }
+    let height = rgb.len() / (width * 3);
+    let mut out = Vec::with_capacity(new_width * height * 3);
+    let ratio = if new_width > 1 {
+        (width - 1) as f64 / (new_width - 1) as f64
+    } else {
+        0.0
+    };
+
+    for row in rgb.chunks_exact(width * 3).take(height) {
+        for x in 0..new_width {
+            let pos = x as f64 * ratio;
+            let left = pos.floor() as usize;
+            let right = (left + 1).min(width - 1);
+            let t = pos - left as f64;
+            for c in 0..3 {
+                let a = f64::from(row[left * 3 + c]);
+                let b = f64::from(row[right * 3 + c]);
+                out.push((a + (b - a) * t).round() as u8);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resample_row_to_ntsc_width() {
+        let width = 256;
+        let mut row = vec![0; width * 3];
+        row[..3].copy_from_slice(&[10, 20, 30]);
+        row[(width - 1) * 3..].copy_from_slice(&[200, 100, 50]);
+
+        let new_width = PixelAspect::Ntsc.width(width);
+        assert_eq!(292, new_width);
+        let out = resample_rows(&row, width, new_width);
+        assert_eq!(new_width * 3, out.len());
+        assert_eq!(&[10, 20, 30], &out[..3]);
+        assert_eq!(&[200, 100, 50], &out[(new_width - 1) * 3..]);
+    }
+
+    #[test]
+    fn resample_keeps_rows() {
+        let rgb: Vec<u8> = (0..4 * 2 * 3).map(|i| i as u8).collect();
+        let out = resample_rows(&rgb, 4, 4);
+        assert_eq!(rgb, out);
+        assert_eq!(2 * 7 * 3, resample_rows(&rgb, 4, 7).len());
+    }
+
+    #[test]
+    fn resample_empty_width() {
+        let rgb = vec![0; 4 * 3];
+        assert!(resample_rows(&rgb, 0, 4).is_empty());
+        assert!(resample_rows(&rgb, 4, 0).is_empty());
+        assert!(resample_rows(&[], 0, 0).is_empty());
+    }
+}
@@ -1,12 +1,12 @@
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Player {
     One,
     Two,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum InputAction {
     A,
     B,
@@ -24,6 +24,59 @@ pub enum InputState {
     Released,
 }
 
+/// State of all the buttons of a controller. One bit per button, in the
+/// order they are read: A, B, SELECT, START, UP, DOWN, LEFT, RIGHT (bit 0 to 7).
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct JoypadState(pub u8);
+
+impl JoypadState {
+    fn bit(action: InputAction) -> u8 {
+        match action {
+            InputAction::A => 0,
+            InputAction::B => 1,
+            InputAction::SELECT => 2,
+            InputAction::START => 3,
+            InputAction::UP => 4,
+            InputAction::DOWN => 5,
+            InputAction::LEFT => 6,
+            InputAction::RIGHT => 7,
+        }
+    }
+
+    /// Same state with the button pressed.
+    pub fn with(self, action: InputAction) -> JoypadState {
+        JoypadState(self.0 | (1 << JoypadState::bit(action)))
+    }
+
+    pub fn is_pressed(self, action: InputAction) -> bool {
+        (self.0 >> JoypadState::bit(action)) & 1 == 1
+    }
+}
+
+/// Device plugged in a controller port. It decides what is returned once
+/// the 8 buttons have been read.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum ControllerKind {
+    /// Official controller. Returns 1 after the 8 buttons.
+    #[default]
+    Standard,
+    /// Light gun. Only the trigger is emulated: the light sensor never sees
+    /// any light.
+    Zapper,
+    /// Nothing plugged. Reads return 0.
+    None,
+}
+
+impl ControllerKind {
+    /// Value of the read number `index` (8 or more) after a strobe.
+    fn read_after_buttons(self, _index: u8) -> u8 {
+        match self {
+            ControllerKind::Standard => 1,
+            ControllerKind::Zapper | ControllerKind::None => 0,
+        }
+    }
+}
+
 // The NES supports several different input devices, including joypads, Zapper (light guns), and
 // four-player devices.
 // Joypad #1 and #2 are read via $4016 and $4017, respectively.
@@ -56,8 +109,15 @@ pub struct Joypad {
     left: u8,
     right: u8,
 
-    // to reset the joypad
+    // Strobe level (bit 0 of the last write to $4016). While it is high,
+    // the joypad keeps reloading the button states so reads return A.
     reset_buf: u8,
+
+    #[serde(default)]
+    kind: ControllerKind,
+    // Zapper trigger, 0 or 1.
+    #[serde(default)]
+    trigger: u8,
 }
 
 impl Joypad {
@@ -73,19 +133,53 @@ impl Joypad {
             left: 0,
             right: 0,
             reset_buf: 0,
+            kind: ControllerKind::Standard,
+            trigger: 0,
         }
     }
 
+    pub fn set_kind(&mut self, kind: ControllerKind) {
+        self.kind = kind;
+    }
+
+    pub fn kind(&self) -> ControllerKind {
+        self.kind
+    }
+
+    pub fn set_trigger(&mut self, pressed: bool) {
+        self.trigger = pressed as u8;
+    }
+
     pub fn write(&mut self, value: u8) {
-        if self.reset_buf == 1 && value == 0 {
+        self.reset_buf = value & 1;
+        if self.reset_buf == 1 {
             self.current_index = 0;
         }
-
-        self.reset_buf = value;
     }
 
     pub fn read(&mut self) -> u8 {
-        let return_value = match self.current_index {
+        let return_value = self.peek();
+        if self.kind == ControllerKind::Standard && self.reset_buf == 0 {
+            self.current_index = self.current_index.saturating_add(1);
+        }
+        return_value
+    }
+
+    /// Value of the next read, without shifting.
+    pub fn peek(&self) -> u8 {
+        match self.kind {
+            ControllerKind::Standard => (),
+            // Bit 3: light not detected, bit 4: trigger.
+            ControllerKind::Zapper => return 0x08 | (self.trigger << 4),
+            ControllerKind::None => return 0,
+        }
+
+        if self.reset_buf == 1 {
+            // Strobe is high, always A.
+            return self.a;
+        }
+
+        match self.current_index {
             0 => self.a,
             1 => self.b,
             2 => self.select,
@@ -94,11 +188,21 @@ impl Joypad {
             5 => self.down,
             6 => self.left,
             7 => self.right,
-            _ => 1,
-        };
+            index => self.kind.read_after_buttons(index),
+        }
+    }
 
-        self.current_index += 1;
-        return_value
+    /// Set all the buttons at once.
+    pub fn set_state(&mut self, state: JoypadState) {
+        let bit = |action| state.is_pressed(action) as u8;
+        self.a = bit(InputAction::A);
+        self.b = bit(InputAction::B);
+        self.select = bit(InputAction::SELECT);
+        self.start = bit(InputAction::START);
+        self.up = bit(InputAction::UP);
+        self.down = bit(InputAction::DOWN);
+        self.left = bit(InputAction::LEFT);
+        self.right = bit(InputAction::RIGHT);
     }
 
     pub fn button_up(&mut self, button: &InputAction) {
@@ -127,3 +231,63 @@ impl Joypad {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strobe_high_returns_a() {
+        let mut joypad = Joypad::new();
+        joypad.button_down(&InputAction::A);
+        joypad.button_down(&InputAction::START);
+
+        joypad.write(1);
+        for _ in 0..5 {
+            assert_eq!(1, joypad.read());
+        }
+
+        joypad.write(0);
+        let expected = [1, 0, 0, 1, 0, 0, 0, 0];
+        for value in expected.iter() {
+            assert_eq!(*value, joypad.read());
+        }
+        // After 8 reads, official controllers return 1.
+        assert_eq!(1, joypad.read());
+    }
+
+    #[test]
+    fn set_state() {
+        let mut joypad = Joypad::new();
+        joypad.set_state(JoypadState::default().with(InputAction::B).with(InputAction::RIGHT));
+        joypad.write(1);
+        joypad.write(0);
+
+        let expected = [0, 1, 0, 0, 0, 0, 0, 1];
+        for value in expected.iter() {
+            assert_eq!(*value, joypad.read());
+        }
+    }
+
+    #[test]
+    fn standard_controller_returns_1_after_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.set_state(JoypadState::default().with(InputAction::SELECT));
+        joypad.write(1);
+        joypad.write(0);
+
+        let reads: Vec<u8> = (0..12).map(|_| joypad.read()).collect();
+        assert_eq!(vec![0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 1, 1], reads);
+    }
+
+    #[test]
+    fn zapper_trigger() {
+        let mut joypad = Joypad::new();
+        joypad.set_kind(ControllerKind::Zapper);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(0x08, joypad.read());
+        joypad.set_trigger(true);
+        assert_eq!(0x18, joypad.read());
+    }
+}
@@ -3,10 +3,16 @@
 extern crate log;
 
 pub mod apu;
+pub mod cheat;
+pub mod config;
 pub mod cpu;
+pub mod error;
 pub mod graphic;
 pub mod joypad;
 pub mod mapper;
 pub mod nes;
+pub mod perf;
 pub mod ppu;
+pub mod retro;
 pub mod rom;
+pub mod speed;
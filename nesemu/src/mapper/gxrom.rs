@@ -0,0 +1,171 @@
+use serde_derive::{Serialize, Deserialize};
+use super::{Mirroring, Multicart};
+use crate::rom::INesFile;
+
+// GxROM is mapper 66. A single register at $8000-$FFFF selects a 32kb PRG
+// bank (bits 4-5) and a 8kb CHR bank (bits 0-1). Some multicarts use it to
+// switch between games, so each PRG bank is also a menu entry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Gxrom {
+    prg_rom_banks: Vec<Vec<u8>>, // 32kb for each element
+    // CHR-RAM, or CHR-ROM if the cartridge has one.
+    chr_rom_banks: Vec<Vec<u8>>, // 8kb for each element
+    chr_read_only: bool,
+
+    prg_bank_idx: usize,
+    chr_bank_idx: usize,
+    mirroring: Mirroring,
+}
+
+impl Gxrom {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x8000;
+    pub const CHR_BANK_SIZE: usize = 0x2000;
+
+    pub fn from(ines: &INesFile) -> Result<Gxrom, String> {
+        let nb_pages = ines.get_prg_rom_pages();
+        if nb_pages == 0 || nb_pages % 2 != 0 {
+            return Err(String::from("GxROM expects 32kb PRG ROM banks"));
+        }
+
+        let mut prg_rom_banks = Vec::new();
+        for nb in (0..nb_pages).step_by(2) {
+            let mut bank = Vec::with_capacity(Self::PRG_BANK_SIZE);
+            bank.extend_from_slice(ines.get_prg_rom(nb)?);
+            bank.extend_from_slice(ines.get_prg_rom(nb + 1)?);
+            prg_rom_banks.push(bank);
+        }
+
+        let mut chr_rom_banks = Vec::new();
+        if ines.has_chr_ram() {
+            chr_rom_banks.push(vec![0; Self::CHR_BANK_SIZE]);
+        } else {
+            for nb in 0..ines.get_chr_rom_pages() {
+                chr_rom_banks.push(ines.get_chr_rom(nb)?.to_vec());
+            }
+        }
+
+        Ok(Gxrom {
+            prg_rom_banks,
+            chr_rom_banks,
+            chr_read_only: !ines.has_chr_ram(),
+            prg_bank_idx: 0,
+            chr_bank_idx: 0,
+            mirroring: ines.get_mirroring(),
+        })
+    }
+
+    pub fn read_prg(&self, addr: usize) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => self.prg_rom_banks[self.prg_bank_idx][addr % 0x8000],
+            _ => 0,
+        }
+    }
+
+    // Writing to PRG will actually write to the register.
+    pub fn write_prg(&mut self, addr: usize, value: u8) {
+        if addr >= 0x8000 {
+            self.prg_bank_idx = ((value >> 4) & 0x3) as usize % self.prg_rom_banks.len();
+            self.chr_bank_idx = (value & 0x3) as usize % self.chr_rom_banks.len();
+        }
+    }
+
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        if addr >= 0x8000 {
+            self.prg_rom_banks[self.prg_bank_idx][addr % 0x8000] = value;
+        }
+    }
+
+    pub fn read_chr(&self, addr: usize) -> u8 {
+        self.chr_rom_banks[self.chr_bank_idx][addr % 0x2000]
+    }
+
+    pub fn write_chr(&mut self, addr: usize, value: u8) {
+        if !self.chr_read_only {
+            self.chr_rom_banks[self.chr_bank_idx][addr % 0x2000] = value;
+        }
+    }
+
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.prg_rom_banks, index, data)
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.chr_rom_banks, index, data)
+    }
+
+    pub fn get_chr(&self, idx: usize) -> &[u8] {
+        let bank = &self.chr_rom_banks[self.chr_bank_idx];
+        if idx == 0 {
+            &bank[0..0x1000]
+        } else {
+            &bank[0x1000..0x2000]
+        }
+    }
+
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![self.prg_bank_idx]
+    }
+
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![self.chr_bank_idx]
+    }
+
+    pub fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn single_screen_page(&self) -> usize {
+        0
+    }
+}
+
+impl Multicart for Gxrom {
+    fn bank_count(&self) -> usize {
+        self.prg_rom_banks.len()
+    }
+
+    // Each game has its PRG bank and the CHR bank with the same number.
+    fn select_menu_entry(&mut self, n: usize) {
+        self.prg_bank_idx = n % self.prg_rom_banks.len();
+        self.chr_bank_idx = n % self.chr_rom_banks.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4 PRG banks and 4 CHR banks filled with their number.
+    fn gxrom() -> Gxrom {
+        let prg_rom = (0..8).flat_map(|i| vec![i / 2; 0x4000]).collect();
+        let chr_rom = (0..4).flat_map(|i| vec![i; 0x2000]).collect();
+        let ines = INesFile::new(prg_rom, 8, chr_rom, 4, 0, 0x20, 0x40, 0, 0, String::from("test"));
+        Gxrom::from(&ines).unwrap()
+    }
+
+    #[test]
+    fn register_switches_banks() {
+        let mut mapper = gxrom();
+        assert_eq!(0, mapper.read_prg(0xFFFF));
+
+        mapper.write_prg(0x8000, 0x21);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(2, mapper.read_prg(0xFFFF));
+        assert_eq!(1, mapper.read_chr(0x1FFF));
+    }
+
+    #[test]
+    fn select_menu_entry() {
+        let mut mapper = gxrom();
+        assert_eq!(4, mapper.bank_count());
+
+        mapper.select_menu_entry(3);
+        assert_eq!(3, mapper.read_prg(0x8000));
+        assert_eq!(3, mapper.read_chr(0x0000));
+    }
+}
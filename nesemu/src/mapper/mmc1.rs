@@ -19,6 +19,9 @@ pub struct Mmc1 {
     //              ...		|          ...
     prg_rom_banks: Vec<Vec<u8>>, // 16kb for each element
     chr_rom_banks: Vec<Vec<u8>>, // 4kb for each element.
+    // Writes to CHR-ROM are ignored. Cartridges without CHR-ROM have CHR-RAM.
+    #[serde(default)]
+    chr_read_only: bool,
 
     prg_low_area_idx: usize,
     prg_high_area_idx: usize,
@@ -51,6 +54,8 @@ pub struct Mmc1 {
 
     // written via $A000-$BFFF
     // to switch chrrom pages
+    // On boards with 512KB of PRG-ROM (SUROM...), bit 4 selects the 256KB
+    // half of the PRG-ROM.
     reg1: u8,
 
     // written via $C000-$DFFF
@@ -62,7 +67,14 @@ pub struct Mmc1 {
     reg3: u8,
 }
 
+// Number of 16KB PRG-ROM banks addressable by the PRG bank register (256KB).
+const PRG_BANKS_PER_HALF: usize = 16;
+
 impl Mmc1 {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x4000;
+    pub const CHR_BANK_SIZE: usize = 0x1000;
+
 
     pub fn read_prg(&self, addr: usize) -> u8 {
         match addr {
@@ -101,6 +113,9 @@ impl Mmc1 {
                     // chr bank 0
                     self.reg1 = value_to_load;
                     self.switch_chr_bank0();
+                    if self.is_prg_512kb() {
+                        self.switch_prg_bank();
+                    }
                 },
                 0xC000..=0xDFFF => {
                     // chr bank 1
@@ -122,6 +137,15 @@ impl Mmc1 {
 
     }
 
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        let bank_idx = match addr {
+            0x8000..=0xBFFF => self.prg_low_area_idx,
+            0xC000..=0xFFFF => self.prg_high_area_idx,
+            _ => return,
+        };
+        self.prg_rom_banks[bank_idx][addr % 0x4000] = value;
+    }
+
     // Read/Write pattern tables. Sometimes, it is RAM instead of ROM
     pub fn read_chr(&self, addr: usize) -> u8 {
         match addr {
@@ -136,6 +160,10 @@ impl Mmc1 {
     }
 
     pub fn write_chr(&mut self, addr: usize, value: u8) {
+        if self.chr_read_only {
+            return;
+        }
+
         match addr {
             0x0000..=0x0FFF => {
                 self.chr_rom_banks[self.chr_low_area_idx][addr % 0x1000] = value;
@@ -147,6 +175,14 @@ impl Mmc1 {
         }
     }
 
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.prg_rom_banks, index, data)
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.chr_rom_banks, index, data)
+    }
+
     pub fn get_chr(&self, idx: usize) -> &[u8] {
         if idx == 0 {
             &self.chr_rom_banks[self.chr_low_area_idx]
@@ -155,6 +191,14 @@ impl Mmc1 {
         }
     }
     
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![self.prg_low_area_idx, self.prg_high_area_idx]
+    }
+
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![self.chr_low_area_idx, self.chr_high_area_idx]
+    }
+
     pub fn get_mirroring(&self) -> Mirroring {
         // bit 0: Toggle mirroring. 0 = vertical, 1 = horizontal
         // bit 1: Toggle between H/V and "one screen mirroring". 0 = one screen
@@ -169,9 +213,25 @@ impl Mmc1 {
         }
     }
 
+    /// Same as writing the mirroring bits of the control register.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        let bits = match mirroring {
+            Mirroring::ONE_SCREEN => 0b00,
+            Mirroring::VERTICAL => 0b10,
+            Mirroring::HORIZONTAL => 0b11,
+        };
+        self.reg0 = (self.reg0 & !0b11) | bits;
+    }
+
+    // With one screen mirroring, bit 0 selects the lower or upper nametable.
+    pub fn single_screen_page(&self) -> usize {
+        (self.reg0 & 0b1) as usize
+    }
+
     pub fn new() -> Mmc1 {
         Mmc1 {
             chr_rom_banks: Vec::new(),
+            chr_read_only: false,
             prg_rom_banks: Vec::new(),
             chr_low_area_idx: 0,
             chr_high_area_idx: 0,
@@ -190,7 +250,7 @@ impl Mmc1 {
         let mut pages = Vec::new();
         for nb in 0..ines.get_prg_rom_pages() {
             let mut prg_page = vec![0; 0x4000];
-            let rom_page = ines.get_prg_rom(nb+1)?;
+            let rom_page = ines.get_prg_rom(nb)?;
             for (i, b) in rom_page.iter().enumerate() {
                 prg_page[i] = *b;
             }
@@ -202,7 +262,7 @@ impl Mmc1 {
             let mut lower_pattern_table_page = vec![0; 0x1000];
             let mut upper_pattern_table_page = vec![0; 0x1000];
 
-            let chr_page = ines.get_chr_rom(nb+1)?;
+            let chr_page = ines.get_chr_rom(nb)?;
             for (i, b) in chr_page[0..0x1000].iter().enumerate() {
                 lower_pattern_table_page[i] = *b;
             }
@@ -229,6 +289,7 @@ impl Mmc1 {
 
         Ok(Mmc1 {
             chr_rom_banks: pattern_table_pages,
+            chr_read_only: !ines.has_chr_ram(),
             prg_rom_banks: pages,
             chr_low_area_idx,
             chr_high_area_idx,
@@ -252,12 +313,14 @@ impl Mmc1 {
 
     // will switch the bank at location $0000
     fn switch_chr_bank0(&mut self) {
+        // CHR-RAM is only 8KB so the bank number wraps around.
+        let nb_banks = self.chr_rom_banks.len().max(1);
         if self.is_chr_8kb() {
-            let idx = self.reg1 >> 1;
-            self.chr_low_area_idx = idx as usize;
-            self.chr_high_area_idx = (idx + 1) as usize;
+            let idx = (self.reg1 >> 1) as usize;
+            self.chr_low_area_idx = idx % nb_banks;
+            self.chr_high_area_idx = (idx + 1) % nb_banks;
         } else {
-            self.chr_low_area_idx = self.reg1 as usize;
+            self.chr_low_area_idx = self.reg1 as usize % nb_banks;
         }
     }
 
@@ -265,11 +328,16 @@ impl Mmc1 {
     fn switch_chr_bank1(&mut self) {
         // ignored in 8kb mode.
         if !self.is_chr_8kb() {
-           self.chr_high_area_idx = self.reg2 as usize; 
+            self.chr_high_area_idx = self.reg2 as usize % self.chr_rom_banks.len().max(1);
         }
     }
 
     fn switch_prg_bank(&mut self) {
+        if self.is_prg_512kb() {
+            self.switch_prg_bank_512kb();
+            return;
+        }
+
         if self.is_prg_32kb() {
             let idx = (self.reg3 >> 1) * 2;
             self.prg_low_area_idx = idx as usize;
@@ -284,6 +352,28 @@ impl Mmc1 {
         }
     }
 
+    // Same as switch_prg_bank, but the bank is chosen within the 256KB half
+    // selected by bit 4 of reg1. The fixed bank is the first or last bank of
+    // that half.
+    fn switch_prg_bank_512kb(&mut self) {
+        let outer = ((self.reg1 >> 4) & 1) as usize * PRG_BANKS_PER_HALF;
+        let bank = (self.reg3 & 0x0F) as usize;
+        if self.is_prg_32kb() {
+            self.prg_low_area_idx = outer + (bank & !1);
+            self.prg_high_area_idx = outer + (bank | 1);
+        } else if self.is_low_area_switcheable() {
+            self.prg_low_area_idx = outer + bank;
+            self.prg_high_area_idx = outer + PRG_BANKS_PER_HALF - 1;
+        } else {
+            self.prg_low_area_idx = outer;
+            self.prg_high_area_idx = outer + bank;
+        }
+    }
+
+    fn is_prg_512kb(&self) -> bool {
+        self.prg_rom_banks.len() > PRG_BANKS_PER_HALF
+    }
+
     fn is_chr_8kb(&self) -> bool {
         (self.reg0 >> 4) & 1 == 0
     }
@@ -406,13 +496,63 @@ mod tests {
         assert_eq!(false, mmc1.is_loading_reg_full());
     }
 
+    // Load a value in a register with 5 serial writes.
+    fn write_register(mmc1: &mut Mmc1, addr: usize, value: u8) {
+        for i in 0..5 {
+            mmc1.write_prg(addr, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn current_banks() {
+        let ines = INesFile::new(vec![0; 0x20000], 8, vec![0; 0x8000], 4, 0, 0x10, 0, 0, 0, "test".to_owned());
+        let mut mmc1 = Mmc1::from(&ines).unwrap();
+
+        // 16KB mode, $8000 switcheable.
+        write_register(&mut mmc1, 0x8000, 0b11100);
+        write_register(&mut mmc1, 0xE000, 5);
+        assert_eq!(vec![5, 7], mmc1.current_prg_banks());
+
+        write_register(&mut mmc1, 0xA000, 2);
+        write_register(&mut mmc1, 0xC000, 6);
+        assert_eq!(vec![2, 6], mmc1.current_chr_banks());
+    }
+
+    #[test]
+    fn prg_512kb_upper_half() {
+        // 32 banks of 16KB, each filled with its number. CHR-RAM like SUROM.
+        let mut prg_rom = vec![];
+        for bank in 0..32 {
+            prg_rom.extend(vec![bank as u8; 0x4000]);
+        }
+        let ines = INesFile::new(prg_rom, 32, vec![], 0, 0, 0x10, 0, 0, 0, "surom".to_owned());
+        let mut mmc1 = Mmc1::from(&ines).unwrap();
+
+        // 16KB mode, $8000 switcheable, bank 3.
+        write_register(&mut mmc1, 0x8000, 0b01100);
+        write_register(&mut mmc1, 0xE000, 3);
+        assert_eq!(3, mmc1.read_prg(0x8000));
+        assert_eq!(15, mmc1.read_prg(0xC000));
+
+        // Bit 4 of CHR bank 0 selects the upper 256KB.
+        write_register(&mut mmc1, 0xA000, 0x10);
+        assert_eq!(16 + 3, mmc1.read_prg(0x8000));
+        assert_eq!(31, mmc1.read_prg(0xC000));
+
+        write_register(&mut mmc1, 0xA000, 0);
+        assert_eq!(3, mmc1.read_prg(0x8000));
+        assert_eq!(15, mmc1.read_prg(0xC000));
+    }
+
     #[test]
     fn test_mirroring() {
         let mut mmc1 = Mmc1::new();
         mmc1.reg0 = 0;
         assert_eq!(Mirroring::ONE_SCREEN, mmc1.get_mirroring());
+        assert_eq!(0, mmc1.single_screen_page());
         mmc1.reg0 = 1;
         assert_eq!(Mirroring::ONE_SCREEN, mmc1.get_mirroring());
+        assert_eq!(1, mmc1.single_screen_page());
         mmc1.reg0 = 2;
         assert_eq!(Mirroring::VERTICAL, mmc1.get_mirroring());
         mmc1.reg0 = 3;
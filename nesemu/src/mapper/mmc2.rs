@@ -0,0 +1,255 @@
+use super::Mirroring;
+use crate::rom::INesFile;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::Cell;
+
+const LATCH_FD: u8 = 0xFD;
+const LATCH_FE: u8 = 0xFE;
+
+// MMC2 is mapper 9, used by Punch-Out!!. PRG is a switchable 8kb bank at
+// $8000 and the last three 8kb banks fixed at $A000-$FFFF.
+//
+// Each pattern table has two CHR registers. A latch selects which one is
+// used and it flips when the PPU fetches tile $FD or $FE:
+// - $0FD8 / $0FE8 for the table at $0000,
+// - $1FD8-$1FDF / $1FE8-$1FEF for the table at $1000.
+// The fetch that flips the latch still reads from the old bank.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mmc2 {
+    prg_rom_banks: Vec<Vec<u8>>, // 8kb for each element
+    chr_rom_banks: Vec<Vec<u8>>, // 4kb for each element
+
+    prg_bank_idx: usize,
+    // $0000 with latch FD, $0000 with latch FE, $1000 with latch FD,
+    // $1000 with latch FE.
+    chr_bank_idx: [usize; 4],
+    // The PPU reads CHR through a shared reference, so the latches can
+    // change during `read_chr`.
+    latch_0: Cell<u8>,
+    latch_1: Cell<u8>,
+    mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x2000;
+    pub const CHR_BANK_SIZE: usize = 0x1000;
+
+    pub fn from(ines: &INesFile) -> Result<Mmc2, String> {
+        let mut prg_rom_banks = Vec::new();
+        for nb in 0..ines.get_prg_rom_pages() {
+            let page = ines.get_prg_rom(nb)?;
+            prg_rom_banks.push(page[0..0x2000].to_vec());
+            prg_rom_banks.push(page[0x2000..0x4000].to_vec());
+        }
+        if prg_rom_banks.len() < 4 {
+            return Err(String::from("MMC2 expects at least 32kb of PRG ROM"));
+        }
+
+        if ines.has_chr_ram() {
+            return Err(String::from("MMC2 expects CHR ROM"));
+        }
+        let mut chr_rom_banks = Vec::new();
+        for nb in 0..ines.get_chr_rom_pages() {
+            let page = ines.get_chr_rom(nb)?;
+            chr_rom_banks.push(page[0..0x1000].to_vec());
+            chr_rom_banks.push(page[0x1000..0x2000].to_vec());
+        }
+
+        Ok(Mmc2 {
+            prg_rom_banks,
+            chr_rom_banks,
+            prg_bank_idx: 0,
+            chr_bank_idx: [0; 4],
+            latch_0: Cell::new(LATCH_FE),
+            latch_1: Cell::new(LATCH_FE),
+            mirroring: ines.get_mirroring(),
+        })
+    }
+
+    fn prg_bank_at(&self, addr: usize) -> usize {
+        let nb_banks = self.prg_rom_banks.len();
+        match addr {
+            0x8000..=0x9FFF => self.prg_bank_idx % nb_banks,
+            0xA000..=0xBFFF => nb_banks - 3,
+            0xC000..=0xDFFF => nb_banks - 2,
+            _ => nb_banks - 1,
+        }
+    }
+
+    fn chr_bank_at(&self, addr: usize) -> usize {
+        let idx = if addr < 0x1000 {
+            if self.latch_0.get() == LATCH_FD {
+                self.chr_bank_idx[0]
+            } else {
+                self.chr_bank_idx[1]
+            }
+        } else if self.latch_1.get() == LATCH_FD {
+            self.chr_bank_idx[2]
+        } else {
+            self.chr_bank_idx[3]
+        };
+        idx % self.chr_rom_banks.len()
+    }
+
+    pub fn read_prg(&self, addr: usize) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => self.prg_rom_banks[self.prg_bank_at(addr)][addr % 0x2000],
+            _ => 0,
+        }
+    }
+
+    // Writing to PRG will actually write to the registers.
+    pub fn write_prg(&mut self, addr: usize, value: u8) {
+        let value = (value & 0x1F) as usize;
+        match addr {
+            0xA000..=0xAFFF => self.prg_bank_idx = value & 0xF,
+            0xB000..=0xBFFF => self.chr_bank_idx[0] = value,
+            0xC000..=0xCFFF => self.chr_bank_idx[1] = value,
+            0xD000..=0xDFFF => self.chr_bank_idx[2] = value,
+            0xE000..=0xEFFF => self.chr_bank_idx[3] = value,
+            0xF000..=0xFFFF => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::VERTICAL
+                } else {
+                    Mirroring::HORIZONTAL
+                };
+            }
+            _ => {}
+        }
+    }
+
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        if addr >= 0x8000 {
+            let bank_idx = self.prg_bank_at(addr);
+            self.prg_rom_banks[bank_idx][addr % 0x2000] = value;
+        }
+    }
+
+    pub fn read_chr(&self, addr: usize) -> u8 {
+        let value = self.peek_chr(addr);
+        match addr {
+            0x0FD8 => self.latch_0.set(LATCH_FD),
+            0x0FE8 => self.latch_0.set(LATCH_FE),
+            0x1FD8..=0x1FDF => self.latch_1.set(LATCH_FD),
+            0x1FE8..=0x1FEF => self.latch_1.set(LATCH_FE),
+            _ => {}
+        }
+        value
+    }
+
+    /// Same as `read_chr` without updating the latches.
+    pub fn peek_chr(&self, addr: usize) -> u8 {
+        self.chr_rom_banks[self.chr_bank_at(addr)][addr % 0x1000]
+    }
+
+    // CHR ROM only.
+    pub fn write_chr(&mut self, _addr: usize, _value: u8) {}
+
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.prg_rom_banks, index, data)
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.chr_rom_banks, index, data)
+    }
+
+    // Banks selected by the current latches.
+    pub fn get_chr(&self, idx: usize) -> &[u8] {
+        if idx == 0 {
+            &self.chr_rom_banks[self.chr_bank_at(0x0000)]
+        } else {
+            &self.chr_rom_banks[self.chr_bank_at(0x1000)]
+        }
+    }
+
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![
+            self.prg_bank_at(0x8000),
+            self.prg_bank_at(0xA000),
+            self.prg_bank_at(0xC000),
+            self.prg_bank_at(0xE000),
+        ]
+    }
+
+    // Banks selected by the current latches.
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![self.chr_bank_at(0x0000), self.chr_bank_at(0x1000)]
+    }
+
+    pub fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn single_screen_page(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 PRG banks and 8 CHR banks filled with their number.
+    fn mmc2() -> Mmc2 {
+        let prg_rom = (0..8).flat_map(|i| vec![i; 0x2000]).collect();
+        let chr_rom = (0..8).flat_map(|i| vec![i; 0x1000]).collect();
+        let ines = INesFile::new(prg_rom, 4, chr_rom, 4, 0, 0x90, 0, 0, 0, String::from("test"));
+        Mmc2::from(&ines).unwrap()
+    }
+
+    #[test]
+    fn prg_banks() {
+        let mut mapper = mmc2();
+        mapper.write_prg(0xA000, 2);
+        assert_eq!(2, mapper.read_prg(0x8000));
+        assert_eq!(5, mapper.read_prg(0xA000));
+        assert_eq!(6, mapper.read_prg(0xC000));
+        assert_eq!(7, mapper.read_prg(0xFFFF));
+    }
+
+    #[test]
+    fn latch_switches_chr_bank() {
+        let mut mapper = mmc2();
+        mapper.write_prg(0xB000, 1); // $0000, FD
+        mapper.write_prg(0xC000, 2); // $0000, FE
+        mapper.write_prg(0xD000, 3); // $1000, FD
+        mapper.write_prg(0xE000, 4); // $1000, FE
+        assert_eq!(2, mapper.read_chr(0x0000));
+        assert_eq!(4, mapper.read_chr(0x1000));
+
+        // The fetch of tile $FD still uses the old bank.
+        assert_eq!(2, mapper.read_chr(0x0FD8));
+        assert_eq!(1, mapper.read_chr(0x0000));
+        assert_eq!(4, mapper.read_chr(0x1000));
+
+        mapper.read_chr(0x1FDD);
+        assert_eq!(3, mapper.read_chr(0x1000));
+        assert_eq!(&[3; 0x1000][..], mapper.get_chr(1));
+
+        // Only $0FD8 and $0FE8 trigger the first latch.
+        mapper.read_chr(0x0FE9);
+        assert_eq!(1, mapper.read_chr(0x0000));
+        mapper.read_chr(0x0FE8);
+        assert_eq!(2, mapper.read_chr(0x0000));
+        mapper.read_chr(0x1FE8);
+        assert_eq!(4, mapper.read_chr(0x1000));
+
+        // Peeks do not touch the latches.
+        assert_eq!(2, mapper.peek_chr(0x0FD8));
+        assert_eq!(2, mapper.read_chr(0x0000));
+    }
+
+    #[test]
+    fn mirroring_register() {
+        let mut mapper = mmc2();
+        mapper.write_prg(0xF000, 0);
+        assert_eq!(Mirroring::VERTICAL, mapper.get_mirroring());
+        mapper.write_prg(0xF000, 1);
+        assert_eq!(Mirroring::HORIZONTAL, mapper.get_mirroring());
+    }
+}
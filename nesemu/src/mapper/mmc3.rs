@@ -63,6 +63,10 @@ pub struct Mmc3 {
 }
 
 impl Mmc3 {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x2000;
+    pub const CHR_BANK_SIZE: usize = 0x400;
+
     pub fn read_prg(&self, addr: usize) -> u8 {
         if addr >= 0x8000 {
             let bank_idx = match addr {
@@ -83,6 +87,17 @@ impl Mmc3 {
         }
     }
 
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        let bank_idx = match addr {
+            0x8000..=0x9FFF => self.prg_index_1,
+            0xA000..=0xBFFF => self.prg_index_2,
+            0xC000..=0xDFFF => self.prg_index_3,
+            0xE000..=0xFFFF => self.prg_index_4,
+            _ => return,
+        };
+        self.prg_rom_banks[bank_idx][addr % 0x2000] = value;
+    }
+
     // 8 registers.
     pub fn write_prg(&mut self, addr: usize, value: u8) {
         match addr {
@@ -239,10 +254,40 @@ impl Mmc3 {
         }
     }
 
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.prg_rom_banks, index, data)
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.chr_rom_banks, index, data)
+    }
+
     pub fn get_chr(&self, _idx: usize) -> &[u8] {
         &self.chr_rom_banks[0]
     }
 
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![
+            self.prg_index_1,
+            self.prg_index_2,
+            self.prg_index_3,
+            self.prg_index_4,
+        ]
+    }
+
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![
+            self.chr_index_1,
+            self.chr_index_2,
+            self.chr_index_3,
+            self.chr_index_4,
+            self.chr_index_5,
+            self.chr_index_6,
+            self.chr_index_7,
+            self.chr_index_8,
+        ]
+    }
+
     pub fn get_mirroring(&self) -> Mirroring {
         if self.reg_mirroring & 1 == 0 {
             Mirroring::VERTICAL
@@ -251,6 +296,20 @@ impl Mmc3 {
         }
     }
 
+    /// Same as writing the mirroring register. One screen mirroring is not
+    /// supported by the MMC3 and is ignored.
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        match mirroring {
+            Mirroring::VERTICAL => self.reg_mirroring &= !1,
+            Mirroring::HORIZONTAL => self.reg_mirroring |= 1,
+            Mirroring::ONE_SCREEN => (),
+        }
+    }
+
+    pub fn single_screen_page(&self) -> usize {
+        0
+    }
+
     pub fn from(ines: &INesFile) -> Result<Mmc3, String> {
         // 0x2000 element vector.
         let mut prg_pages = Vec::new();
@@ -258,7 +317,7 @@ impl Mmc3 {
             let mut prg_page_low = vec![0; 0x2000];
             let mut prg_page_high = vec![0; 0x2000];
 
-            let rom_page = ines.get_prg_rom(nb + 1)?;
+            let rom_page = ines.get_prg_rom(nb)?;
             for (i, b) in rom_page[0..0x2000].iter().enumerate() {
                 prg_page_low[i] = *b;
             }
@@ -273,7 +332,7 @@ impl Mmc3 {
         // 0x400 element vector
         let mut pattern_table_pages = Vec::new();
         for nb in 0..ines.get_chr_rom_pages() {
-            let chr_page = ines.get_chr_rom(nb + 1)?;
+            let chr_page = ines.get_chr_rom(nb)?;
             for i in (0..0x2000).step_by(0x400) {
                 let mut page = vec![0; 0x400];
                 for j in 0..0x400 {
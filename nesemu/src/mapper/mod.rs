@@ -4,11 +4,14 @@
 //
 //
 use serde_derive::{Deserialize, Serialize};
+pub mod gxrom;
 pub mod mmc1;
+pub mod mmc2;
 pub mod mmc3;
 pub mod nrom;
 pub mod uxrom;
 
+use crate::error::NesError;
 use crate::rom;
 
 #[allow(non_camel_case_types)]
@@ -19,6 +22,54 @@ pub enum Mirroring {
     ONE_SCREEN,
 }
 
+/// Game selection of the multicart mappers. Regular cartridges keep the
+/// default implementation: a single entry that cannot be switched.
+pub trait Multicart {
+    /// Number of entries that can be selected with `select_menu_entry`.
+    fn bank_count(&self) -> usize {
+        1
+    }
+
+    /// Switch to the entry `n`, as the menu of the cartridge would.
+    fn select_menu_entry(&mut self, _n: usize) {}
+}
+
+impl Multicart for nrom::Nrom {}
+impl Multicart for mmc1::Mmc1 {}
+impl Multicart for mmc2::Mmc2 {}
+impl Multicart for uxrom::Uxrom {}
+impl Multicart for mmc3::Mmc3 {}
+
+/// Banks currently mapped by the cartridge, for debuggers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BankInfo {
+    pub prg_bank_size: usize,
+    /// Bank in each PRG window, from $8000 up.
+    pub prg_banks: Vec<usize>,
+    pub chr_bank_size: usize,
+    /// Bank in each CHR window, from $0000 up.
+    pub chr_banks: Vec<usize>,
+}
+
+/// Overwrite `banks[index]` with `data`, which should have the size of the
+/// bank.
+fn replace_bank(banks: &mut [Vec<u8>], index: usize, data: &[u8]) -> Result<(), String> {
+    let nb_banks = banks.len();
+    let bank = banks
+        .get_mut(index)
+        .ok_or_else(|| format!("No bank {}, only {} banks", index, nb_banks))?;
+    if bank.len() != data.len() {
+        return Err(format!(
+            "Bank {} is {} bytes, got {}",
+            index,
+            bank.len(),
+            data.len()
+        ));
+    }
+    bank.copy_from_slice(data);
+    Ok(())
+}
+
 macro_rules! mapper_types {
     ($($name:ident: ($id: expr, $mapper:ty)),+) => {
         #[derive(Serialize, Deserialize)]
@@ -47,6 +98,25 @@ macro_rules! mapper_types {
                 }
             }
 
+            /// Write on the cycle right after another write, like the second
+            /// write of read-modify-write instructions. MMC1 ignores it.
+            pub fn write_prg_consecutive(&mut self, addr: usize, value: u8) {
+                match *self {
+                    MapperType::Mmc1(_) => (),
+                    _ => self.write_prg(addr, value),
+                }
+            }
+
+            /// Write to the PRG bank currently mapped at `addr`, without
+            /// touching the mapper registers.
+            pub fn poke_prg(&mut self, addr: usize, value: u8) {
+                match *self {
+                    $(
+                        MapperType::$name(ref mut x) => x.poke_prg(addr, value),
+                        )+
+                }
+            }
+
             // Read/Write pattern tables. Sometimes, it is RAM instead of ROM
             pub fn read_chr(&self, addr: usize) -> u8 {
                 match *self {
@@ -56,6 +126,15 @@ macro_rules! mapper_types {
                 }
             }
 
+            /// Same as `read_chr`, without the side effects of the PPU
+            /// fetches (MMC2 latches).
+            pub fn peek_chr(&self, addr: usize) -> u8 {
+                match *self {
+                    MapperType::Mmc2(ref x) => x.peek_chr(addr),
+                    _ => self.read_chr(addr),
+                }
+            }
+
             pub fn write_chr(&mut self, addr: usize, value: u8) {
                 match *self {
                     $(
@@ -72,6 +151,78 @@ macro_rules! mapper_types {
                 }
             }
 
+            /// Overwrite a PRG bank. The size of the banks depends on the
+            /// mapper (see `prg_bank_size`).
+            pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+                match *self {
+                    $(
+                        MapperType::$name(ref mut x) => x.replace_prg_bank(index, data),
+                        )+
+                }
+            }
+
+            /// Overwrite a CHR bank. The size of the banks depends on the
+            /// mapper (see `chr_bank_size`).
+            pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+                match *self {
+                    $(
+                        MapperType::$name(ref mut x) => x.replace_chr_bank(index, data),
+                        )+
+                }
+            }
+
+            pub fn prg_bank_size(&self) -> usize {
+                match *self {
+                    $(
+                        MapperType::$name(_) => <$mapper>::PRG_BANK_SIZE,
+                        )+
+                }
+            }
+
+            pub fn chr_bank_size(&self) -> usize {
+                match *self {
+                    $(
+                        MapperType::$name(_) => <$mapper>::CHR_BANK_SIZE,
+                        )+
+                }
+            }
+
+            /// Bank mapped in each PRG window, from $8000 up. In units of
+            /// `prg_bank_size`.
+            pub fn current_prg_banks(&self) -> Vec<usize> {
+                match *self {
+                    $(
+                        MapperType::$name(ref x) => x.current_prg_banks(),
+                        )+
+                }
+            }
+
+            /// Bank mapped in each CHR window, from $0000 up. In units of
+            /// `chr_bank_size`.
+            pub fn current_chr_banks(&self) -> Vec<usize> {
+                match *self {
+                    $(
+                        MapperType::$name(ref x) => x.current_chr_banks(),
+                        )+
+                }
+            }
+
+            pub fn bank_count(&self) -> usize {
+                match *self {
+                    $(
+                        MapperType::$name(ref x) => x.bank_count(),
+                        )+
+                }
+            }
+
+            pub fn select_menu_entry(&mut self, n: usize) {
+                match *self {
+                    $(
+                        MapperType::$name(ref mut x) => x.select_menu_entry(n),
+                        )+
+                }
+            }
+
             pub fn get_mirroring(&self) -> Mirroring {
                 match *self {
                     $(
@@ -80,21 +231,68 @@ macro_rules! mapper_types {
                 }
             }
 
+            pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+                match *self {
+                    $(
+                        MapperType::$name(ref mut x) => x.set_mirroring(mirroring),
+                        )+
+                }
+            }
+
+            /// Physical nametable used by one screen mirroring.
+            pub fn single_screen_page(&self) -> usize {
+                match *self {
+                    $(
+                        MapperType::$name(ref x) => x.single_screen_page(),
+                        )+
+                }
+            }
+
+            /// iNES mapper number.
+            pub fn id(&self) -> u8 {
+                match *self {
+                    $(
+                        MapperType::$name(_) => $id,
+                        )+
+                }
+            }
+
+            /// Version of the serialized mapper state. Each mapper bumps its
+            /// STATE_VERSION when its fields change so that old saves are
+            /// rejected.
+            pub fn state_version(&self) -> u32 {
+                match *self {
+                    $(
+                        MapperType::$name(_) => <$mapper>::STATE_VERSION,
+                        )+
+                }
+            }
+
+            /// Same as `state_version` for the given mapper number. None if
+            /// the mapper is not supported.
+            pub fn state_version_of(mapper_id: u8) -> Option<u32> {
+                match mapper_id {
+                    $(
+                        $id => Some(<$mapper>::STATE_VERSION),
+                        )+
+                    _ => None,
+                }
+            }
         }
 
 
-        pub fn create_mapper(rom: &rom::INesFile) -> Result<MapperType, String> {
+        pub fn create_mapper(rom: &rom::INesFile) -> Result<MapperType, NesError> {
 
             let mapper_id = rom.get_mapper_id();
 
             match mapper_id {
                 $(
                     $id => {
-                        let x = <$mapper>::from(&rom).unwrap();
+                        let x = <$mapper>::from(&rom).map_err(NesError::RomParse)?;
                         Ok(MapperType::$name(x))
                     },
                     )+
-                    _ => Err(String::from("Not implemented yet"))
+                    _ => Err(NesError::UnsupportedMapper(mapper_id))
             }
 
         }
@@ -105,5 +303,68 @@ mapper_types!(
     Nrom: (0, nrom::Nrom),
     Mmc1: (1, mmc1::Mmc1),
     Uxrom: (2, uxrom::Uxrom),
-    Mmc3: (4, mmc3::Mmc3)
+    Mmc3: (4, mmc3::Mmc3),
+    Gxrom: (66, gxrom::Gxrom),
+    // New mappers go at the end: the variant index is part of the save
+    // states.
+    Mmc2: (9, mmc2::Mmc2)
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ines_without_chr_rom(mapper_id: u8) -> rom::INesFile {
+        rom::INesFile::new(
+            vec![0; 0x8000],
+            2,
+            vec![],
+            0,
+            0,
+            mapper_id << 4,
+            0,
+            0,
+            0,
+            String::from("test"),
+        )
+    }
+
+    #[test]
+    fn chr_ram_when_no_chr_rom() {
+        for mapper_id in &[0, 1, 2] {
+            let mut mapper = create_mapper(&ines_without_chr_rom(*mapper_id)).unwrap();
+            mapper.write_chr(0x0123, 0x42);
+            mapper.write_chr(0x1FFF, 0x24);
+            assert_eq!(0x42, mapper.read_chr(0x0123));
+            assert_eq!(0x24, mapper.read_chr(0x1FFF));
+        }
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let ines = rom::INesFile::new(
+            vec![0; 0x8000],
+            2,
+            vec![0x11; 0x2000],
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            String::from("test"),
+        );
+        let mut mapper = create_mapper(&ines).unwrap();
+        mapper.write_chr(0x0123, 0x42);
+        assert_eq!(0x11, mapper.read_chr(0x0123));
+    }
+
+    #[test]
+    fn unknown_mapper() {
+        match create_mapper(&ines_without_chr_rom(7)) {
+            Err(NesError::UnsupportedMapper(7)) => (),
+            Err(err) => panic!("Expected UnsupportedMapper(7), got {:?}", err),
+            Ok(_) => panic!("Mapper 7 is not implemented"),
+        }
+    }
+}
@@ -16,11 +16,18 @@ pub struct Nrom {
 
     // PPU pattern tables
     chr_rom: Vec<u8>,
+    // Writes to CHR-ROM are ignored. Cartridges without CHR-ROM have CHR-RAM.
+    #[serde(default)]
+    chr_read_only: bool,
 
     mirroring: Mirroring,
 }
 
 impl Nrom {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x4000;
+    pub const CHR_BANK_SIZE: usize = 0x2000;
+
     // empty NROM
     pub fn new() -> Nrom {
         Nrom {
@@ -28,6 +35,7 @@ impl Nrom {
             prg_rom_first: vec![0; 0x4000],
             prg_rom_last: vec![0; 0x4000],
             chr_rom: vec![0; 0x2000],
+            chr_read_only: false,
             mirroring: Mirroring::HORIZONTAL,
         }
     }
@@ -43,17 +51,17 @@ impl Nrom {
 
         if page_nb == 1 {
 
-            let page = ines.get_prg_rom(1)?;
+            let page = ines.get_prg_rom(0)?;
             for (i, b) in page.iter().enumerate() {
                 prg_rom_first[i] = *b;
                 prg_rom_last[i] = *b;
             }
         } else if page_nb == 2 {
-            let page = ines.get_prg_rom(1)?;
+            let page = ines.get_prg_rom(0)?;
             for (i, b) in page.iter().enumerate() {
                 prg_rom_first[i] = *b;
             }
-            let page2 = ines.get_prg_rom(2)?;
+            let page2 = ines.get_prg_rom(1)?;
             for (i, b) in page2.iter().enumerate() {
                 prg_rom_last[i] = *b;
             }
@@ -66,7 +74,7 @@ impl Nrom {
         // ----------------------------------
         let mut chr_rom = vec![0; 0x2000];
         if ines.get_chr_rom_pages() > 0 {
-            let vrom = ines.get_chr_rom(1)?;
+            let vrom = ines.get_chr_rom(0)?;
             for (i, b) in vrom.iter().enumerate() {
                 chr_rom[i] = *b;
             }
@@ -77,6 +85,7 @@ impl Nrom {
             prg_rom_first,
             prg_rom_last,
             chr_rom,
+            chr_read_only: !ines.has_chr_ram(),
             mirroring: ines.get_mirroring(),
         })
     }
@@ -105,13 +114,38 @@ impl Nrom {
         }
     }
 
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        // No register, PRG writes already go to the ROM.
+        self.write_prg(addr, value);
+    }
+
     // Read/Write pattern tables. Sometimes, it is RAM instead of ROM
     pub fn read_chr(&self, addr: usize) -> u8 {
         self.chr_rom[addr]
     }
 
     pub fn write_chr(&mut self, addr: usize, value: u8) {
-        self.chr_rom[addr] = value;
+        if !self.chr_read_only {
+            self.chr_rom[addr] = value;
+        }
+    }
+
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        if index >= self.nb_page {
+            return Err(format!("No PRG bank {}, only {} banks", index, self.nb_page));
+        }
+        if index == 0 {
+            super::replace_bank(std::slice::from_mut(&mut self.prg_rom_first), 0, data)?;
+        }
+        // With only one page, the last area is a mirror of the first one.
+        if index == 1 || self.nb_page == 1 {
+            super::replace_bank(std::slice::from_mut(&mut self.prg_rom_last), 0, data)?;
+        }
+        Ok(())
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(std::slice::from_mut(&mut self.chr_rom), index, data)
     }
 
     pub fn get_chr(&self, idx: usize) -> &[u8] {
@@ -122,9 +156,25 @@ impl Nrom {
         }
     }
 
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![0, self.nb_page - 1]
+    }
+
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![0]
+    }
+
     pub fn get_mirroring(&self) -> Mirroring {
         self.mirroring
     }
+
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn single_screen_page(&self) -> usize {
+        0
+    }
 }
 
 
@@ -11,14 +11,20 @@ pub struct Uxrom {
     // low area is switcheable, upper area is fixed
     prg_rom_banks: Vec<Vec<u8>>, // 16kb for each element
 
-    // just RAM
+    // CHR-RAM, or CHR-ROM if the cartridge has one.
     chr_rom_banks: Vec<Vec<u8>>, // 4kb for each element.
+    #[serde(default)]
+    chr_read_only: bool,
 
     prg_bank_idx: usize,
     mirroring: Mirroring,
 }
 
 impl Uxrom {
+    pub const STATE_VERSION: u32 = 1;
+    pub const PRG_BANK_SIZE: usize = 0x4000;
+    pub const CHR_BANK_SIZE: usize = 0x1000;
+
 
     pub fn read_prg(&self, addr: usize) -> u8 {
         match addr {
@@ -37,6 +43,15 @@ impl Uxrom {
         self.prg_bank_idx = (value & 0xF) as usize;
     }
 
+    pub fn poke_prg(&mut self, addr: usize, value: u8) {
+        let bank_idx = match addr {
+            0x8000..=0xBFFF => self.prg_bank_idx,
+            0xC000..=0xFFFF => self.prg_rom_banks.len() - 1,
+            _ => return,
+        };
+        self.prg_rom_banks[bank_idx][addr % 0x4000] = value;
+    }
+
     // Read/Write pattern tables. Sometimes, it is RAM instead of ROM
     pub fn read_chr(&self, addr: usize) -> u8 {
         match addr {
@@ -51,6 +66,10 @@ impl Uxrom {
     }
 
     pub fn write_chr(&mut self, addr: usize, value: u8) {
+        if self.chr_read_only {
+            return;
+        }
+
         match addr {
             0x0000..=0x0FFF => {
                 self.chr_rom_banks[0][addr % 0x1000] = value;
@@ -62,6 +81,14 @@ impl Uxrom {
         }
     }
 
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.prg_rom_banks, index, data)
+    }
+
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        super::replace_bank(&mut self.chr_rom_banks, index, data)
+    }
+
     pub fn get_chr(&self, idx: usize) -> &[u8] {
         if idx == 0 {
             &self.chr_rom_banks[0]
@@ -70,14 +97,31 @@ impl Uxrom {
         }
     }
     
+    pub fn current_prg_banks(&self) -> Vec<usize> {
+        vec![self.prg_bank_idx, self.prg_rom_banks.len() - 1]
+    }
+
+    pub fn current_chr_banks(&self) -> Vec<usize> {
+        vec![0, 1]
+    }
+
     pub fn get_mirroring(&self) -> Mirroring {
         self.mirroring
     }
 
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn single_screen_page(&self) -> usize {
+        0
+    }
+
 
     pub fn new() -> Uxrom {
         Uxrom {
             chr_rom_banks: Vec::new(),
+            chr_read_only: false,
             prg_rom_banks: Vec::new(),
             prg_bank_idx: 0,
             mirroring: Mirroring::HORIZONTAL,
@@ -89,21 +133,25 @@ impl Uxrom {
         let mut pages = Vec::new();
         for nb in 0..ines.get_prg_rom_pages() {
             let mut prg_page = vec![0; 0x4000];
-            let rom_page = ines.get_prg_rom(nb+1)?;
+            let rom_page = ines.get_prg_rom(nb)?;
             for (i, b) in rom_page.iter().enumerate() {
                 prg_page[i] = *b;
             }
             pages.push(prg_page);
         }
 
-        let mut pattern_table_pages = Vec::new();
-        pattern_table_pages.push(vec![0; 0x1000]);
-        pattern_table_pages.push(vec![0; 0x1000]);
+        let mut pattern_table_pages = vec![vec![0; 0x1000], vec![0; 0x1000]];
+        if !ines.has_chr_ram() {
+            let chr_page = ines.get_chr_rom(0)?;
+            pattern_table_pages[0].copy_from_slice(&chr_page[0..0x1000]);
+            pattern_table_pages[1].copy_from_slice(&chr_page[0x1000..0x2000]);
+        }
 
         let mirroring = ines.get_mirroring();
         let prg_bank_idx = 0;
         Ok(Uxrom {
             chr_rom_banks: pattern_table_pages,
+            chr_read_only: !ines.has_chr_ram(),
             prg_rom_banks: pages,
             prg_bank_idx,
             mirroring,
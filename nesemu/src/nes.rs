@@ -1,17 +1,87 @@
 //
 //
-use crate::apu::{Apu, ApuLevels};
-use crate::cpu::cpu::Cpu;
-use crate::cpu::memory::Memory;
-use crate::graphic::EmulatorInput;
-use crate::joypad::{InputState, Player};
-use crate::ppu::Ppu;
-use crate::rom;
+use crate::apu::{write_log::WriteLog, Apu, ApuLevels};
+use crate::cheat::Cheat;
+use crate::cpu::cpu::{Cpu, IllegalOpcodePolicy};
+use crate::cpu::memory::{MemRegion, Memory};
+use crate::cpu::trace::{Trace, TraceEntry};
+use crate::cpu::watch::{AccessKind, WatchHit};
+use crate::error::NesError;
+use crate::graphic::{self, EmulatorInput, PixelAspect};
+use crate::joypad::{ControllerKind, InputAction, InputState, Joypad, JoypadState, Player};
+use crate::mapper::{BankInfo, MapperType, Mirroring};
+use crate::ppu::{overlay::DebugOverlay, palette, Ppu};
+use crate::rom::{self, Region};
+use crate::speed::Speed;
 
 use serde_derive::{Deserialize, Serialize};
-use std::error::Error;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the serialized state. Bump it when the fields of `Nes` (or of
+/// anything it contains, except the mappers) change.
+const STATE_VERSION: u32 = 20;
+
+/// CPU cycles of the OAM DMA, plus one when it starts on an odd cycle.
+const OAM_DMA_CYCLES: u64 = 513;
+
+/// Written before the state in save files, so that saves from another version
+/// of the emulator are rejected with a clear error.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StateHeader {
+    version: u32,
+    mapper_id: u8,
+    mapper_version: u32,
+}
+
+impl StateHeader {
+    fn current(nes: &Nes) -> StateHeader {
+        StateHeader {
+            version: STATE_VERSION,
+            mapper_id: nes.memory.mapper.id(),
+            mapper_version: nes.memory.mapper.state_version(),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.version != STATE_VERSION {
+            return Err(format!(
+                "save version mismatch: file v{}, emulator v{}",
+                self.version, STATE_VERSION
+            ));
+        }
+
+        match MapperType::state_version_of(self.mapper_id) {
+            Some(version) if version == self.mapper_version => Ok(()),
+            Some(version) => Err(format!(
+                "save version mismatch for mapper {}: file v{}, emulator v{}",
+                self.mapper_id, self.mapper_version, version
+            )),
+            None => Err(format!("save uses unsupported mapper {}", self.mapper_id)),
+        }
+    }
+}
+
+/// What happened during one `Nes::tick_instruction`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// CPU cycles, including the interrupt if one was serviced.
+    pub cycles: u64,
+    /// Address of the executed instruction.
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+/// Number of pixels hidden on each side of the frame, like on a TV.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Nes {
@@ -23,6 +93,31 @@ pub struct Nes {
     pub is_debug: bool,
     pub is_pause: bool,
     pub should_run: bool,
+
+    // PPU dots of the last instruction not executed yet because
+    // `frame_advance` stopped at the start of vertical blank.
+    ppu_dots_owed: u64,
+
+    // Display setting, not part of the state.
+    #[serde(skip)]
+    overscan: Overscan,
+    // Used by the screenshots taken with EmulatorInput::SCREENSHOT.
+    #[serde(skip)]
+    pixel_aspect: PixelAspect,
+
+    // Emulate the corruption of joypad reads by DMC fetches.
+    #[serde(skip)]
+    dmc_controller_glitch: bool,
+
+    #[serde(skip)]
+    vblank_callback: Option<Box<dyn FnMut()>>,
+
+    #[serde(skip)]
+    speed: Speed,
+
+    // Last instructions executed, for debuggers.
+    #[serde(skip)]
+    trace: Trace,
 }
 
 impl Nes {
@@ -42,13 +137,32 @@ impl Nes {
             is_debug: false,
             is_pause: false,
             should_run: false,
+            ppu_dots_owed: 0,
+            overscan: Overscan::default(),
+            pixel_aspect: PixelAspect::default(),
+            dmc_controller_glitch: false,
+            vblank_callback: None,
+            speed: Speed::default(),
+            trace: Trace::default(),
         }
     }
 
-    pub fn new(ines: rom::INesFile) -> Result<Nes, String> {
+    pub fn new(ines: rom::INesFile) -> Result<Nes, NesError> {
+        let memory = Memory::new(&ines)?;
+        Ok(Nes::power_on(memory, String::from(ines.rom_name())))
+    }
+
+    /// Create the console around a cartridge built by the caller, for tests
+    /// and tools that do not have an iNES file.
+    pub fn with_mapper(mut mapper: MapperType, mirroring: Mirroring, rom_name: String) -> Nes {
+        mapper.set_mirroring(mirroring);
+        Nes::power_on(Memory::with_mapper(mapper), rom_name)
+    }
+
+    fn power_on(mut memory: Memory, rom_name: String) -> Nes {
         let mut cpu = Cpu::new();
-        let ppu = Ppu::new();
-        let mut memory = Memory::new(&ines)?;
+        let mut ppu = Ppu::new();
+        ppu.power_on(&mut memory);
 
         // Need to set the correct PC. It is at FFFC-FFFD
         let lsb = memory.get(0xFFFC) as u16;
@@ -56,8 +170,7 @@ impl Nes {
         let start_pc = (msb << 8) + lsb;
         cpu.set_pc(start_pc);
 
-        let rom_name = String::from(ines.rom_name());
-        Ok(Nes {
+        Nes {
             cpu,
             ppu,
             apu: Apu::new(),
@@ -66,7 +179,35 @@ impl Nes {
             is_debug: false,
             is_pause: false,
             should_run: true,
-        })
+            ppu_dots_owed: 0,
+            overscan: Overscan::default(),
+            pixel_aspect: PixelAspect::default(),
+            dmc_controller_glitch: false,
+            vblank_callback: None,
+            speed: Speed::default(),
+            trace: Trace::default(),
+        }
+    }
+
+    /// Create the console from the content of an iNES file. Invalid or
+    /// unsupported ROMs return an error.
+    pub fn from_rom_bytes(rom_name: &str, bytes: Vec<u8>) -> Result<Nes, NesError> {
+        let ines = rom::from_bytes(rom_name.to_owned(), bytes)?;
+        Nes::new(ines)
+    }
+
+    /// Same as `from_rom_bytes` for the iNES file at `path`. A file that
+    /// cannot be read returns `NesError::Io`.
+    pub fn from_rom_file<P: AsRef<Path>>(path: P) -> Result<Nes, NesError> {
+        Nes::new(rom::read(path)?)
+    }
+
+    /// Start at `pc` instead of the address of the reset vector. nestest
+    /// runs without a screen when started at $C000.
+    pub fn with_start_pc(ines: rom::INesFile, pc: u16) -> Result<Nes, NesError> {
+        let mut nes = Nes::new(ines)?;
+        nes.cpu.set_pc(pc);
+        Ok(nes)
     }
 
     pub fn width(&self) -> usize {
@@ -77,22 +218,95 @@ impl Nes {
         240
     }
 
+    /// Hide some pixels on each side of the frame returned by
+    /// `visible_frame_rgb`. At least one pixel is always visible.
+    pub fn set_overscan(&mut self, top: usize, bottom: usize, left: usize, right: usize) {
+        let top = top.min(self.height() - 1);
+        let left = left.min(self.width() - 1);
+        self.overscan = Overscan {
+            top,
+            bottom: bottom.min(self.height() - 1 - top),
+            left,
+            right: right.min(self.width() - 1 - left),
+        };
+    }
+
+    pub fn overscan(&self) -> Overscan {
+        self.overscan
+    }
+
+    /// Width of the frame without the overscan.
+    pub fn visible_width(&self) -> usize {
+        self.width() - self.overscan.left - self.overscan.right
+    }
+
+    /// Height of the frame without the overscan.
+    pub fn visible_height(&self) -> usize {
+        self.height() - self.overscan.top - self.overscan.bottom
+    }
+
+    /// Read-only view of a memory area for the debugger.
+    pub fn memory_region(&self, region: MemRegion) -> &[u8] {
+        self.memory.region(region)
+    }
+
     pub fn cpu(&self) -> &Cpu {
         &self.cpu
     }
 
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    // Mutable access to the components, for debugging tools. Changing them
+    // directly can break the emulation, so it needs the `debug-internals`
+    // feature.
+
+    #[cfg(feature = "debug-internals")]
     pub fn cpu_mut(&mut self) -> &mut Cpu {
         &mut self.cpu
     }
 
+    #[cfg(feature = "debug-internals")]
     pub fn ppu_mut(&mut self) -> &mut Ppu {
         &mut self.ppu
     }
 
-    pub fn memory(&mut self) -> &Memory {
+    #[cfg(feature = "debug-internals")]
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    #[cfg(feature = "debug-internals")]
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Current (scanline, dot) of the PPU.
+    pub fn ppu_position(&self) -> (usize, usize) {
+        (self.ppu.scanline(), self.ppu.dot())
+    }
+
+    pub fn memory(&self) -> &Memory {
         &self.memory
     }
 
+    /// Read CPU memory without side effects.
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.memory.peek(addr as usize)
+    }
+
+    /// Write CPU memory without side effects: mapper registers are not
+    /// touched, the value goes directly to the backing RAM or ROM. Used by
+    /// debuggers and cheat tools.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.memory.poke(addr as usize, value);
+    }
+
     pub fn should_display(&mut self) -> bool {
         self.ppu.should_display()
     }
@@ -104,22 +318,452 @@ impl Nes {
         pixel
     }
 
+    /// Palette index (0 to 63) of each pixel of the current frame, row by
+    /// row. For front-ends that do the color conversion themselves, like an
+    /// NTSC shader.
+    pub fn frame_indices(&self) -> &[u8] {
+        &self.ppu.pixels
+    }
+
+    /// Fill `out` with the RGB values (3 bytes per pixel) of the current frame.
+    /// `out` should be exactly width * height * 3 bytes.
+    pub fn frame_rgb(&self, out: &mut [u8]) -> Result<(), String> {
+        let expected = self.width() * self.height() * 3;
+        if out.len() != expected {
+            return Err(format!(
+                "Frame buffer should be {} bytes, got {}",
+                expected,
+                out.len()
+            ));
+        }
+
+        self.ppu.frame_rgb(out);
+        Ok(())
+    }
+
+    /// Colors of the current frame packed in u32 (0x00RRGGBB), resolved in
+    /// one pass. `out` should be 256*240 long.
+    pub fn frame_rgb_packed(&self, out: &mut [u32]) -> Result<(), String> {
+        let expected = self.width() * self.height();
+        if out.len() != expected {
+            return Err(format!(
+                "Frame buffer should be {} pixels, got {}",
+                expected,
+                out.len()
+            ));
+        }
+
+        self.ppu.resolve_frame(out);
+        Ok(())
+    }
+
+    /// Hash of the RGB values of the current frame, to compare frames in
+    /// tests. The algorithm (64 bits FNV-1a) must not change, otherwise the
+    /// recorded hashes become invalid.
+    pub fn frame_hash(&self) -> u64 {
+        let mut frame = vec![0; self.width() * self.height() * 3];
+        self.ppu.frame_rgb(&mut frame);
+        fnv1a(&frame)
+    }
+
+    /// Last complete frame in RGB. Front-ends can display it again without
+    /// running the emulation, for example while paused.
+    pub fn last_frame(&self) -> Vec<(u8, u8, u8)> {
+        self.ppu.last_frame()
+    }
+
+    /// Last complete frame with all the channels scaled by `factor`
+    /// (clamped between 0 and 1). For the pause screen.
+    pub fn last_frame_dimmed(&self, factor: f32) -> Vec<(u8, u8, u8)> {
+        let factor = if factor.is_nan() {
+            1.0
+        } else {
+            factor.clamp(0.0, 1.0)
+        };
+        let dim = |c: u8| (f32::from(c) * factor) as u8;
+        self.last_frame()
+            .into_iter()
+            .map(|(r, g, b)| (dim(r), dim(g), dim(b)))
+            .collect()
+    }
+
+    /// Same as `frame_rgb` without the overscan. `out` should be exactly
+    /// visible_width * visible_height * 3 bytes.
+    pub fn visible_frame_rgb(&self, out: &mut [u8]) -> Result<(), String> {
+        let expected = self.visible_width() * self.visible_height() * 3;
+        if out.len() != expected {
+            return Err(format!(
+                "Frame buffer should be {} bytes, got {}",
+                expected,
+                out.len()
+            ));
+        }
+
+        let mut frame = vec![0; self.width() * self.height() * 3];
+        self.ppu.frame_rgb(&mut frame);
+        let row_len = self.visible_width() * 3;
+        for (y, row) in out.chunks_exact_mut(row_len).enumerate() {
+            let start = ((self.overscan.top + y) * self.width() + self.overscan.left) * 3;
+            row.copy_from_slice(&frame[start..start + row_len]);
+        }
+        Ok(())
+    }
+
+    /// Watch CPU accesses to `addr`. Check for accesses with
+    /// `take_watch_hit`.
+    pub fn add_watch(&mut self, addr: u16, kind: AccessKind) {
+        self.memory.watches.add(addr, kind);
+    }
+
+    pub fn remove_watch(&mut self, addr: u16, kind: AccessKind) {
+        self.memory.watches.remove(addr, kind);
+    }
+
+    /// First access to a watched address since the last call, if any.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.memory.watches.take_hit()
+    }
+
+    /// Overwrite a PRG bank of the cartridge while the game runs (ROM
+    /// hacking). The bank size depends on the mapper.
+    pub fn replace_prg_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        self.memory.mapper.replace_prg_bank(index, data)
+    }
+
+    /// Same as `replace_prg_bank` for the pattern tables. Writes even if the
+    /// cartridge has CHR-ROM.
+    pub fn replace_chr_bank(&mut self, index: usize, data: &[u8]) -> Result<(), String> {
+        self.memory.mapper.replace_chr_bank(index, data)
+    }
+
+    /// Number of games of a multicart. 1 for regular cartridges.
+    pub fn game_count(&self) -> usize {
+        self.memory.mapper.bank_count()
+    }
+
+    /// Switch the game of a multicart, as its menu would. The console is not
+    /// reset.
+    pub fn select_game(&mut self, n: usize) -> Result<(), String> {
+        let count = self.memory.mapper.bank_count();
+        if n >= count {
+            return Err(format!("No game {}, only {} games", n, count));
+        }
+        self.memory.mapper.select_menu_entry(n);
+        Ok(())
+    }
+
+    /// PRG and CHR banks currently mapped by the cartridge.
+    pub fn bank_info(&self) -> BankInfo {
+        let mapper = &self.memory.mapper;
+        BankInfo {
+            prg_bank_size: mapper.prg_bank_size(),
+            prg_banks: mapper.current_prg_banks(),
+            chr_bank_size: mapper.chr_bank_size(),
+            chr_banks: mapper.current_chr_banks(),
+        }
+    }
+
+    /// Keep the last `capacity` instructions executed, see `recent_trace`. 0
+    /// (the default) disables the trace.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.trace.set_capacity(capacity);
+    }
+
+    /// Last instructions executed, oldest first, with the registers before
+    /// each instruction.
+    pub fn recent_trace(&self) -> Vec<String> {
+        self.trace.entries().map(|entry| entry.to_string()).collect()
+    }
+
+    /// Stop with an error or continue on unknown and KIL opcodes. Continue by
+    /// default.
+    pub fn set_illegal_opcode_policy(&mut self, policy: IllegalOpcodePolicy) {
+        self.cpu.set_illegal_opcode_policy(policy);
+    }
+
+    /// Timing of the PPU. NTSC by default. The CPU and APU still run at
+    /// the NTSC speed.
+    pub fn set_region(&mut self, region: Region) {
+        self.ppu.set_region(region);
+    }
+
+    pub fn region(&self) -> Region {
+        self.ppu.region()
+    }
+
+    /// Disable the 8 sprites per line limit to remove flickering. Enabled by
+    /// default (hardware behaviour).
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.ppu.set_sprite_limit(enabled);
+    }
+
+    /// Enable a debug layer built at the end of each frame. Off by default.
+    pub fn set_debug_overlay(&mut self, kind: DebugOverlay) {
+        self.ppu.set_debug_overlay(kind);
+    }
+
+    /// Debug layer of the last frame, one byte per pixel. None if disabled.
+    pub fn debug_overlay(&self) -> Option<&[u8]> {
+        self.ppu.debug_overlay()
+    }
+
+    /// Replace the palette by the colors in the given .pal file. The file
+    /// should contain 64 RGB colors (192 bytes).
+    pub fn load_palette<P: AsRef<Path>>(&mut self, path: P) -> Result<(), io::Error> {
+        let colors = palette::load_palette(path)?;
+        self.ppu.set_colors(colors);
+        Ok(())
+    }
+
     // Load from json file.
-    pub fn load_state(path: String) -> Result<Nes, Box<dyn Error>> {
+    pub fn load_state(path: String) -> Result<Nes, NesError> {
         let mut file = File::open(path)?;
         let mut json_str = String::new();
         file.read_to_string(&mut json_str)?;
-        let n: Nes = serde_json::from_str(&json_str)?;
+
+        // The header is written before the state. Check the version before
+        // parsing the rest of the file.
+        let mut de = serde_json::Deserializer::from_str(&json_str);
+        let header = <StateHeader as serde::Deserialize>::deserialize(&mut de).map_err(|_| {
+            NesError::BadState(String::from(
+                "save file has no version. It was created by an older emulator",
+            ))
+        })?;
+        header.check().map_err(NesError::BadState)?;
+
+        let n = <Nes as serde::Deserialize>::deserialize(&mut de)?;
+        de.end()?;
         Ok(n)
     }
 
+    /// Soft reset, like pressing the reset button on the console. RAM and
+    /// cartridge content are kept.
+    pub fn reset(&mut self) {
+        self.cpu.reset(&mut self.memory);
+        self.memory.apu_mem.reset();
+    }
+
+    /// Serialize the full state of the emulator to an in-memory buffer. The
+    /// frame being rendered is not part of the state, the last complete one
+    /// is.
+    pub fn serialize_state(&self) -> Result<Vec<u8>, NesError> {
+        self.serialize_with_header(&StateHeader::current(self))
+    }
+
+    fn serialize_with_header(&self, header: &StateHeader) -> Result<Vec<u8>, NesError> {
+        let mut state = bincode::serialize(header)?;
+        bincode::serialize_into(&mut state, self)?;
+        Ok(state)
+    }
+
+    /// Recreate an emulator from a buffer created by `serialize_state`.
+    pub fn deserialize_state(mut state: &[u8]) -> Result<Nes, NesError> {
+        let header: StateHeader = bincode::deserialize_from(&mut state)?;
+        header.check().map_err(NesError::BadState)?;
+
+        let mut nes: Nes = bincode::deserialize_from(state)?;
+        nes.apu.restore_filters();
+        Ok(nes)
+    }
+
     pub fn tick(&mut self, is_debug: bool) -> Result<u64, &'static str> {
-        let cpu_cycles = self.cpu.next(&mut self.memory)?;
-        self.ppu.next(3 * cpu_cycles, &mut self.memory, is_debug)?;
+        self.step(is_debug, false)
+    }
+
+    // Execute one instruction. With `park_at_vblank`, the PPU stops at the
+    // first dot of vertical blank and the rest of its dots are executed
+    // before the next instruction, so the emulation is the same.
+    fn step(&mut self, is_debug: bool, park_at_vblank: bool) -> Result<u64, &'static str> {
+        let owed = std::mem::replace(&mut self.ppu_dots_owed, 0);
+        self.ppu.next(owed, &mut self.memory, is_debug)?;
+
+        let frame = self.ppu.frame_count();
+        let park_frame = if park_at_vblank { Some(frame) } else { None };
+        self.memory.last_joypad_read = None;
+        if self.trace.is_enabled() {
+            let opcode = self.memory.peek(self.cpu.get_pc() as usize);
+            self.trace.push(TraceEntry::new(&self.cpu, opcode));
+        }
+        let mut cpu_cycles = self.cpu.next(&mut self.memory)?;
+        self.run_ppu(3 * cpu_cycles, is_debug, park_frame)?;
         self.apu.next(cpu_cycles, &mut self.memory);
+
+        // The CPU is halted during the OAM DMA but the PPU and APU keep
+        // running.
+        if self.memory.take_oam_dma() {
+            let dma = OAM_DMA_CYCLES + self.cpu.cycles() % 2;
+            self.cpu.stall(dma);
+            self.run_ppu(3 * dma, is_debug, park_frame)?;
+            self.apu.next(dma, &mut self.memory);
+            cpu_cycles += dma;
+        }
+
+        let mut stall = self.apu.take_dmc_stall();
+        if stall > 0 && self.dmc_controller_glitch {
+            // The joypad sees the DMC fetch as an extra read, so a bit is
+            // lost. Approximated to any fetch during the instruction.
+            if let Some(addr) = self.memory.last_joypad_read {
+                self.memory.get(addr as usize);
+            }
+        }
+
+        // DMC fetches stall the CPU but the PPU and APU keep running. The
+        // stall can end with another fetch.
+        while stall > 0 {
+            self.cpu.stall(stall);
+            self.run_ppu(3 * stall, is_debug, park_frame)?;
+            self.apu.next(stall, &mut self.memory);
+            cpu_cycles += stall;
+            stall = self.apu.take_dmc_stall();
+        }
+
+        if self.ppu.frame_count() != frame {
+            if let Some(callback) = self.vblank_callback.as_mut() {
+                callback();
+            }
+        }
         Ok(cpu_cycles)
     }
 
+    // Run the PPU. If `park_frame` is set, stop when the PPU leaves that
+    // frame and keep the remaining dots for later.
+    fn run_ppu(
+        &mut self,
+        dots: u64,
+        is_debug: bool,
+        park_frame: Option<u64>,
+    ) -> Result<(), &'static str> {
+        let frame = match park_frame {
+            Some(frame) => frame,
+            None => return self.ppu.next(dots, &mut self.memory, is_debug),
+        };
+
+        if self.ppu.frame_count() != frame {
+            self.ppu_dots_owed += dots;
+            return Ok(());
+        }
+        for done in 0..dots {
+            self.ppu.next(1, &mut self.memory, is_debug)?;
+            if self.ppu.frame_count() != frame {
+                self.ppu_dots_owed = dots - done - 1;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `callback` once per frame, after the instruction during which
+    /// the PPU entered vertical blank. Not kept in the save states.
+    pub fn set_vblank_callback(&mut self, callback: Box<dyn FnMut()>) {
+        self.vblank_callback = Some(callback);
+    }
+
+    /// Emulate the joypad read corruption caused by DMC fetches. Games that
+    /// play DMC samples read the joypad several times to work around it.
+    /// Off by default.
+    pub fn set_dmc_controller_glitch(&mut self, enabled: bool) {
+        self.dmc_controller_glitch = enabled;
+    }
+
+    /// Execute exactly one instruction (same as `tick`) and return what was
+    /// executed. Used for step-by-step debugging.
+    pub fn tick_instruction(&mut self) -> Result<Step, &'static str> {
+        let cycles = self.tick(self.is_debug)?;
+        let (pc, opcode) = self.cpu.last_instruction();
+        Ok(Step { cycles, pc, opcode })
+    }
+
+    /// Execute instructions until the CPU is about to execute the instruction
+    /// at `addr`. Returns the number of CPU cycles that were executed.
+    pub fn run_until_pc(&mut self, addr: u16) -> Result<u64, &'static str> {
+        let mut cycles = 0;
+        while self.cpu.get_pc() != addr {
+            cycles += self.tick(self.is_debug)?;
+        }
+        Ok(cycles)
+    }
+
+    /// Run instructions until at least `target` CPU cycles have elapsed.
+    /// Return the number of cycles executed, which can be a bit more than
+    /// `target` as instructions are not interrupted.
+    pub fn run_cycles(&mut self, target: u64) -> Result<u64, &'static str> {
+        let mut cycles = 0;
+        while cycles < target {
+            cycles += self.tick(self.is_debug)?;
+        }
+        Ok(cycles)
+    }
+
+    /// Execute instructions until the PPU starts the next vertical blank.
+    pub fn run_frame(&mut self) -> Result<(), &'static str> {
+        let frame = self.ppu.frame_count();
+        while self.ppu.frame_count() == frame {
+            self.tick(self.is_debug)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `run_frame` but execute at most `max_instructions` CPU
+    /// instructions, for ROMs that cannot be trusted to ever reach the
+    /// next frame. The console stays where it stopped.
+    pub fn run_frame_bounded(&mut self, max_instructions: u64) -> Result<(), NesError> {
+        let frame = self.ppu.frame_count();
+        let mut instructions = 0;
+        while self.ppu.frame_count() == frame {
+            if instructions == max_instructions {
+                return Err(NesError::WatchdogTripped);
+            }
+            self.tick(self.is_debug)?;
+            instructions += 1;
+        }
+        Ok(())
+    }
+
+    /// Set the state of both controllers then run exactly one frame. Inputs
+    /// only change at frame boundaries so that the emulation is deterministic
+    /// for the same sequence of inputs.
+    pub fn advance_frame_with_inputs(
+        &mut self,
+        p1: JoypadState,
+        p2: JoypadState,
+    ) -> Result<(), &'static str> {
+        self.memory.joypad_p1.set_state(p1);
+        self.memory.joypad_p2.set_state(p2);
+        self.run_frame()
+    }
+
+    /// Frame advance for tool-assisted play. Set the state of both
+    /// controllers then run until the PPU is on the first dot of the next
+    /// vertical blank (line 241, dot 1), even from the middle of a frame.
+    /// The next instruction completes the dots of the CPU instruction that
+    /// was interrupted, so stopping there does not change the emulation.
+    pub fn frame_advance(&mut self, p1: JoypadState, p2: JoypadState) -> Result<(), &'static str> {
+        self.memory.joypad_p1.set_state(p1);
+        self.memory.joypad_p2.set_state(p2);
+        let frame = self.ppu.frame_count();
+        while self.ppu.frame_count() == frame {
+            self.step(self.is_debug, true)?;
+        }
+        Ok(())
+    }
+
+    /// Playback speed: 0.25 for slow motion, 2.0 for fast forward... Not
+    /// kept in the save states.
+    pub fn set_speed(&mut self, multiplier: f64) {
+        self.speed.set_multiplier(multiplier);
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed.multiplier()
+    }
+
+    /// To call once per frame of the front-end loop. Return the number of
+    /// frames to emulate for the current speed, and the factor to stretch
+    /// the audio of these frames with (see `apu::queue::stretch`).
+    pub fn frames_to_run(&mut self) -> (u32, f64) {
+        self.speed.next_frame()
+    }
+
     pub fn audio_samples(&mut self) -> Vec<i16> {
         self.apu.samples()
     }
@@ -133,19 +777,53 @@ impl Nes {
                 Err(err) => println!("Error while saving state: {}", err),
                 Ok(_) => println!("Successfully saved to {}", self.get_save_name()),
             },
-            EmulatorInput::INPUT(player, action, state) => {
-                //
-                match (player, state) {
-                    (Player::One, InputState::Pressed) => {
-                        self.memory.joypad_p1.button_down(&action)
-                    }
-                    (Player::Two, InputState::Pressed) => {
-                        self.memory.joypad_p2.button_down(&action)
-                    }
-                    (Player::One, InputState::Released) => self.memory.joypad_p1.button_up(&action),
-                    (Player::Two, InputState::Released) => self.memory.joypad_p2.button_up(&action),
+            EmulatorInput::SCREENSHOT => {
+                let path = self.get_screenshot_name();
+                let result = std::fs::create_dir_all("screenshots")
+                    .map_err(NesError::from)
+                    .and_then(|_| self.screenshot(&path, self.pixel_aspect));
+                match result {
+                    Err(err) => println!("Error while taking screenshot: {}", err),
+                    Ok(_) => println!("Screenshot saved to {}", path),
                 }
             }
+            EmulatorInput::RESET => self.reset(),
+            EmulatorInput::INPUT(player, action, state) => self.set_button(player, action, state),
+        }
+    }
+
+    /// Press or release a button of a controller. The change is visible on
+    /// the next read of $4016/$4017.
+    pub fn set_button(&mut self, player: Player, action: InputAction, state: InputState) {
+        let joypad = self.port_mut(player);
+        match state {
+            InputState::Pressed => joypad.button_down(&action),
+            InputState::Released => joypad.button_up(&action),
+        }
+    }
+
+    /// Plug a device in a controller port. Both ports have a standard
+    /// controller by default.
+    pub fn set_port_device(&mut self, player: Player, kind: ControllerKind) {
+        self.port_mut(player).set_kind(kind);
+    }
+
+    pub fn port_device(&self, player: Player) -> ControllerKind {
+        match player {
+            Player::One => self.memory.joypad_p1.kind(),
+            Player::Two => self.memory.joypad_p2.kind(),
+        }
+    }
+
+    /// Pull or release the trigger of the Zapper plugged in the port.
+    pub fn set_zapper_trigger(&mut self, player: Player, pressed: bool) {
+        self.port_mut(player).set_trigger(pressed);
+    }
+
+    fn port_mut(&mut self, player: Player) -> &mut Joypad {
+        match player {
+            Player::One => &mut self.memory.joypad_p1,
+            Player::Two => &mut self.memory.joypad_p2,
         }
     }
 
@@ -165,6 +843,49 @@ impl Nes {
         format!("saves/saved_{}.json", self.rom_name)
     }
 
+    /// Timestamped name so that screenshots do not overwrite each other.
+    pub fn get_screenshot_name(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!(
+            "screenshots/{}_{}{:03}.png",
+            self.rom_name,
+            now.as_secs(),
+            now.subsec_millis()
+        )
+    }
+
+    /// Aspect of the screenshots taken by the SCREENSHOT input. Square by
+    /// default.
+    pub fn set_pixel_aspect(&mut self, aspect: PixelAspect) {
+        self.pixel_aspect = aspect;
+    }
+
+    pub fn pixel_aspect(&self) -> PixelAspect {
+        self.pixel_aspect
+    }
+
+    /// Write the current frame to a PNG file. With the NTSC aspect, the
+    /// frame is stretched horizontally to 292x240.
+    pub fn screenshot<P: AsRef<Path>>(&self, path: P, aspect: PixelAspect) -> Result<(), NesError> {
+        let mut frame = vec![0; self.width() * self.height() * 3];
+        self.frame_rgb(&mut frame)?;
+        let width = aspect.width(self.width());
+        if width != self.width() {
+            frame = graphic::resample_rows(&frame, self.width(), width);
+        }
+
+        let file = File::create(path)?;
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), width as u32, self.height() as u32);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&frame)?;
+        Ok(())
+    }
+
     pub fn save_state(&self) -> Result<(), String> {
         let mut file = OpenOptions::new()
             .write(true)
@@ -172,13 +893,842 @@ impl Nes {
             .create(true)
             .open(self.get_save_name())
             .map_err(|err| err.to_string())?;
+        // Header first, then the state.
+        let header =
+            serde_json::to_string(&StateHeader::current(self)).map_err(|err| err.to_string())?;
         let state = serde_json::to_string(&self).map_err(|err| err.to_string())?;
-        write!(file, "{}", state).map_err(|err| err.to_string())?;
+        write!(file, "{}\n{}", header, state).map_err(|err| err.to_string())?;
+
+        Ok(())
+    }
 
+    /// Add a Game Genie code (6 or 8 letters).
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), String> {
+        let cheat = Cheat::from_game_genie(code)?;
+        self.memory.cheats.push(cheat);
         Ok(())
     }
 
+    pub fn clear_cheats(&mut self) {
+        self.memory.cheats.clear();
+    }
+
     pub fn apply_new_sound_config(&mut self, levels: ApuLevels) {
         self.apu.levels = levels;
     }
+
+    /// Set the sample rate of the audio device. `audio_samples` will return
+    /// around hz/60 samples per frame.
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.apu.set_sample_rate(hz);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    /// Record all the APU register writes to a CSV file (cycle,addr,value)
+    /// until `stop_apu_log`. Replaces the current log if any.
+    pub fn start_apu_log<P: AsRef<Path>>(&mut self, path: P) -> Result<(), NesError> {
+        self.stop_apu_log()?;
+        let mut log = WriteLog::create(path)?;
+        log.set_cycle(self.apu.cycles);
+        self.memory.apu_mem.write_log = Some(log);
+        Ok(())
+    }
+
+    /// Stop recording the APU writes and flush the file.
+    pub fn stop_apu_log(&mut self) -> Result<(), NesError> {
+        if let Some(log) = self.memory.apu_mem.write_log.take() {
+            log.finish()?;
+        }
+        Ok(())
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::DMC_FETCH_STALL;
+    use crate::graphic::Color;
+    use crate::rom::INesFile;
+
+    /// Create a NES with the program loaded at $8000. The reset vector
+    /// points to the start of the program.
+    fn nes_with_program(program: &[u8]) -> Nes {
+        let mut prg_rom = vec![0; 0x4000];
+        for (i, b) in program.iter().enumerate() {
+            prg_rom[i] = *b;
+        }
+        // Reset vector -> $8000
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0x80;
+
+        let ines = INesFile::new(
+            prg_rom,
+            1,
+            vec![0; 0x2000],
+            1,
+            0,
+            0,
+            0,
+            0,
+            0,
+            "test".to_owned(),
+        );
+        Nes::new(ines).unwrap()
+    }
+
+    #[test]
+    fn run_cycles_advances_ppu() {
+        // JMP $8000
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let cycles = nes.run_cycles(1000).unwrap();
+        assert!((1000..1003).contains(&cycles));
+
+        let dots = nes.ppu.scanline() as u64 * 341 + nes.ppu.dot() as u64;
+        assert_eq!(3 * cycles, dots);
+    }
+
+    #[test]
+    fn tick_instruction_returns_opcodes() {
+        // LDA #$01; TAX; INX; STX $00; JMP $8000
+        let mut nes = nes_with_program(&[0xA9, 0x01, 0xAA, 0xE8, 0x86, 0x00, 0x4C, 0x00, 0x80]);
+
+        let expected = [
+            (0x8000, 0xA9, 2),
+            (0x8002, 0xAA, 2),
+            (0x8003, 0xE8, 2),
+            (0x8004, 0x86, 3),
+            (0x8006, 0x4C, 3),
+            (0x8000, 0xA9, 2),
+        ];
+        for &(pc, opcode, cycles) in expected.iter() {
+            let step = nes.tick_instruction().unwrap();
+            assert_eq!(Step { cycles, pc, opcode }, step);
+        }
+    }
+
+    #[test]
+    fn audio_samples_after_one_frame() {
+        let mut nes = nes_with_program(&[
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x15, 0x40, // STA $4015 (enable pulse 1)
+            0xA9, 0xBF, // LDA #$BF
+            0x8D, 0x00, 0x40, // STA $4000 (constant volume 15)
+            0xA9, 0xFD, // LDA #$FD
+            0x8D, 0x02, 0x40, // STA $4002
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x03, 0x40, // STA $4003
+            0x4C, 0x14, 0x80, // JMP $8014
+        ]);
+        nes.run_frame().unwrap();
+
+        let samples = nes.audio_samples();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().any(|s| *s != 0));
+        // drained
+        assert!(nes.audio_samples().is_empty());
+    }
+
+    #[test]
+    fn frame_hash_is_fnv1a() {
+        // Reference values of 64 bits FNV-1a.
+        assert_eq!(0xcbf29ce484222325, fnv1a(b""));
+        assert_eq!(0xaf63dc4c8601ec8c, fnv1a(b"a"));
+        assert_eq!(0x85944171f73967e8, fnv1a(b"foobar"));
+
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_frame().unwrap();
+        let hash = nes.frame_hash();
+        nes.run_frame().unwrap();
+        assert_eq!(hash, nes.frame_hash());
+    }
+
+    #[test]
+    fn screenshot_is_a_png() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_frame().unwrap();
+
+        let path = std::env::temp_dir().join("nesemu_screenshot_test.png");
+        nes.screenshot(&path, PixelAspect::Square).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let (info, _) = decoder.read_info().unwrap();
+        assert_eq!((256, 240), (info.width, info.height));
+        assert_eq!(png::ColorType::RGB, info.color_type);
+
+        nes.screenshot(&path, PixelAspect::Ntsc).unwrap();
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let (info, _) = decoder.read_info().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((292, 240), (info.width, info.height));
+    }
+
+    #[test]
+    fn invalid_rom_bytes() {
+        assert!(Nes::from_rom_bytes("empty", vec![]).is_err());
+        assert!(Nes::from_rom_bytes("garbage", vec![0xFF; 0x6000]).is_err());
+
+        // Truncated PRG-ROM
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0; 0x100]);
+        assert!(Nes::from_rom_bytes("truncated", bytes).is_err());
+
+        // Mapper 7 is not supported
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x70, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0; 0x6000]);
+        assert!(Nes::from_rom_bytes("mapper7", bytes).is_err());
+    }
+
+    #[test]
+    fn invalid_rom_file() {
+        let path = std::env::temp_dir().join("nesemu_test_truncated.nes");
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0; 0x100]);
+        std::fs::write(&path, bytes).unwrap();
+        let result = Nes::from_rom_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(NesError::RomParse(_)) => (),
+            Err(err) => panic!("Expected RomParse, got {:?}", err),
+            Ok(_) => panic!("The ROM is truncated"),
+        }
+
+        match Nes::from_rom_file(std::env::temp_dir().join("nesemu_no_such_rom.nes")) {
+            Err(NesError::Io(_)) => (),
+            Err(err) => panic!("Expected Io, got {:?}", err),
+            Ok(_) => panic!("The file does not exist"),
+        }
+    }
+
+    #[test]
+    fn debug_overlay_after_frame() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_frame().unwrap();
+        assert!(nes.debug_overlay().is_none());
+        // PPUADDR writes are ignored until the PPU is warmed up.
+        nes.run_frame().unwrap();
+
+        // attribute of the top-left quadrant = 2
+        nes.memory.set(0x2006, 0x23);
+        nes.memory.set(0x2006, 0xC0);
+        nes.memory.set(0x2007, 0b10);
+
+        nes.set_debug_overlay(DebugOverlay::TileGrid);
+        nes.run_frame().unwrap();
+        let overlay = nes.debug_overlay().unwrap();
+        assert_eq!(3, overlay[0]);
+        assert_eq!(0, overlay[256 + 1]);
+        assert_eq!(1, overlay[32]);
+    }
+
+    #[test]
+    fn state_version_mismatch() {
+        let nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+
+        let mut header = StateHeader::current(&nes);
+        header.version += 1;
+        let state = nes.serialize_with_header(&header).unwrap();
+        let err = Nes::deserialize_state(&state).err().unwrap();
+        assert_eq!(
+            format!(
+                "invalid save state: save version mismatch: file v{}, emulator v{}",
+                STATE_VERSION + 1,
+                STATE_VERSION
+            ),
+            err.to_string()
+        );
+
+        let mut header = StateHeader::current(&nes);
+        header.mapper_version += 1;
+        let state = nes.serialize_with_header(&header).unwrap();
+        match Nes::deserialize_state(&state) {
+            Err(NesError::BadState(msg)) => {
+                assert!(msg.starts_with("save version mismatch for mapper 0"))
+            }
+            _ => panic!("Expected BadState"),
+        }
+    }
+
+    #[test]
+    fn poke_does_not_switch_bank() {
+        // UxROM with 4 banks. Each bank is filled with its number.
+        let mut prg_rom = vec![];
+        for bank in 0..4 {
+            prg_rom.extend(vec![bank as u8; 0x4000]);
+        }
+        let ines = INesFile::new(prg_rom, 4, vec![], 0, 0, 0x20, 0, 0, 0, "test".to_owned());
+        let mut nes = Nes::new(ines).unwrap();
+
+        // Poke to the bank select register. Bank 0 is still mapped.
+        nes.poke(0x8000, 2);
+        assert_eq!(2, nes.peek(0x8000));
+        assert_eq!(0, nes.peek(0x8001));
+
+        // Normal write switches to bank 1
+        nes.memory.set(0x8000, 1);
+        assert_eq!(1, nes.peek(0x8001));
+
+        // RAM
+        nes.poke(0x0010, 0x42);
+        assert_eq!(0x42, nes.peek(0x0010));
+        assert_eq!(0x42, nes.peek(0x0810));
+    }
+
+    #[test]
+    fn player_two_input_only_on_4017() {
+        let mut nes = nes_with_program(&[]);
+        nes.handle_event(EmulatorInput::INPUT(
+            Player::Two,
+            InputAction::A,
+            InputState::Pressed,
+        ));
+
+        // strobe both controllers
+        nes.memory.set(0x4016, 1);
+        nes.memory.set(0x4016, 0);
+
+        for _ in 0..8 {
+            assert_eq!(0, nes.memory.get(0x4016) & 1);
+        }
+        assert_eq!(1, nes.memory.get(0x4017) & 1);
+        for _ in 1..8 {
+            assert_eq!(0, nes.memory.get(0x4017) & 1);
+        }
+    }
+
+    #[test]
+    fn set_button_is_read_on_4016() {
+        let mut nes = nes_with_program(&[]);
+        nes.set_button(Player::One, InputAction::A, InputState::Pressed);
+        nes.memory.set(0x4016, 1);
+        nes.memory.set(0x4016, 0);
+        assert_eq!(1, nes.memory.get(0x4016) & 1);
+
+        nes.set_button(Player::One, InputAction::A, InputState::Released);
+        nes.memory.set(0x4016, 1);
+        nes.memory.set(0x4016, 0);
+        assert_eq!(0, nes.memory.get(0x4016) & 1);
+    }
+
+    #[test]
+    fn vblank_callback_once_per_frame() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let count = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&count);
+        nes.set_vblank_callback(Box::new(move || counter.set(counter.get() + 1)));
+
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+        assert_eq!(3, count.get());
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_cpu() {
+        // LDA #$10; STA $4015; NOP
+        let mut nes = nes_with_program(&[0xA9, 0x10, 0x8D, 0x15, 0x40, 0xEA]);
+        assert_eq!(2, nes.tick_instruction().unwrap().cycles);
+        // DMC starts and fetches its first byte right away.
+        assert_eq!(4 + DMC_FETCH_STALL, nes.tick_instruction().unwrap().cycles);
+        assert_eq!(2, nes.tick_instruction().unwrap().cycles);
+    }
+
+    #[test]
+    fn oam_region_after_dma() {
+        let mut nes = nes_with_program(&[]);
+        let sprite = [0x20, 0x05, 0x41, 0x80];
+        for (i, b) in sprite.iter().enumerate() {
+            nes.memory.set(0x0200 + i, *b);
+        }
+        nes.memory.set(0x4014, 0x02);
+
+        let oam = nes.memory_region(MemRegion::Oam);
+        assert_eq!(256, oam.len());
+        assert_eq!(&sprite[..], &oam[..4]);
+        assert_eq!(0x41, nes.memory_region(MemRegion::InternalRam)[0x0202]);
+    }
+
+    #[test]
+    fn oam_dma_advances_ppu() {
+        // LDA #$02, STA $4014, JMP $8005
+        let mut nes = nes_with_program(&[0xA9, 0x02, 0x8D, 0x14, 0x40, 0x4C, 0x05, 0x80]);
+        let dots = |nes: &Nes| {
+            let (line, dot) = nes.ppu_position();
+            line * 341 + dot
+        };
+        nes.tick(false).unwrap();
+        let before = dots(&nes);
+        let cycles = nes.tick(false).unwrap();
+        assert!(cycles == 4 + 513 || cycles == 4 + 514, "{}", cycles);
+        assert_eq!(before + 3 * cycles as usize, dots(&nes));
+
+        // Only one transfer.
+        assert_eq!(3, nes.tick(false).unwrap());
+    }
+
+    #[test]
+    fn replace_chr_bank() {
+        let mut nes = nes_with_program(&[]);
+        assert_eq!(0x2000, nes.memory.mapper.chr_bank_size());
+        nes.replace_chr_bank(0, &[0xAB; 0x2000]).unwrap();
+        assert_eq!(0xAB, nes.memory.mapper.read_chr(0x0000));
+        assert_eq!(0xAB, nes.memory.mapper.read_chr(0x1FFF));
+
+        assert!(nes.replace_chr_bank(0, &[0; 0x1000]).is_err());
+        assert!(nes.replace_chr_bank(1, &[0; 0x2000]).is_err());
+    }
+
+    #[test]
+    fn replace_prg_bank_of_nrom_128() {
+        let mut nes = nes_with_program(&[]);
+        nes.replace_prg_bank(0, &[0xEA; 0x4000]).unwrap();
+        // Mirrored at $C000
+        assert_eq!(0xEA, nes.memory.get(0x8000));
+        assert_eq!(0xEA, nes.memory.get(0xFFFC));
+        assert!(nes.replace_prg_bank(1, &[0; 0x4000]).is_err());
+    }
+
+    #[test]
+    fn select_game_of_gxrom() {
+        let prg_rom = (0..4).flat_map(|i| vec![i / 2; 0x4000]).collect();
+        let ines = INesFile::new(prg_rom, 4, vec![0; 0x4000], 2, 0, 0x20, 0x40, 0, 0, "test".to_owned());
+        let mut nes = Nes::new(ines).unwrap();
+        assert_eq!(2, nes.game_count());
+        assert_eq!(0, nes.memory.get(0x8000));
+
+        nes.select_game(1).unwrap();
+        assert_eq!(1, nes.memory.get(0x8000));
+        assert!(nes.select_game(2).is_err());
+        let info = nes.bank_info();
+        assert_eq!((0x8000, vec![1]), (info.prg_bank_size, info.prg_banks));
+        assert_eq!((0x2000, vec![1]), (info.chr_bank_size, info.chr_banks));
+
+        // Regular cartridges have one game.
+        let mut nes = nes_with_program(&[]);
+        assert_eq!(1, nes.game_count());
+        assert!(nes.select_game(1).is_err());
+    }
+
+    #[test]
+    fn write_watch_reports_pc() {
+        // LDA #$42; NOP; STA $0200; JMP $8006
+        let mut nes = nes_with_program(&[0xA9, 0x42, 0xEA, 0x8D, 0x00, 0x02, 0x4C, 0x06, 0x80]);
+        nes.add_watch(0x0200, AccessKind::Write);
+        nes.run_until_pc(0x8006).unwrap();
+
+        assert_eq!(
+            Some(WatchHit {
+                addr: 0x0200,
+                kind: AccessKind::Write,
+                pc: 0x8003,
+                value: 0x42,
+            }),
+            nes.take_watch_hit()
+        );
+        assert_eq!(None, nes.take_watch_hit());
+    }
+
+    #[test]
+    fn run_until_pc_stops_at_address() {
+        // LDA #$01; LDX #$02; LDY #$03; JMP $8006
+        let mut nes = nes_with_program(&[0xA9, 0x01, 0xA2, 0x02, 0xA0, 0x03, 0x4C, 0x06, 0x80]);
+        let cycles = nes.run_until_pc(0x8004).unwrap();
+
+        assert_eq!(0x8004, nes.cpu().get_pc());
+        assert_eq!(4, cycles);
+        assert_eq!(0x01, nes.cpu().get_acc());
+        assert_eq!(0x02, nes.cpu().get_regx());
+        // LDY was not executed yet.
+        assert_eq!(0x00, nes.cpu().get_regy());
+    }
+
+    #[test]
+    fn frame_rgb_wrong_size() {
+        let nes = Nes::empty();
+        let mut out = vec![0; 256 * 240];
+        assert!(nes.frame_rgb(&mut out).is_err());
+    }
+
+    #[test]
+    fn frame_rgb_fills_buffer() {
+        let nes = Nes::empty();
+        let mut out = vec![0xFF; 256 * 240 * 3];
+        nes.frame_rgb(&mut out).unwrap();
+        assert_eq!(184320, out.len());
+        // Empty screen is black.
+        assert!(out.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn last_frame_while_paused() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.ppu.set_colors([Color { r: 1, g: 2, b: 3 }; 64]);
+        nes.run_frame().unwrap();
+        nes.handle_event(EmulatorInput::PAUSE);
+        assert!(nes.is_pause);
+
+        let position = (nes.ppu.scanline(), nes.ppu.dot(), nes.ppu.frame_count());
+        let first = nes.last_frame();
+        let second = nes.last_frame();
+        assert_eq!(first, second);
+        assert_eq!(256 * 240, first.len());
+        assert_eq!((1, 2, 3), first[0]);
+        assert_eq!(
+            position,
+            (nes.ppu.scanline(), nes.ppu.dot(), nes.ppu.frame_count())
+        );
+    }
+
+    #[test]
+    fn frame_indices_of_background() {
+        let mut nes = Nes::empty();
+        // Tile 0, used by the whole nametable: 4 pixels of color 1 then 4
+        // pixels of color 0.
+        for row in 0..8 {
+            nes.memory.mapper.write_chr(row, 0xF0);
+        }
+        nes.memory.set(0x2006, 0x3F);
+        nes.memory.set(0x2006, 0x00);
+        nes.memory.set(0x2007, 0x0F);
+        nes.memory.set(0x2007, 0x21);
+        nes.memory.set(0x2006, 0x00);
+        nes.memory.set(0x2006, 0x00);
+        nes.memory.set(0x2001, 0x0A);
+
+        // Second frame, the first one starts without the pre-render line.
+        nes.ppu.next(2 * 341 * 262, &mut nes.memory, false).unwrap();
+        let indices = nes.frame_indices();
+        assert_eq!(256 * 240, indices.len());
+        let row = [0x21, 0x21, 0x21, 0x21, 0x0F, 0x0F, 0x0F, 0x0F];
+        assert_eq!(&row, &indices[..8]);
+        assert_eq!(&row, &indices[256 * 100 + 128..256 * 100 + 136]);
+    }
+
+    #[test]
+    fn overscan_crops_frame() {
+        let mut nes = Nes::empty();
+        // One color per row.
+        for (i, pixel) in nes.ppu.pixels.iter_mut().enumerate() {
+            *pixel = (i / 256) as u8 & 0x3F;
+        }
+        let mut full = vec![0; 256 * 240 * 3];
+        nes.frame_rgb(&mut full).unwrap();
+
+        nes.set_overscan(8, 8, 0, 0);
+        assert_eq!(256, nes.visible_width());
+        assert_eq!(224, nes.visible_height());
+        let mut cropped = vec![0; 256 * 224 * 3];
+        nes.visible_frame_rgb(&mut cropped).unwrap();
+        assert_eq!(&full[8 * 256 * 3..9 * 256 * 3], &cropped[..256 * 3]);
+        assert_eq!(&full[231 * 256 * 3..232 * 256 * 3], &cropped[223 * 256 * 3..]);
+
+        // Full frame is still available.
+        let mut out = vec![0; 256 * 240 * 3];
+        nes.frame_rgb(&mut out).unwrap();
+        assert_eq!(full, out);
+    }
+
+    #[test]
+    fn frame_rgb_uses_ppu_palette() {
+        let mut nes = Nes::empty();
+        let mut colors = palette::build_default_colors();
+        colors[palette::BLACK_INDEX as usize] = crate::graphic::Color::rgb(1, 2, 3);
+        nes.ppu.set_colors(colors);
+
+        let mut out = vec![0; 256 * 240 * 3];
+        nes.frame_rgb(&mut out).unwrap();
+        assert_eq!(&[1, 2, 3], &out[0..3]);
+        assert_eq!(&[1, 2, 3], &out[out.len() - 3..]);
+    }
+
+    #[test]
+    fn load_palette_from_file() {
+        let content: Vec<u8> = (0..192).map(|i| i as u8).collect();
+        let path = std::env::temp_dir().join("nesemu_test_palette.pal");
+        std::fs::write(&path, &content).unwrap();
+
+        let mut nes = Nes::empty();
+        nes.load_palette(&path).unwrap();
+        let color = nes.ppu.colors()[1];
+        assert_eq!(&content[3..6], &[color.r, color.g, color.b]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn serialize_deserialize_state() {
+        // INC $10; JMP $8000
+        let mut nes = nes_with_program(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        nes.run_until_pc(0x8002).unwrap();
+        let state = nes.serialize_state().unwrap();
+
+        nes.run_until_pc(0x8000).unwrap();
+        nes.run_until_pc(0x8002).unwrap();
+        assert_eq!(2, nes.memory().peek(0x10));
+
+        let mut restored = Nes::deserialize_state(&state).unwrap();
+        assert_eq!(0x8002, restored.cpu().get_pc());
+        assert_eq!(1, restored.memory().peek(0x10));
+    }
+
+    #[test]
+    fn sample_rate_after_load() {
+        // Pulse 1 at full volume.
+        let mut nes = nes_with_program(&[
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x15, 0x40, // STA $4015
+            0xA9, 0xBF, // LDA #$BF
+            0x8D, 0x00, 0x40, // STA $4000
+            0xA9, 0xFD, // LDA #$FD
+            0x8D, 0x02, 0x40, // STA $4002
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x03, 0x40, // STA $4003
+            0x4C, 0x14, 0x80, // JMP $8014
+        ]);
+        nes.set_sample_rate(48000);
+        let state = nes.serialize_state().unwrap();
+
+        let mut restored = Nes::deserialize_state(&state).unwrap();
+        assert_eq!(48000, restored.sample_rate());
+        nes.run_frame().unwrap();
+        restored.run_frame().unwrap();
+        let samples = nes.audio_samples();
+        assert!(samples.iter().any(|s| *s != 0));
+        assert_eq!(samples, restored.audio_samples());
+    }
+
+    #[test]
+    fn last_frame_after_load() {
+        // JMP $8000
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.ppu.set_colors([crate::graphic::Color::rgb(200, 100, 50); 64]);
+        nes.run_frame().unwrap();
+        let state = nes.serialize_state().unwrap();
+
+        let mut restored = Nes::deserialize_state(&state).unwrap();
+        restored.ppu.set_colors(*nes.ppu.colors());
+        assert_eq!(nes.last_frame(), restored.last_frame());
+    }
+
+    #[test]
+    fn same_inputs_same_state() {
+        // Strobe the controller, add the A button to $10 and loop.
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x29,
+            0x01, 0x65, 0x10, 0x85, 0x10, 0x4C, 0x00, 0x80,
+        ];
+        let mut nes1 = nes_with_program(&program);
+        let mut nes2 = nes_with_program(&program);
+
+        for frame in 0..10 {
+            let p1 = if frame % 3 == 0 {
+                JoypadState::default().with(InputAction::A)
+            } else {
+                JoypadState::default()
+            };
+            let p2 = JoypadState::default().with(InputAction::START);
+            nes1.advance_frame_with_inputs(p1, p2).unwrap();
+            nes2.advance_frame_with_inputs(p1, p2).unwrap();
+            assert_eq!(
+                nes1.serialize_state().unwrap(),
+                nes2.serialize_state().unwrap()
+            );
+        }
+        assert_ne!(0, nes1.memory().peek(0x10));
+    }
+
+    #[test]
+    fn frame_advance_stops_at_vblank() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let mut reference = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_cycles(1000).unwrap();
+        assert_ne!(0, nes.ppu_position().1);
+
+        let none = JoypadState::default();
+        for _ in 0..2 {
+            nes.frame_advance(none, none).unwrap();
+            assert_eq!((241, 1), nes.ppu_position());
+        }
+
+        // Same state as without stopping at vblank.
+        nes.run_frame().unwrap();
+        for _ in 0..3 {
+            reference.run_frame().unwrap();
+        }
+        assert_eq!(
+            reference.serialize_state().unwrap(),
+            nes.serialize_state().unwrap()
+        );
+    }
+
+    #[test]
+    fn recent_trace_keeps_last_instructions() {
+        // NOPs then LDA #$42
+        let mut program = vec![0xEA; 9];
+        program.extend(&[0xA9, 0x42]);
+        let mut nes = nes_with_program(&program);
+        nes.tick(false).unwrap();
+        assert!(nes.recent_trace().is_empty());
+
+        nes.set_trace_capacity(4);
+        for _ in 0..9 {
+            nes.tick(false).unwrap();
+        }
+        let trace = nes.recent_trace();
+        assert_eq!(4, trace.len());
+        assert!(trace[0].starts_with("8006  EA"), "{}", trace[0]);
+        assert_eq!("8009  A9  A:00 X:00 Y:00 P:24 SP:FD", trace[3]);
+    }
+
+    #[test]
+    fn with_handmade_nrom() {
+        // LDA #$42; STA $10; JMP $8004
+        let mut prg = vec![0; 0x4000];
+        prg[..7].copy_from_slice(&[0xA9, 0x42, 0x85, 0x10, 0x4C, 0x04, 0x80]);
+        prg[0x3FFD] = 0x80;
+        let mut mapper = MapperType::Nrom(crate::mapper::nrom::Nrom::new());
+        mapper.replace_prg_bank(0, &prg).unwrap();
+
+        let mut nes = Nes::with_mapper(mapper, Mirroring::VERTICAL, "handmade".to_owned());
+        assert_eq!(0x8000, nes.cpu().get_pc());
+        nes.run_until_pc(0x8004).unwrap();
+        assert_eq!(0x42, nes.peek(0x10));
+        assert_eq!(Mirroring::VERTICAL, nes.memory.mapper.get_mirroring());
+    }
+
+    #[test]
+    fn reset_keeps_ram() {
+        // LDA #$42; STA $10; JMP $8004
+        let mut nes = nes_with_program(&[0xA9, 0x42, 0x85, 0x10, 0x4C, 0x04, 0x80]);
+        nes.run_until_pc(0x8004).unwrap();
+
+        nes.reset();
+        assert_eq!(0x8000, nes.cpu().get_pc());
+        assert_eq!(0x42, nes.memory().peek(0x10));
+    }
+
+    #[test]
+    fn apu_log_records_writes() {
+        let mut nes = nes_with_program(&[
+            0xA9, 0xBF, // LDA #$BF
+            0x8D, 0x00, 0x40, // STA $4000
+            0xA9, 0xFD, // LDA #$FD
+            0x8D, 0x02, 0x40, // STA $4002
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x03, 0x40, // STA $4003
+            0x4C, 0x0F, 0x80, // JMP $800F
+        ]);
+        let path = std::env::temp_dir().join("nesemu_test_apu_log.csv");
+        nes.start_apu_log(&path).unwrap();
+        nes.run_until_pc(0x800F).unwrap();
+        nes.stop_apu_log().unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut lines = content.lines();
+        assert_eq!(Some("cycle,addr,value"), lines.next());
+        let writes: Vec<(u64, String)> = lines
+            .map(|line| {
+                let (cycle, write) = line.split_at(line.find(',').unwrap());
+                (cycle.parse().unwrap(), write[1..].to_owned())
+            })
+            .collect();
+        let expected = ["4000,BF", "4002,FD", "4003,00"];
+        assert_eq!(expected.len(), writes.len());
+        for (write, expected) in writes.iter().zip(expected.iter()) {
+            assert_eq!(expected, &write.1);
+        }
+        assert!(writes.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn disconnected_port() {
+        // Strobe, then read $4016 and $4017 twice.
+        let mut nes = nes_with_program(&[
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x16, 0x40, // STA $4016
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x16, 0x40, // STA $4016
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x85, 0x10, // STA $10
+            0xAD, 0x17, 0x40, // LDA $4017
+            0x85, 0x11, // STA $11
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x85, 0x12, // STA $12
+            0xAD, 0x17, 0x40, // LDA $4017
+            0x85, 0x13, // STA $13
+            0x4C, 0x1E, 0x80, // JMP $801E
+        ]);
+        nes.set_port_device(Player::Two, ControllerKind::None);
+        assert_eq!(ControllerKind::Standard, nes.port_device(Player::One));
+        let a_pressed = JoypadState::default().with(InputAction::A);
+        nes.memory.joypad_p1.set_state(a_pressed);
+        nes.memory.joypad_p2.set_state(a_pressed);
+        nes.run_until_pc(0x801E).unwrap();
+
+        // Only the open bus bits for port 2.
+        assert_eq!(0x41, nes.peek(0x10));
+        assert_eq!(0x40, nes.peek(0x11));
+        assert_eq!(0x40, nes.peek(0x12));
+        assert_eq!(0x40, nes.peek(0x13));
+    }
+
+    #[cfg(feature = "debug-internals")]
+    #[test]
+    fn cpu_mut_changes_next_instruction() {
+        // JMP $8000, and LDA #$42 at $8010
+        let mut program = vec![0x4C, 0x00, 0x80];
+        program.resize(0x10, 0xEA);
+        program.extend(&[0xA9, 0x42]);
+        let mut nes = nes_with_program(&program);
+
+        nes.cpu_mut().set_pc(0x8010);
+        nes.tick(false).unwrap();
+        assert_eq!(0x42, nes.cpu().get_acc());
+        assert_eq!(0x8012, nes.cpu().get_pc());
+    }
+
+    #[test]
+    fn last_frame_dimmed() {
+        // JMP $8000
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.ppu.set_colors([crate::graphic::Color::rgb(200, 100, 50); 64]);
+        nes.run_frame().unwrap();
+        assert!(nes.last_frame().iter().all(|p| *p == (200, 100, 50)));
+
+        assert_eq!(nes.last_frame(), nes.last_frame_dimmed(1.0));
+        let dimmed = nes.last_frame_dimmed(0.5);
+        assert_eq!(nes.last_frame().len(), dimmed.len());
+        assert!(dimmed.iter().all(|p| *p == (100, 50, 25)));
+        assert!(nes.last_frame_dimmed(-1.0).iter().all(|p| *p == (0, 0, 0)));
+    }
+
+    #[test]
+    fn run_frame_bounded_trips_watchdog() {
+        // JMP $8000
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        match nes.run_frame_bounded(1000) {
+            Err(NesError::WatchdogTripped) => (),
+            other => panic!("Expected the watchdog, got {:?}", other),
+        }
+        assert_eq!(0, nes.ppu.frame_count());
+
+        // A frame is around 10000 JMP.
+        nes.run_frame_bounded(20_000).unwrap();
+        assert_eq!(1, nes.ppu.frame_count());
+    }
 }
@@ -0,0 +1,136 @@
+//! Frame time monitoring for the front-ends. They record how long each frame
+//! took and can skip frames or grow the audio buffer when the emulation
+//! cannot keep up.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// One frame at 60 FPS.
+pub const NTSC_FRAME_BUDGET: Duration = Duration::from_nanos(16_666_667);
+/// Number of frames kept for the statistics (one second at 60 FPS).
+pub const DEFAULT_WINDOW: usize = 60;
+
+#[derive(Debug, Clone)]
+pub struct PerfMonitor {
+    budget: Duration,
+    capacity: usize,
+    durations: VecDeque<Duration>,
+    total: Duration,
+}
+
+impl Default for PerfMonitor {
+    fn default() -> Self {
+        Self::new(NTSC_FRAME_BUDGET, DEFAULT_WINDOW)
+    }
+}
+
+impl PerfMonitor {
+    /// Keep the last `window` frame durations. A frame should take less
+    /// than `budget`.
+    pub fn new(budget: Duration, window: usize) -> Self {
+        let capacity = window.max(1);
+        Self {
+            budget,
+            capacity,
+            durations: VecDeque::with_capacity(capacity),
+            total: Duration::new(0, 0),
+        }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        if self.durations.len() == self.capacity {
+            if let Some(oldest) = self.durations.pop_front() {
+                self.total -= oldest;
+            }
+        }
+        self.durations.push_back(duration);
+        self.total += duration;
+    }
+
+    /// Average of the recorded frames. None if nothing was recorded.
+    pub fn average(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            None
+        } else {
+            Some(self.total / self.durations.len() as u32)
+        }
+    }
+
+    /// 99th percentile (nearest rank) of the recorded frames.
+    pub fn p99(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.durations.iter().cloned().collect();
+        sorted.sort();
+        let rank = (sorted.len() * 99).div_ceil(100);
+        Some(sorted[rank - 1])
+    }
+
+    /// True when the average frame takes longer than the budget. A single
+    /// slow frame is not enough.
+    pub fn is_over_budget(&self) -> bool {
+        self.average().is_some_and(|avg| avg > self.budget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn rolling_average() {
+        let mut monitor = PerfMonitor::new(ms(16), 4);
+        assert_eq!(None, monitor.average());
+        for &d in [10, 20, 30, 40].iter() {
+            monitor.record(ms(d));
+        }
+        assert_eq!(Some(ms(25)), monitor.average());
+
+        // 10 is dropped
+        monitor.record(ms(50));
+        assert_eq!(Some(ms(35)), monitor.average());
+    }
+
+    #[test]
+    fn p99_is_the_slowest_frames() {
+        let mut monitor = PerfMonitor::new(ms(16), 200);
+        for d in 1..=200 {
+            monitor.record(ms(d));
+        }
+        assert_eq!(Some(ms(198)), monitor.p99());
+
+        let mut monitor = PerfMonitor::new(ms(16), 10);
+        monitor.record(ms(3));
+        assert_eq!(Some(ms(3)), monitor.p99());
+    }
+
+    #[test]
+    fn over_budget() {
+        let mut monitor = PerfMonitor::new(ms(16), 4);
+        assert!(!monitor.is_over_budget());
+
+        // One spike is not enough
+        for &d in [5, 5, 5, 40].iter() {
+            monitor.record(ms(d));
+        }
+        assert!(!monitor.is_over_budget());
+
+        for _ in 0..4 {
+            monitor.record(ms(20));
+        }
+        assert!(monitor.is_over_budget());
+
+        for _ in 0..4 {
+            monitor.record(ms(15));
+        }
+        assert!(!monitor.is_over_budget());
+    }
+}
@@ -36,6 +36,28 @@ impl RegisterType {
     }
 }
 
+/// Offset in the 2kb of VRAM of a nametable address ($2000-$3EFF). The 4
+/// logical nametables are mapped to the 2 physical ones depending on the
+/// mirroring. With one screen mirroring, all of them use the page
+/// `single_screen_page` (0 or 1) selected by the mapper.
+pub fn physical_nt_addr(logical: u16, mirroring: Mirroring, single_screen_page: usize) -> usize {
+    let table = ((logical >> 10) & 0b11) as usize;
+    let page = match mirroring {
+        Mirroring::HORIZONTAL => table >> 1,
+        Mirroring::VERTICAL => table & 1,
+        Mirroring::ONE_SCREEN => single_screen_page & 1,
+    };
+    page * 0x400 + (logical & 0x3FF) as usize
+}
+
+// After power on, the PPU ignores writes to PPUCTRL, PPUMASK, PPUSCROLL and
+// PPUADDR for around 29658 CPU cycles.
+pub const WARM_UP_CPU_CYCLES: u32 = 29658;
+
+// Enabling NMI in PPUCTRL during vblank raises the NMI a CPU cycle after the
+// write, so the CPU runs one more instruction before entering the handler.
+const NMI_DELAY: u8 = 3;
+
 #[derive(Serialize, Deserialize)]
 pub struct PpuMemory {
     // Interrupt flag
@@ -105,6 +127,28 @@ pub struct PpuMemory {
     pub palettes: Vec<u8>,    //0x0020
 
     pub is_rendering: bool,
+
+    // The PPU clears the secondary OAM during dots 1-64 of visible lines.
+    // Reading OAMDATA returns $FF during that time.
+    #[serde(default)]
+    pub secondary_oam_clear: bool,
+
+    // Reading PPUSTATUS right when the vblank flag is set will suppress the NMI
+    // for that frame. vblank_race is true during the few dots where this can happen.
+    #[serde(default)]
+    vblank_race: bool,
+
+    // NMI enable bit (PPUCTRL bit 7) of the last write. An NMI is raised
+    // when it goes from 0 to 1 during vblank.
+    #[serde(default)]
+    nmi_output: bool,
+    // PPU dots left before raising the NMI enabled by a PPUCTRL write.
+    #[serde(default)]
+    nmi_delay: u8,
+
+    // PPU dots left before the registers accept writes after power on.
+    #[serde(default)]
+    warm_up_dots: u32,
 }
 
 impl fmt::Debug for PpuMemory {
@@ -122,6 +166,51 @@ impl fmt::Debug for PpuMemory {
     }
 }
 
+/// Increment the coarse X part of v (yyy NN YYYYY XXXXX). Switch the
+/// horizontal nametable when reaching the end of the line.
+pub fn coarse_x_increment(mut v: u16) -> u16 {
+    if (v & 0x1F) == 31 {
+        // at the limit of the screen. We need to switch
+        // nametable in that case.
+        v &= !0x1F; // X = 0
+
+        // Switch nametable.
+        v ^= 0x400;
+    } else {
+        v += 1;
+    }
+    v
+}
+
+/// Increment the fine Y part of v, and the coarse Y when fine Y overflows.
+pub fn y_increment(mut v: u16) -> u16 {
+    // yyy NN YYYYY XXXXX
+    if (v & 0x7000) != 0x7000 {
+        // fine y is < 7.
+        v += 0x1000;
+    } else {
+        // reset fine.
+        v &= !0x7000;
+
+        let mut y = (v & 0x3e0) >> 5;
+
+        if y == 29 {
+            y = 0;
+            // switch vertical nametable
+            v ^= 0x800;
+        } else if y == 31 {
+            // y can be set out of bound to read attributes. in that case, wrap to 0
+            // without changing the nametable.
+            y = 0;
+        } else {
+            y += 1;
+        }
+
+        v = (v & !0x3e0) | (y << 5);
+    }
+    v
+}
+
 use self::RegisterType::*;
 impl PpuMemory {
     pub fn new() -> PpuMemory {
@@ -147,6 +236,11 @@ impl PpuMemory {
             nametable_2: vec![0; 0x400],
             palettes: vec![0; 0x20],
             is_rendering: false,
+            secondary_oam_clear: false,
+            vblank_race: false,
+            nmi_output: false,
+            nmi_delay: 0,
+            warm_up_dots: 0,
         }
     }
 
@@ -158,6 +252,44 @@ impl PpuMemory {
         self.nmi = false;
     }
 
+    /// Set the vblank flag at the start of vertical blank and raise the NMI.
+    /// Until the PPU calls `end_vblank_race` a few dots later, reading
+    /// PPUSTATUS cancels the NMI.
+    pub fn start_vblank(&mut self) {
+        self.ppustatus |= 0x80;
+        self.vblank_race = true;
+        self.raise_nmi();
+    }
+
+    /// Ignore writes to some registers until the PPU is warmed up.
+    pub fn start_warm_up(&mut self) {
+        self.warm_up_dots = 3 * WARM_UP_CPU_CYCLES;
+    }
+
+    /// Called by the PPU every dot.
+    pub fn warm_up_tick(&mut self) {
+        self.warm_up_dots = self.warm_up_dots.saturating_sub(1);
+    }
+
+    pub fn is_warming_up(&self) -> bool {
+        self.warm_up_dots > 0
+    }
+
+    pub fn end_vblank_race(&mut self) {
+        self.vblank_race = false;
+    }
+
+    /// Called by the PPU every dot. Raise the NMI enabled during vblank
+    /// once the delay is over.
+    pub fn nmi_delay_tick(&mut self) {
+        if self.nmi_delay > 0 {
+            self.nmi_delay -= 1;
+            if self.nmi_delay == 0 {
+                self.raise_nmi();
+            }
+        }
+    }
+
     pub fn v(&self) -> u16 {
         self.v
     }
@@ -186,7 +318,10 @@ impl PpuMemory {
     /// the ppu status is set by PPU with hardware (Vblank and so on?)
     pub fn update(&mut self, register_type: RegisterType, value: u8) {
         match register_type {
-            PPUCTRL => self.ppuctrl = value,
+            PPUCTRL => {
+                self.ppuctrl = value;
+                self.nmi_output = value & 0x80 == 0x80;
+            }
             PPUMASK => self.ppumask = value,
             PPUSTATUS => {
                 self.ppustatus = value;
@@ -204,6 +339,13 @@ impl PpuMemory {
     /// Write will set new value to register. This can have side effect on
     /// other registers.
     pub fn write(&mut self, register_type: RegisterType, value: u8, mapper: &mut MapperType) {
+        if self.is_warming_up() {
+            match register_type {
+                PPUCTRL | PPUMASK | PPUSCROLL | PPUADDR => return,
+                _ => (),
+            }
+        }
+
         match register_type {
             PPUCTRL => self.write_ctrl(value),
             PPUMASK => self.write_mask(value),
@@ -228,7 +370,25 @@ impl PpuMemory {
             }
             PPUSTATUS => self.read_status(),
             PPUDATA => self.read_data(mapper),
-            _ => 8,
+            OAMDATA => self.read_oamdata(),
+        }
+    }
+
+    /// Same as read but without side effects. PPUDATA returns the value that
+    /// would be returned by the next read.
+    pub fn peek_read(&self, register_type: RegisterType, mapper: &MapperType) -> u8 {
+        match register_type {
+            PPUSTATUS => self.ppustatus,
+            PPUDATA => {
+                let addr = self.v % 0x4000;
+                if addr >= 0x3F00 {
+                    self.peek_vram_at(addr as usize, mapper)
+                } else {
+                    self.vram_read_buffer
+                }
+            }
+            OAMDATA => self.read_oamdata(),
+            _ => 0,
         }
     }
 
@@ -240,18 +400,26 @@ impl PpuMemory {
         self.ppustatus = old_status & !0x80;
         // Reading PPU STATUS Will also clear the address latch.
         self.w = 0;
+        // Read too close to the vblank flag set. No NMI for this frame.
+        if self.vblank_race {
+            self.nmi = false;
+        }
         // self.t = 0;
         // self.v = 0;
         old_status
     }
 
     fn write_ctrl(&mut self, ctrl: u8) {
-        let nmi_enabled = self.ppuctrl & 0x80 == 0x80;
+        let nmi_output = ctrl & 0x80 == 0x80;
         self.ppuctrl = ctrl;
         self.t = (self.t & !0xc00) | ((ctrl & 0b11) as u16) << 10;
-        if !nmi_enabled {
-            self.raise_nmi();
+        // Enabling NMI while the vblank flag is set triggers an NMI after a
+        // short delay. Toggling the bit several times during vblank gives
+        // several NMIs.
+        if nmi_output && !self.nmi_output && self.ppustatus & 0x80 == 0x80 {
+            self.nmi_delay = NMI_DELAY;
         }
+        self.nmi_output = nmi_output;
     }
 
     fn write_mask(&mut self, mask: u8) {
@@ -262,6 +430,15 @@ impl PpuMemory {
         self.oam_addr = oamaddr;
     }
 
+    // Reading does not increment OAMADDR.
+    fn read_oamdata(&self) -> u8 {
+        if self.secondary_oam_clear {
+            0xFF
+        } else {
+            self.oam[self.oam_addr as usize]
+        }
+    }
+
     fn write_oamdata(&mut self, oamdata: u8) {
         // TODO ignored during rendering.
         // need to add flag is_rendering...
@@ -325,10 +502,20 @@ impl PpuMemory {
         let addr_latch = self.v;
 
         self.write_vram_at((addr_latch as usize) % 0x4000, data, mapper);
-        if self.ppuctrl & 4 == 4 {
-            self.v = addr_latch + 32;
+        self.increment_vram_addr();
+    }
+
+    // After a PPUDATA access, v is incremented by 1 or 32 (PPUCTRL bit 2).
+    // During rendering, v is used by the PPU to fetch tiles. The access
+    // triggers a coarse X and a Y increment instead.
+    fn increment_vram_addr(&mut self) {
+        let rendering_enabled = self.ppumask & 0x18 != 0;
+        if self.is_rendering && rendering_enabled {
+            self.v = y_increment(coarse_x_increment(self.v));
+        } else if self.ppuctrl & 4 == 4 {
+            self.v = self.v.wrapping_add(32) & 0x7FFF;
         } else {
-            self.v = addr_latch + 1;
+            self.v = self.v.wrapping_add(1) & 0x7FFF;
         }
     }
 
@@ -337,30 +524,10 @@ impl PpuMemory {
             0x0000..=0x1FFF => {
                 mapper.write_chr(addr, data);
             }
-            0x2000..=0x23FF => {
-                self.write_to_1st_nametable(addr, data);
-            }
-            0x2400..=0x27FF => match mapper.get_mirroring() {
-                Mirroring::HORIZONTAL => self.write_to_1st_nametable(addr, data),
-                Mirroring::VERTICAL => self.write_to_2nd_nametable(addr, data),
-                Mirroring::ONE_SCREEN => self.write_to_1st_nametable(addr, data),
-            },
-            0x2800..=0x2BFF => match mapper.get_mirroring() {
-                Mirroring::HORIZONTAL => self.write_to_2nd_nametable(addr, data),
-                Mirroring::VERTICAL => self.write_to_1st_nametable(addr, data),
-                Mirroring::ONE_SCREEN => self.write_to_1st_nametable(addr, data),
-            },
-            0x2C00..=0x2FFF => {
-                if mapper.get_mirroring() == Mirroring::ONE_SCREEN {
-                    self.write_to_1st_nametable(addr, data);
-                } else {
-                    self.write_to_2nd_nametable(addr, data);
-                }
-            }
-            0x3000..=0x3EFF => {
-                // Mirrors of 0x2000, 0x2EFFF
-                let newaddr = 0x2000 | (addr & 0xFFF);
-                self.write_vram_at(newaddr, data, mapper);
+            // Nametables and their mirrors
+            0x2000..=0x3EFF => {
+                let offset = self.nametable_offset(addr, mapper);
+                self.write_nametable(offset, data);
             }
             // palettes mirrors
             0x3F00..=0x3FFF => {
@@ -380,23 +547,27 @@ impl PpuMemory {
         }
     }
 
-    fn write_to_1st_nametable(&mut self, addr: usize, data: u8) {
-        let offset = addr % 0x400;
-        self.nametable_1[offset] = data;
+    fn nametable_offset(&self, addr: usize, mapper: &MapperType) -> usize {
+        physical_nt_addr(addr as u16, mapper.get_mirroring(), mapper.single_screen_page())
     }
 
-    fn write_to_2nd_nametable(&mut self, addr: usize, data: u8) {
-        let offset = addr % 0x400;
-        self.nametable_2[offset] = data;
+    fn write_nametable(&mut self, offset: usize, data: u8) {
+        if offset < 0x400 {
+            self.nametable_1[offset] = data;
+        } else {
+            self.nametable_2[offset - 0x400] = data;
+        }
     }
 
     fn read_data(&mut self, mapper: &MapperType) -> u8 {
         let addr_latch = self.v % 0x4000;
 
         let v = match addr_latch {
-            0x3F00..=0x4000 => {
-                self.vram_read_buffer = self.read_vram_at(addr_latch as usize, mapper);
-                self.vram_read_buffer
+            0x3F00..=0x3FFF => {
+                // Palette is returned immediately. The buffer is filled with
+                // the nametable byte "under" the palette.
+                self.vram_read_buffer = self.read_vram_at(addr_latch as usize - 0x1000, mapper);
+                self.read_vram_at(addr_latch as usize, mapper)
             }
             _ => {
                 let old_buffer = self.vram_read_buffer;
@@ -405,81 +576,38 @@ impl PpuMemory {
             }
         };
 
-        if self.ppuctrl & 4 == 4 {
-            self.v = addr_latch + 32;
-        } else {
-            self.v = addr_latch + 1;
-        }
-
+        self.increment_vram_addr();
         v
     }
 
     pub fn read_vram_at(&self, addr: usize, mapper: &MapperType) -> u8 {
         match addr {
             0x0..=0x1FFF => mapper.read_chr(addr),
-            0x2000..=0x23FF => self.read_from_1st_nametable(addr),
-            0x2400..=0x27FF => match mapper.get_mirroring() {
-                Mirroring::HORIZONTAL => self.read_from_1st_nametable(addr),
-                Mirroring::VERTICAL => self.read_from_2nd_nametable(addr),
-                Mirroring::ONE_SCREEN => self.read_from_1st_nametable(addr),
-            },
-            0x2800..=0x2BFF => match mapper.get_mirroring() {
-                Mirroring::HORIZONTAL => self.read_from_2nd_nametable(addr),
-                Mirroring::VERTICAL => self.read_from_1st_nametable(addr),
-                Mirroring::ONE_SCREEN => self.read_from_1st_nametable(addr),
-            },
-            0x2C00..=0x2FFF => {
-                if mapper.get_mirroring() == Mirroring::ONE_SCREEN {
-                    self.read_from_1st_nametable(addr)
-                } else {
-                    self.read_from_2nd_nametable(addr)
-                }
-            }
-            // Mirrors of 0x2000 - 0x2EFFF
-            //            0x3000..=0x33FF => self.read_from_1st_nametable(addr),
-            //            0x3400..=0x37FF => {
-            //                match mapper.get_mirroring() {
-            //                    Mirroring::HORIZONTAL => self.read_from_1st_nametable(addr),
-            //                    Mirroring::VERTICAL => self.read_from_2nd_nametable(addr),
-            //                    Mirroring::ONE_SCREEN => self.read_from_1st_nametable(addr),
-            //                }
-            //            },
-            //            0x3800..=0x3BFF => {
-            //                match mapper.get_mirroring() {
-            //                    Mirroring::HORIZONTAL => self.read_from_2nd_nametable(addr),
-            //                    Mirroring::VERTICAL => self.read_from_1st_nametable(addr),
-            //                    Mirroring::ONE_SCREEN => self.read_from_1st_nametable(addr),
-            //                }
-            //            },
-            //            0x3C00..=0x3EFF => {
-            //                if mapper.get_mirroring() == Mirroring::ONE_SCREEN {
-            //                    self.read_from_1st_nametable(addr)
-            //                } else {
-            //                    self.read_from_2nd_nametable(addr)
-            //                }
-            //            },
-            0x3000..=0x3EFF => {
-                // Mirrors of 0x2000, 0x2EFFF
-                let newaddr = 0x2000 | (addr & 0xFFF);
-                self.read_vram_at(newaddr, mapper)
-            }
+            _ => self.peek_vram_at(addr, mapper),
+        }
+    }
+
+    /// Same as `read_vram_at`, without updating the mapper latches.
+    pub fn peek_vram_at(&self, addr: usize, mapper: &MapperType) -> u8 {
+        match addr {
+            0x0..=0x1FFF => mapper.peek_chr(addr),
+            // Nametables and their mirrors
+            0x2000..=0x3EFF => self.read_nametable(self.nametable_offset(addr, mapper)),
             // palettes
             0x3F00..=0x3FFF => {
                 let offset = (addr & 0xFF) % 0x20;
                 self.palettes[offset]
             }
-            _ => panic!("read_vram_at Out of bounds: {:X}", addr),
+            _ => panic!("peek_vram_at Out of bounds: {:X}", addr),
         }
     }
 
-    fn read_from_1st_nametable(&self, addr: usize) -> u8 {
-        let offset = addr % 0x400;
-        self.nametable_1[offset]
-    }
-
-    fn read_from_2nd_nametable(&self, addr: usize) -> u8 {
-        let offset = addr % 0x400;
-        self.nametable_2[offset]
+    fn read_nametable(&self, offset: usize) -> u8 {
+        if offset < 0x400 {
+            self.nametable_1[offset]
+        } else {
+            self.nametable_2[offset - 0x400]
+        }
     }
 
     fn raise_nmi(&mut self) {
@@ -491,26 +619,234 @@ impl PpuMemory {
     // in NES vram, but with mirroring the logical tables are 4.
     // ------------------------------------------------------
     pub fn get_logical_table(&self, table_nb: u8, mapper: &MapperType) -> &[u8] {
-        match table_nb {
-            0 => &self.nametable_1,
-            1 => match mapper.get_mirroring() {
-                Mirroring::HORIZONTAL => &self.nametable_1,
-                Mirroring::VERTICAL => &self.nametable_2,
-                Mirroring::ONE_SCREEN => &self.nametable_1,
-            },
-            2 => match mapper.get_mirroring() {
-                Mirroring::VERTICAL => &self.nametable_1,
-                Mirroring::HORIZONTAL => &self.nametable_2,
-                Mirroring::ONE_SCREEN => &self.nametable_1,
-            },
-            3 => {
-                if mapper.get_mirroring() == Mirroring::ONE_SCREEN {
-                    &self.nametable_1
-                } else {
-                    &self.nametable_2
-                }
-            }
-            _ => panic!("Only 4 nametables"),
+        if table_nb > 3 {
+            panic!("Only 4 nametables");
         }
+        let addr = 0x2000 + 0x400 * table_nb as usize;
+        if self.nametable_offset(addr, mapper) < 0x400 {
+            &self.nametable_1
+        } else {
+            &self.nametable_2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::nrom::Nrom;
+
+    fn set_addr(ppu_mem: &mut PpuMemory, addr: u16, mapper: &mut MapperType) {
+        ppu_mem.write(PPUADDR, (addr >> 8) as u8, mapper);
+        ppu_mem.write(PPUADDR, (addr & 0xFF) as u8, mapper);
+    }
+
+    #[test]
+    fn read_status_clears_vblank() {
+        let mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        ppu_mem.start_vblank();
+
+        assert_eq!(0x80, ppu_mem.read(PPUSTATUS, &mapper) & 0x80);
+        assert_eq!(0, ppu_mem.read(PPUSTATUS, &mapper) & 0x80);
+        assert_eq!(0, ppu_mem.peek(PPUSTATUS) & 0x80);
+    }
+
+    #[test]
+    fn read_status_resets_write_latch() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        // Only the first write. Next write would be the low byte.
+        ppu_mem.write(PPUADDR, 0x3F, &mut mapper);
+        ppu_mem.read(PPUSTATUS, &mapper);
+
+        set_addr(&mut ppu_mem, 0x2345, &mut mapper);
+        assert_eq!(0x2345, ppu_mem.v());
+    }
+
+    #[test]
+    fn ppuaddr_writes_set_v() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        ppu_mem.write(PPUADDR, 0x23, &mut mapper);
+        // v only changes on the second write.
+        assert_eq!(0, ppu_mem.v());
+        assert_eq!(0x2300, ppu_mem.t);
+        ppu_mem.write(PPUADDR, 0xC0, &mut mapper);
+        assert_eq!(0x23C0, ppu_mem.v());
+
+        // Bit 14 is cleared, only 6 bits of the high byte are kept.
+        set_addr(&mut ppu_mem, 0xFF12, &mut mapper);
+        assert_eq!(0x3F12, ppu_mem.v());
+    }
+
+    #[test]
+    fn ppuscroll_writes_set_t_and_x() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        // X = 0b01111_101, Y = 0b01011_110
+        ppu_mem.write(PPUSCROLL, 0x7D, &mut mapper);
+        assert_eq!(0b01111, ppu_mem.t);
+        assert_eq!(0b101, ppu_mem.x);
+        ppu_mem.write(PPUSCROLL, 0x5E, &mut mapper);
+        assert_eq!(0b110_00_01011_01111, ppu_mem.t);
+        assert_eq!(0, ppu_mem.v());
+    }
+
+    #[test]
+    fn ppuscroll_and_ppuaddr_share_latch() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        // First write to $2005, then the $2006 write is the second one.
+        ppu_mem.write(PPUSCROLL, 0x08, &mut mapper);
+        ppu_mem.write(PPUADDR, 0x45, &mut mapper);
+        assert_eq!(0x0045, ppu_mem.v());
+    }
+
+    #[test]
+    fn ppudata_read_is_buffered() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        set_addr(&mut ppu_mem, 0x2000, &mut mapper);
+        for b in &[0x11, 0x22, 0x33] {
+            ppu_mem.write(PPUDATA, *b, &mut mapper);
+        }
+
+        set_addr(&mut ppu_mem, 0x2000, &mut mapper);
+        // first read returns the old content of the buffer.
+        assert_eq!(0x00, ppu_mem.read(PPUDATA, &mapper));
+        assert_eq!(0x11, ppu_mem.read(PPUDATA, &mapper));
+        assert_eq!(0x22, ppu_mem.read(PPUDATA, &mapper));
+        assert_eq!(0x33, ppu_mem.read(PPUDATA, &mapper));
+    }
+
+    #[test]
+    fn ppudata_palette_read_is_immediate() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        // nametable byte under the palette
+        set_addr(&mut ppu_mem, 0x2F01, &mut mapper);
+        ppu_mem.write(PPUDATA, 0x44, &mut mapper);
+        set_addr(&mut ppu_mem, 0x3F01, &mut mapper);
+        ppu_mem.write(PPUDATA, 0x2A, &mut mapper);
+
+        set_addr(&mut ppu_mem, 0x3F01, &mut mapper);
+        assert_eq!(0x2A, ppu_mem.read(PPUDATA, &mapper));
+        // buffer contains the nametable byte
+        assert_eq!(0x44, ppu_mem.vram_read_buffer);
+    }
+
+    #[test]
+    fn ppudata_increment() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        set_addr(&mut ppu_mem, 0x2000, &mut mapper);
+        ppu_mem.read(PPUDATA, &mapper);
+        assert_eq!(0x2001, ppu_mem.v());
+
+        ppu_mem.write(PPUCTRL, 0x04, &mut mapper);
+        ppu_mem.read(PPUDATA, &mapper);
+        assert_eq!(0x2021, ppu_mem.v());
+        ppu_mem.write(PPUDATA, 0x00, &mut mapper);
+        assert_eq!(0x2041, ppu_mem.v());
+    }
+
+    #[test]
+    fn ppudata_increment_during_rendering() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        set_addr(&mut ppu_mem, 0x2000, &mut mapper);
+        ppu_mem.write(PPUMASK, 0x18, &mut mapper);
+        ppu_mem.is_rendering = true;
+
+        ppu_mem.read(PPUDATA, &mapper);
+        // coarse X + 1 and fine Y + 1
+        assert_eq!(0x3001, ppu_mem.v());
+    }
+
+    fn run_nmi_delay(ppu_mem: &mut PpuMemory) {
+        for _ in 0..NMI_DELAY {
+            ppu_mem.nmi_delay_tick();
+        }
+    }
+
+    #[test]
+    fn enable_nmi_during_vblank() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        ppu_mem.start_vblank();
+        ppu_mem.end_vblank_race();
+        assert!(!ppu_mem.get_nmi_occured());
+
+        ppu_mem.write(PPUCTRL, 0x80, &mut mapper);
+        assert!(!ppu_mem.get_nmi_occured());
+        run_nmi_delay(&mut ppu_mem);
+        assert!(ppu_mem.get_nmi_occured());
+        ppu_mem.consume_nmi();
+
+        // Still enabled, no new NMI.
+        ppu_mem.write(PPUCTRL, 0x84, &mut mapper);
+        run_nmi_delay(&mut ppu_mem);
+        assert!(!ppu_mem.get_nmi_occured());
+
+        // Off then on again gives another NMI.
+        ppu_mem.write(PPUCTRL, 0x00, &mut mapper);
+        assert!(!ppu_mem.get_nmi_occured());
+        ppu_mem.write(PPUCTRL, 0x80, &mut mapper);
+        run_nmi_delay(&mut ppu_mem);
+        assert!(ppu_mem.get_nmi_occured());
+    }
+
+    #[test]
+    fn disable_nmi_before_delay_cancels_it() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        let mut ppu_mem = PpuMemory::new();
+        ppu_mem.start_vblank();
+        ppu_mem.end_vblank_race();
+
+        ppu_mem.write(PPUCTRL, 0x80, &mut mapper);
+        ppu_mem.nmi_delay_tick();
+        ppu_mem.write(PPUCTRL, 0x00, &mut mapper);
+        run_nmi_delay(&mut ppu_mem);
+        assert!(!ppu_mem.get_nmi_occured());
+    }
+
+    #[test]
+    fn nametable_vertical_mirroring() {
+        assert_eq!(0x000, physical_nt_addr(0x2000, Mirroring::VERTICAL, 0));
+        assert_eq!(0x400, physical_nt_addr(0x2400, Mirroring::VERTICAL, 0));
+        assert_eq!(0x000, physical_nt_addr(0x2800, Mirroring::VERTICAL, 0));
+        assert_eq!(0x7FF, physical_nt_addr(0x2FFF, Mirroring::VERTICAL, 0));
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(0x410, physical_nt_addr(0x3410, Mirroring::VERTICAL, 0));
+    }
+
+    #[test]
+    fn nametable_horizontal_mirroring() {
+        assert_eq!(0x005, physical_nt_addr(0x2405, Mirroring::HORIZONTAL, 0));
+        assert_eq!(0x400, physical_nt_addr(0x2800, Mirroring::HORIZONTAL, 0));
+        assert_eq!(0x7FF, physical_nt_addr(0x2FFF, Mirroring::HORIZONTAL, 0));
+    }
+
+    #[test]
+    fn nametable_one_screen_mirroring() {
+        for &logical in [0x2000, 0x2400, 0x2800, 0x2C00, 0x3800].iter() {
+            assert_eq!(0x000, physical_nt_addr(logical, Mirroring::ONE_SCREEN, 0));
+            assert_eq!(0x400, physical_nt_addr(logical, Mirroring::ONE_SCREEN, 1));
+        }
+    }
+
+    #[test]
+    fn vram_access_uses_mirroring() {
+        let mut mapper = MapperType::Nrom(Nrom::new());
+        mapper.set_mirroring(Mirroring::VERTICAL);
+        let mut ppu_mem = PpuMemory::new();
+        set_addr(&mut ppu_mem, 0x2C01, &mut mapper);
+        ppu_mem.write(PPUDATA, 0x42, &mut mapper);
+
+        assert_eq!(0x42, ppu_mem.read_vram_at(0x2401, &mapper));
+        assert_eq!(0x42, ppu_mem.nametable_2[1]);
+        assert_eq!(0x42, ppu_mem.get_logical_table(1, &mapper)[1]);
+        assert_eq!(0, ppu_mem.read_vram_at(0x2801, &mapper));
     }
 }
@@ -1,11 +1,25 @@
 pub mod memory;
+pub mod overlay;
 pub mod palette;
-use self::memory::RegisterType;
+use self::memory::{coarse_x_increment, y_increment, RegisterType};
 use super::cpu::memory::Memory;
+use crate::graphic::Color;
+use crate::rom::Region;
+use overlay::DebugOverlay;
 use palette::BLACK_INDEX;
 
 use serde_derive::{Deserialize, Serialize};
 
+// Number of dots after the vblank flag is set during which a read to PPUSTATUS
+// will suppress the NMI. (the set dot and the two following)
+const VBLANK_RACE_DOTS: u8 = 3;
+
+// Sprites per line on real hardware. The sprite overflow flag is always
+// based on this value.
+const HARDWARE_SPRITE_LIMIT: usize = 8;
+// Without the sprite limit, all the sprites of OAM can be on the same line.
+const MAX_SPRITES: usize = 64;
+
 fn reverse_bit(mut in_byte: u8) -> u8 {
     let mut out_byte: u8 = 0;
     let mut rest = 8;
@@ -57,7 +71,8 @@ impl Default for SpriteData {
 pub struct Ppu {
     nmi_timer: u8,
     debug: bool,
-    // 262 line per frame.
+    region: Region,
+    // 262 line per frame (312 for PAL).
     line: usize,
     // 341 cycle per line.
     cycle: usize,
@@ -79,27 +94,49 @@ pub struct Ppu {
     y_bg_attr_shift: u16,
 
     odd_frame: bool,
+    // Incremented at the start of each vertical blank.
+    #[serde(default)]
+    frame_count: u64,
     // For sprites
-    secondary_oam: [u8; 32],
+    secondary_oam: Vec<u8>,
     nb_sprites: usize,
 
-    // 8 sprites per line!
-    sprite_data: [SpriteData; 8],
+    // 8 sprites per line! (or 64 without sprite limit)
+    sprite_data: Vec<SpriteData>,
+    sprite_limit: bool,
 
     #[serde(skip)]
     #[serde(default = "empty_screen")]
     pub pixels: [u8; 0xF000],
+
+    // RGB value for each palette index.
+    #[serde(skip)]
+    #[serde(default = "palette::build_default_colors")]
+    colors: [Color; 64],
+
+    // Debug layer built at the end of each frame. Empty when Off.
+    #[serde(skip)]
+    debug_overlay: DebugOverlay,
+    #[serde(skip)]
+    overlay: Vec<u8>,
+
+    // Copy of the palette indices of the last complete frame, made at the
+    // start of vblank. Part of the state so a paused game can be displayed
+    // after loading.
+    last_frame: Vec<u8>,
 }
 
 fn empty_screen() -> [u8; 0xF000] {
     [BLACK_INDEX; 0xF000]
 }
 
+
 impl Ppu {
     pub fn new() -> Ppu {
         Ppu {
             nmi_timer: 0,
             debug: false,
+            region: Region::Ntsc,
             line: 0,
             cycle: 0,
             display_flag: false,
@@ -112,14 +149,30 @@ impl Ppu {
             y_bg_attr_shift: 0,
             low_bg_shift_reg: 0,
             odd_frame: false,
-            secondary_oam: [0; 32],
+            frame_count: 0,
+            secondary_oam: vec![0; 4 * HARDWARE_SPRITE_LIMIT],
             nb_sprites: 0,
-            sprite_data: [SpriteData::default(); 8],
+            sprite_data: vec![SpriteData::default(); HARDWARE_SPRITE_LIMIT],
+            sprite_limit: true,
 
             pixels: empty_screen(),
+            colors: palette::build_default_colors(),
+            debug_overlay: DebugOverlay::Off,
+            overlay: Vec::new(),
+            last_frame: empty_screen().to_vec(),
         }
     }
 
+    /// State when the console is switched on. Unlike a soft reset, the
+    /// PPU ignores writes to most registers for a while.
+    pub fn power_on(&mut self, memory: &mut Memory) {
+        self.line = 0;
+        self.cycle = 0;
+        self.odd_frame = false;
+        self.nmi_timer = 0;
+        memory.ppu_mem.start_warm_up();
+    }
+
     // Do not display too much :D
     pub fn should_display(&mut self) -> bool {
         if self.display_flag {
@@ -130,13 +183,147 @@ impl Ppu {
         }
     }
 
+    /// NTSC and PAL have a different number of scanlines, and PAL never
+    /// skips a dot on odd frames.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.line %= region.scanlines();
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Current scanline (0-261, or 0-311 for PAL). The last one is the
+    /// pre-render line.
+    pub fn scanline(&self) -> usize {
+        self.line
+    }
+
+    /// Current dot in the scanline (0-340).
+    pub fn dot(&self) -> usize {
+        self.cycle
+    }
+
+    /// Number of frames rendered so far (incremented at vblank).
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// True if the current frame is odd.
+    pub fn frame_parity(&self) -> bool {
+        self.odd_frame
+    }
+
+    /// Change the RGB values of the palette. Will be used for the next frame_rgb.
+    pub fn set_colors(&mut self, colors: [Color; 64]) {
+        self.colors = colors;
+    }
+
+    pub fn colors(&self) -> &[Color; 64] {
+        &self.colors
+    }
+
+    /// When disabled, more than 8 sprites can be displayed on the same line.
+    /// This removes flickering in some games but is not accurate. The sprite
+    /// overflow flag is not affected.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        let nb_sprites = if enabled {
+            HARDWARE_SPRITE_LIMIT
+        } else {
+            MAX_SPRITES
+        };
+        self.sprite_limit = enabled;
+        self.secondary_oam.resize(4 * nb_sprites, 0);
+        self.sprite_data.resize(nb_sprites, SpriteData::default());
+        self.nb_sprites = self.nb_sprites.min(nb_sprites);
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_debug_overlay(&mut self, kind: DebugOverlay) {
+        self.debug_overlay = kind;
+        if kind == DebugOverlay::Off {
+            self.overlay = Vec::new();
+        } else {
+            self.overlay = vec![0; overlay::WIDTH * overlay::HEIGHT];
+        }
+    }
+
+    /// Overlay of the last frame (see `overlay` module). None if disabled.
+    pub fn debug_overlay(&self) -> Option<&[u8]> {
+        if self.debug_overlay == DebugOverlay::Off {
+            None
+        } else {
+            Some(&self.overlay)
+        }
+    }
+
+    fn build_overlay(&mut self, memory: &Memory) {
+        match self.debug_overlay {
+            DebugOverlay::Off => (),
+            DebugOverlay::TileGrid => {
+                // Nametable selected by PPUCTRL. Scrolling is ignored.
+                let table_nb = memory.ppu_mem.peek(RegisterType::PPUCTRL) & 0b11;
+                let nametable = memory.ppu_mem.get_logical_table(table_nb, &memory.mapper);
+                overlay::tile_grid(nametable, &mut self.overlay);
+            }
+        }
+    }
+
+    /// Last complete frame in RGB. Unlike `pixels`, it is not modified while
+    /// the next frame is rendered. The colors are resolved at each call.
+    pub fn last_frame(&self) -> Vec<(u8, u8, u8)> {
+        self.last_frame
+            .iter()
+            .map(|pixel| {
+                let color = self.colors[(*pixel & 0x3F) as usize];
+                (color.r, color.g, color.b)
+            })
+            .collect()
+    }
+
+    fn store_last_frame(&mut self) {
+        self.last_frame.copy_from_slice(&self.pixels);
+    }
+
+    /// Write the RGB value of each pixel of the current frame to `out`. `out`
+    /// should be 256*240*3 bytes.
+    pub fn frame_rgb(&self, out: &mut [u8]) {
+        for (pixel, rgb) in self.pixels.iter().zip(out.chunks_exact_mut(3)) {
+            let color = self.colors[(*pixel & 0x3F) as usize];
+            rgb[0] = color.r;
+            rgb[1] = color.g;
+            rgb[2] = color.b;
+        }
+    }
+
+    /// Same as `frame_rgb` in a single pass over the frame, with each color
+    /// packed in a u32 (0x00RRGGBB). `out` should be 256*240 long. Meant for
+    /// headless use, after the frame is complete.
+    pub fn resolve_frame(&self, out: &mut [u32]) {
+        let mut packed = [0u32; 64];
+        for (p, color) in packed.iter_mut().zip(self.colors.iter()) {
+            *p = (u32::from(color.r) << 16) | (u32::from(color.g) << 8) | u32::from(color.b);
+        }
+        for (pixel, rgb) in self.pixels.iter().zip(out.iter_mut()) {
+            *rgb = packed[(*pixel & 0x3F) as usize];
+        }
+    }
+
     fn tick(&mut self, is_rendering: bool) {
         self.cycle += 1;
 
         if self.cycle == 341 {
-            self.line = (self.line + 1) % 262;
-            self.odd_frame = !self.odd_frame;
-            if self.odd_frame && self.line == 0 && is_rendering {
+            self.line = (self.line + 1) % self.region.scanlines();
+            if self.line == 0 {
+                self.odd_frame = !self.odd_frame;
+            }
+            // NTSC only: the first dot is skipped on odd frames.
+            let skip_dot = self.region == Region::Ntsc && self.odd_frame && is_rendering;
+            if skip_dot && self.line == 0 {
                 self.cycle = 1;
             } else {
                 self.cycle = 0;
@@ -147,15 +334,24 @@ impl Ppu {
     fn render_pixel(&mut self, memory: &mut Memory, render_bg: bool, render_sprite: bool) {
         let ppu_mask = memory.ppu_mem.peek(RegisterType::PPUMASK);
         let idx = 256 * self.line + (self.cycle - 1);
-        let bg_pixel_v = self.fetch_bg_pixel(&memory);
+        // The background is gated at the output of the shift registers. They
+        // keep shifting while it is hidden, so the pixels stay aligned with
+        // fine X when it is shown again. A hidden background is transparent:
+        // it shows the backdrop color, loses the priority against sprites
+        // and cannot trigger the sprite 0 hit.
+        let show_bg = render_bg && !(((ppu_mask >> 1) & 1 == 0) && self.cycle <= 8);
+        let bg_pixel_v = if show_bg {
+            self.fetch_bg_pixel(memory)
+        } else {
+            0
+        };
         let bg_pixel = {
-            if !render_bg || (((ppu_mask >> 1) & 1 == 0) && self.cycle <= 8) {
-                //(0, 0, 0)
-                BLACK_INDEX
+            let attribute = if show_bg {
+                self.fetch_bg_attr(memory)
             } else {
-                let attribute = self.fetch_bg_attr(&memory);
-                palette::get_color_index_bg(attribute, &memory.ppu_mem.palettes, bg_pixel_v)
-            }
+                0
+            };
+            palette::get_color_index_bg(attribute, &memory.ppu_mem.palettes, bg_pixel_v)
         };
 
         let sprite_pixel_data = self.fetch_sprite_pixel(memory, bg_pixel_v != 0);
@@ -258,9 +454,18 @@ impl Ppu {
         let rendering_enabled = render_bg || render_sprite;
 
         self.tick(rendering_enabled);
+        memory.ppu_mem.warm_up_tick();
+        memory.ppu_mem.nmi_delay_tick();
+
+        if self.nmi_timer > 0 {
+            self.nmi_timer -= 1;
+            if self.nmi_timer == 0 {
+                memory.ppu_mem.end_vblank_race();
+            }
+        }
 
         let visible_line = self.line < 240;
-        let pre_render_line = self.line == 261;
+        let pre_render_line = self.line == self.region.scanlines() - 1;
 
         let fetch_cycles =
             (self.cycle > 0 && self.cycle <= 256) || (self.cycle >= 321 && self.cycle < 337);
@@ -271,6 +476,8 @@ impl Ppu {
         } else if self.line == 0 {
             memory.ppu_mem.is_rendering = true;
         }
+        memory.ppu_mem.secondary_oam_clear =
+            rendering_enabled && visible_line && self.cycle >= 1 && self.cycle <= 64;
 
         // first, display the pixel at (x,y)
         if visible_line && rendering_enabled && pixel_cycles {
@@ -344,30 +551,45 @@ impl Ppu {
                 } else if self.cycle == 65 {
                     // populate secondary OAM
                     // Find the sprites that are in range for the next Y.
-                    let mut addr = memory.ppu_mem.oam_addr as usize;
+                    // The scan starts at OAMADDR and wraps around the OAM,
+                    // so 64 sprites are always checked.
+                    let start = memory.ppu_mem.oam_addr as usize;
                     let y_lower_bound = if is_16x8_sprites(ppu_ctrl) { 16 } else { 8 };
 
                     let mut secondary_oam_addr = 0;
-                    while addr < 0x100 {
-                        let sprite_y = memory.ppu_mem.oam[addr] as usize;
+                    let mut nb_in_range = 0;
+                    for n in 0..64 {
+                        let addr = start + 4 * n;
+                        let oam = &memory.ppu_mem.oam;
+                        let entry = [
+                            oam[addr & 0xFF],
+                            oam[(addr + 1) & 0xFF],
+                            oam[(addr + 2) & 0xFF],
+                            oam[(addr + 3) & 0xFF],
+                        ];
+                        let sprite_y = entry[0] as usize;
                         let next_line = (self.line + 1) % 240;
-                        if next_line >= sprite_y && next_line < sprite_y + y_lower_bound {
-                            self.secondary_oam[secondary_oam_addr] = memory.ppu_mem.oam[addr];
-                            self.secondary_oam[secondary_oam_addr + 1] =
-                                memory.ppu_mem.oam[addr + 1];
-                            self.secondary_oam[secondary_oam_addr + 2] =
-                                memory.ppu_mem.oam[addr + 2];
-                            self.secondary_oam[secondary_oam_addr + 3] =
-                                memory.ppu_mem.oam[addr + 3];
+                        let in_range =
+                            next_line >= sprite_y && next_line < sprite_y + y_lower_bound;
+                        if in_range {
+                            nb_in_range += 1;
+                            if nb_in_range > HARDWARE_SPRITE_LIMIT {
+                                self.sprite_overflow_set(memory);
+                            }
+                        }
+
+                        if in_range && secondary_oam_addr < self.secondary_oam.len() {
+                            self.secondary_oam[secondary_oam_addr..secondary_oam_addr + 4]
+                                .copy_from_slice(&entry);
                             secondary_oam_addr += 4;
                             self.nb_sprites += 1;
                         }
 
-                        // 4 bytes per sprites.
-                        addr += 4;
-
-                        // if we already have 8 sprites, stop here.
-                        if secondary_oam_addr == 32 {
+                        // if we already have 8 sprites and know about the
+                        // overflow, stop here.
+                        if secondary_oam_addr == self.secondary_oam.len()
+                            && nb_in_range > HARDWARE_SPRITE_LIMIT
+                        {
                             break;
                         }
                     }
@@ -387,16 +609,18 @@ impl Ppu {
 
         // Vertical blank stuff.
         if self.line == 241 && self.cycle == 1 {
-            memory
-                .ppu_mem
-                .update(RegisterType::PPUSTATUS, ppu_status | 0x80);
+            memory.ppu_mem.start_vblank();
+            self.nmi_timer = VBLANK_RACE_DOTS;
             self.display_flag = true;
+            self.frame_count += 1;
+            self.store_last_frame();
+            self.build_overlay(memory);
         }
 
         if pre_render_line && self.cycle == 1 {
             memory
                 .ppu_mem
-                .update(RegisterType::PPUSTATUS, ppu_status & !0x80);
+                .update(RegisterType::PPUSTATUS, ppu_status & !0xA0);
             self.sprite_0_clear(memory);
         }
     }
@@ -434,7 +658,8 @@ impl Ppu {
         let eightb_nametable = 0x1000 * ((ppu_ctrl >> 3) & 1) as usize;
         let is_16b = is_16x8_sprites(ppu_ctrl);
         for (i, mut sprite) in self.sprite_data.iter_mut().enumerate() {
-            if i <= self.nb_sprites {
+            // Only the first nb_sprites slots of the secondary OAM are valid.
+            if i < self.nb_sprites {
                 let secondary_oam_addr = 4 * i;
                 let y = (self.line + 1) % 240;
                 let x = self.secondary_oam[secondary_oam_addr + 3];
@@ -451,21 +676,11 @@ impl Ppu {
 
                 let attr_byte = self.secondary_oam[secondary_oam_addr + 2];
 
-                let mut tile_y = y - self.secondary_oam[secondary_oam_addr] as usize;
-                let mut bottom_tile = false;
-                if tile_y > 7 {
-                    tile_y = tile_y % 8;
-                    bottom_tile = true;
-                }
-
-                if (attr_byte >> 7) & 1 == 1 {
-                    // reverse y...
-                    //
-                    tile_y = 7 - tile_y;
-                    bottom_tile = !bottom_tile;
-                }
+                let row = y.wrapping_sub(self.secondary_oam[secondary_oam_addr] as usize);
+                let flip_vertical = (attr_byte >> 7) & 1 == 1;
+                let (bottom_tile, tile_y) = sprite_tile_row(row, is_16b, flip_vertical);
 
-                if bottom_tile && is_16b {
+                if bottom_tile {
                     tile_addr += 1;
                 }
 
@@ -557,49 +772,13 @@ impl Ppu {
     }
 
     fn coarse_x_increment(&self, memory: &mut Memory) {
-        let mut v = memory.ppu_mem.v();
-        if (v & 0x1F) == 31 {
-            // at the limit of the screen. We need to switch
-            // nametable in that case.
-            v &= !0x1F; // X = 0
-
-            // Switch nametable.
-            v ^= 0x400;
-        } else {
-            v += 1;
-        }
-
-        memory.ppu_mem.set_v(v);
+        let v = memory.ppu_mem.v();
+        memory.ppu_mem.set_v(coarse_x_increment(v));
     }
 
     fn y_increment(&self, memory: &mut Memory) {
-        // yyy NN YYYYY XXXXX
-        let mut v = memory.ppu_mem.v();
-        if (v & 0x7000) != 0x7000 {
-            // fine y is < 7.
-            v += 0x1000;
-        } else {
-            // reset fine.
-            v &= !0x7000;
-
-            let mut y = (v & 0x3e0) >> 5;
-
-            if y == 29 {
-                y = 0;
-                // switch vertical nametable
-                v ^= 0x800;
-            } else if y == 31 {
-                // y can be set out of bound to read attributes. in that case, wrap to 0
-                // without changing the nametable.
-                y = 0;
-            } else {
-                y += 1;
-            }
-
-            v = (v & !0x3e0) | (y << 5);
-        }
-
-        memory.ppu_mem.set_v(v);
+        let v = memory.ppu_mem.v();
+        memory.ppu_mem.set_v(y_increment(v));
     }
 
     fn copy_vertical_t(&self, memory: &mut Memory) {
@@ -625,6 +804,13 @@ impl Ppu {
             .update(RegisterType::PPUSTATUS, ppu_status | 0x40);
     }
 
+    fn sprite_overflow_set(&self, memory: &mut Memory) {
+        let ppu_status = memory.ppu_mem.peek(RegisterType::PPUSTATUS);
+        memory
+            .ppu_mem
+            .update(RegisterType::PPUSTATUS, ppu_status | 0x20);
+    }
+
     fn sprite_0_clear(&self, memory: &mut Memory) {
         let ppu_status = memory.ppu_mem.peek(RegisterType::PPUSTATUS);
         memory
@@ -637,6 +823,17 @@ fn is_16x8_sprites(ppu_ctrl: u8) -> bool {
     (ppu_ctrl >> 5) & 1 == 1
 }
 
+/// For a row of a sprite (0-7, or 0-15 for 8x16 sprites), return whether the
+/// bottom tile should be used (8x16 only) and the row within that tile. When
+/// flipped vertically, the full sprite is flipped so top and bottom tiles are
+/// swapped for 8x16 sprites.
+fn sprite_tile_row(row: usize, is_16b: bool, flip_vertical: bool) -> (bool, usize) {
+    let height = if is_16b { 16 } else { 8 };
+    let row = row % height;
+    let row = if flip_vertical { height - 1 - row } else { row };
+    (row > 7, row % 8)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -646,4 +843,417 @@ mod tests {
         assert_eq!(0b00010000, reverse_bit(0b00001000));
         assert_eq!(0b11010000, reverse_bit(0b00001011));
     }
+
+    #[test]
+    fn ppu_position_wraps_after_341_dots() {
+        let mut memory = Memory::default();
+        let mut ppu = Ppu::new();
+
+        ppu.next(340, &mut memory, false).unwrap();
+        assert_eq!((0, 340), (ppu.scanline(), ppu.dot()));
+        ppu.next(1, &mut memory, false).unwrap();
+        assert_eq!((1, 0), (ppu.scanline(), ppu.dot()));
+        ppu.next(5, &mut memory, false).unwrap();
+        assert_eq!((1, 5), (ppu.scanline(), ppu.dot()));
+    }
+
+    #[test]
+    fn frame_parity_toggles_every_frame() {
+        let mut memory = Memory::default();
+        let mut ppu = Ppu::new();
+        assert!(!ppu.frame_parity());
+
+        ppu.next(341 * 261, &mut memory, false).unwrap();
+        assert!(!ppu.frame_parity());
+        ppu.next(341, &mut memory, false).unwrap();
+        assert_eq!((0, 0), (ppu.scanline(), ppu.dot()));
+        assert!(ppu.frame_parity());
+        ppu.next(341 * 262, &mut memory, false).unwrap();
+        assert!(!ppu.frame_parity());
+    }
+
+    #[test]
+    fn sprite_tile_row_8x8() {
+        assert_eq!((false, 2), sprite_tile_row(2, false, false));
+        assert_eq!((false, 5), sprite_tile_row(2, false, true));
+    }
+
+    #[test]
+    fn sprite_tile_row_8x16() {
+        for row in 0..16 {
+            assert_eq!((row > 7, row % 8), sprite_tile_row(row, true, false));
+            let flipped = 15 - row;
+            assert_eq!((flipped > 7, flipped % 8), sprite_tile_row(row, true, true));
+        }
+    }
+
+    // Fetch the pattern of the first sprite for each of the 16 rows of a
+    // 8x16 sprite.
+    fn tall_sprite_rows(memory: &mut Memory, attributes: u8) -> Vec<u8> {
+        let mut ppu = Ppu::new();
+        let sprite_y = 10;
+        (0..16)
+            .map(|row| {
+                ppu.line = sprite_y + row - 1;
+                ppu.secondary_oam[0] = sprite_y as u8;
+                ppu.secondary_oam[1] = 0x02;
+                ppu.secondary_oam[2] = attributes;
+                ppu.secondary_oam[3] = 0;
+                ppu.nb_sprites = 1;
+                ppu.evaluate_sprites(memory, 0x20);
+                ppu.sprite_data[0].low_sprite_bmp_reg
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resolve_frame_matches_frame_rgb() {
+        let mut ppu = Ppu::new();
+        for (i, pixel) in ppu.pixels.iter_mut().enumerate() {
+            // Include indices with the unused upper bits set.
+            *pixel = (i * 7 % 256) as u8;
+        }
+        let mut rgb = vec![0; 0xF000 * 3];
+        ppu.frame_rgb(&mut rgb);
+        let mut packed = vec![0; 0xF000];
+        ppu.resolve_frame(&mut packed);
+
+        for (p, rgb) in packed.iter().zip(rgb.chunks_exact(3)) {
+            let expected =
+                (u32::from(rgb[0]) << 16) | (u32::from(rgb[1]) << 8) | u32::from(rgb[2]);
+            assert_eq!(expected, *p);
+        }
+    }
+
+    #[test]
+    fn background_disabled_mid_line_keeps_fine_x() {
+        let mut memory = Memory::default();
+        // Every tile has opaque pixels in even columns.
+        for addr in 0..0x2000 {
+            let low_plane = addr & 0x8 == 0;
+            memory.mapper.write_chr(addr, if low_plane { 0xAA } else { 0 });
+        }
+        memory.ppu_mem.palettes[0] = 0x21;
+        memory.ppu_mem.palettes[1] = 0x16;
+        memory.ppu_mem.x = 1;
+        // Sprites stay enabled so that the PPU keeps rendering.
+        memory.set(0x2001, 0x1A);
+        let mut ppu = Ppu::new();
+        ppu.line = 9;
+        ppu.next(341 + 100, &mut memory, false).unwrap();
+        memory.set(0x2001, 0x12);
+        ppu.next(50, &mut memory, false).unwrap();
+        memory.set(0x2001, 0x1A);
+        ppu.next(100, &mut memory, false).unwrap();
+
+        // With fine X = 1, odd pixels show the even columns of the tiles.
+        let expected = |x: usize| if x % 2 == 1 { 0x16 } else { 0x21 };
+        let row = &ppu.pixels[256 * 10..256 * 11];
+        for x in 0..250 {
+            let hidden = (100..150).contains(&x);
+            let pixel = if hidden { 0x21 } else { expected(x) };
+            assert_eq!(pixel, row[x], "pixel {}", x);
+        }
+    }
+
+    #[test]
+    fn mid_frame_2006_write_splits_the_screen() {
+        let mut memory = Memory::default();
+        // Tile 1 is opaque, tile 0 is transparent.
+        for addr in 0x10..0x20 {
+            memory.mapper.write_chr(addr, 0xFF);
+        }
+        memory.ppu_mem.palettes[0] = 0x21;
+        memory.ppu_mem.palettes[3] = 0x16;
+        // Rows 15 to 29 of the first nametable use tile 1.
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0xE0);
+        for _ in 0..15 * 32 {
+            memory.set(0x2007, 1);
+        }
+        memory.set(0x2006, 0x20);
+        memory.set(0x2006, 0x00);
+        memory.set(0x2001, 0x0A);
+
+        let mut ppu = Ppu::new();
+        ppu.line = 9;
+        // Hblank of line 20, after the horizontal copy of dot 257.
+        ppu.next(341 * 11 + 300, &mut memory, false).unwrap();
+        // Row 15, fine Y 2.
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0xE0);
+        assert_eq!(0x21E0, memory.ppu_mem.v());
+        ppu.next(341 * 2, &mut memory, false).unwrap();
+
+        let line = |n: usize| &ppu.pixels[256 * n..256 * (n + 1)];
+        assert!(line(20).iter().all(|p| *p == 0x21));
+        // The next line is fetched from the new address.
+        assert!(line(21).iter().all(|p| *p == 0x16));
+        assert!(line(22).iter().all(|p| *p == 0x16));
+    }
+
+    #[test]
+    fn left_clip_shows_backdrop() {
+        let mut memory = Memory::default();
+        for addr in 0..0x2000 {
+            memory.mapper.write_chr(addr, 0xFF);
+        }
+        memory.ppu_mem.palettes[0] = 0x21;
+        memory.ppu_mem.palettes[3] = 0x16;
+        // Background without the leftmost 8 pixels.
+        memory.set(0x2001, 0x08);
+        let mut ppu = Ppu::new();
+        ppu.line = 9;
+        ppu.next(341 * 2, &mut memory, false).unwrap();
+
+        let row = &ppu.pixels[256 * 10..256 * 11];
+        assert!(row[..8].iter().all(|p| *p == 0x21), "{:?}", &row[..8]);
+        assert_eq!(0x16, row[20]);
+    }
+
+    #[test]
+    fn sprite_evaluation_starts_at_oam_addr() {
+        let mut memory = Memory::default();
+        memory.set(0x2001, 0x10);
+        // Entries 2, 8 and 20 are on line 10. Their tile is their number.
+        for &n in [2, 8, 20].iter() {
+            memory.ppu_mem.oam[4 * n] = 10;
+            memory.ppu_mem.oam[4 * n + 1] = n as u8;
+        }
+        memory.ppu_mem.oam_addr = 0x20;
+        let mut ppu = Ppu::new();
+        ppu.line = 9;
+        ppu.cycle = 64;
+        ppu.next(1, &mut memory, false).unwrap();
+
+        assert_eq!(3, ppu.nb_sprites);
+        let tiles: Vec<u8> = ppu.secondary_oam.chunks(4).take(3).map(|s| s[1]).collect();
+        assert_eq!(vec![8, 20, 2], tiles);
+    }
+
+    #[test]
+    fn unused_sprite_slots_are_cleared() {
+        let mut memory = Memory::default();
+        for addr in 0..0x2000 {
+            memory.mapper.write_chr(addr, 0xFF);
+        }
+        let mut ppu = Ppu::new();
+        ppu.line = 19;
+        // 2 sprites in range, then what is left of an older line.
+        ppu.secondary_oam[..12].copy_from_slice(&[20, 1, 0, 8, 20, 1, 0, 16, 20, 1, 0x03, 24]);
+        ppu.sprite_data[2].is_active = true;
+        ppu.nb_sprites = 2;
+        ppu.evaluate_sprites(&memory, 0);
+
+        assert_eq!(0xFF, ppu.sprite_data[1].low_sprite_bmp_reg);
+        assert_eq!(16, ppu.sprite_data[1].x_position_counters);
+        let slot = &ppu.sprite_data[2];
+        assert!(!slot.is_active);
+        assert_eq!(
+            (0, 0, 0, 0),
+            (
+                slot.low_sprite_bmp_reg,
+                slot.high_sprite_bmp_reg,
+                slot.x_position_counters,
+                slot.sprite_attributes
+            )
+        );
+    }
+
+    #[test]
+    fn flipped_8x16_sprite() {
+        let mut memory = Memory::default();
+        // Tile 2 is the top half, tile 3 the bottom half. Low plane of each row
+        // is the row number in the full sprite.
+        for row in 0..8 {
+            memory.mapper.write_chr(0x20 + row, row as u8);
+            memory.mapper.write_chr(0x30 + row, 8 + row as u8);
+        }
+
+        let rows = tall_sprite_rows(&mut memory, 0);
+        assert_eq!((0..16).collect::<Vec<u8>>(), rows);
+
+        let rows = tall_sprite_rows(&mut memory, 0x80);
+        assert_eq!((0..16).rev().collect::<Vec<u8>>(), rows);
+    }
+
+    // 12 sprites on line 50 (drawn from line 51), tile 1 is filled with
+    // color 1. Return the number of sprites visible on line 54.
+    fn visible_sprites_on_line(sprite_limit: bool) -> usize {
+        let mut memory = Memory::default();
+        for row in 0..8 {
+            memory.mapper.write_chr(0x10 + row, 0xFF);
+        }
+        memory.ppu_mem.palettes[0x11] = 0x16;
+        for i in 0..12 {
+            memory.ppu_mem.oam[4 * i] = 50;
+            memory.ppu_mem.oam[4 * i + 1] = 1;
+            memory.ppu_mem.oam[4 * i + 2] = 0;
+            memory.ppu_mem.oam[4 * i + 3] = 16 + 16 * i as u8;
+        }
+        memory.set(0x2001, 0x10);
+
+        let mut ppu = Ppu::new();
+        ppu.set_sprite_limit(sprite_limit);
+        // Second frame, after the line with the sprites.
+        ppu.next(341 * (262 + 100), &mut memory, false).unwrap();
+
+        let status = memory.ppu_mem.peek(RegisterType::PPUSTATUS);
+        assert_eq!(0x20, status & 0x20, "sprite overflow should be set");
+        (0..12)
+            .filter(|i| ppu.pixels[256 * 54 + 16 + 16 * i + 4] == 0x16)
+            .count()
+    }
+
+    #[test]
+    fn sprite_limit() {
+        assert_eq!(8, visible_sprites_on_line(true));
+        assert_eq!(12, visible_sprites_on_line(false));
+    }
+
+    #[test]
+    fn oamdata_read_during_secondary_oam_clear() {
+        let mut memory = Memory::default();
+        let mut ppu = Ppu::new();
+        memory.ppu_mem.update(RegisterType::PPUMASK, 0x18);
+        memory.ppu_mem.oam[0] = 0x12;
+
+        // Line 0, dot 10
+        ppu.next(10, &mut memory, false).unwrap();
+        assert_eq!(0xFF, memory.get(0x2004));
+
+        // Line 0, dot 100
+        ppu.next(90, &mut memory, false).unwrap();
+        assert_eq!(0x12, memory.get(0x2004));
+        assert_eq!(0x12, memory.get(0x2004));
+        assert_eq!(0, memory.ppu_mem.oam_addr);
+    }
+
+    #[test]
+    fn writes_ignored_during_warm_up() {
+        let mut memory = Memory::default();
+        let mut ppu = Ppu::new();
+        ppu.power_on(&mut memory);
+
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0x00);
+        assert_eq!(0, memory.ppu_mem.v());
+
+        let warm_up_dots = 3 * super::memory::WARM_UP_CPU_CYCLES as u64;
+        ppu.next(warm_up_dots, &mut memory, false).unwrap();
+        memory.set(0x2006, 0x21);
+        memory.set(0x2006, 0x00);
+        assert_eq!(0x2100, memory.ppu_mem.v());
+    }
+
+    #[test]
+    fn rendering_disabled_mid_line_freezes_v() {
+        let mut memory = Memory::default();
+        memory.set(0x2001, 0x08);
+        let mut ppu = Ppu::new();
+        ppu.line = 10;
+        ppu.cycle = 0;
+
+        // Around dot 100, coarse X was incremented on each tile.
+        ppu.next(100, &mut memory, false).unwrap();
+        let v = memory.ppu_mem.v();
+        assert!(v & 0x1F > 10);
+
+        // No increment or copy of t, even on dots 256 and 257.
+        memory.set(0x2001, 0x00);
+        ppu.next(200, &mut memory, false).unwrap();
+        assert_eq!(v, memory.ppu_mem.v());
+
+        // Around dot 330, the first tile of the next line was fetched.
+        memory.set(0x2001, 0x08);
+        ppu.next(30, &mut memory, false).unwrap();
+        assert_eq!(v + 1, memory.ppu_mem.v());
+    }
+
+    #[test]
+    fn ntsc_odd_frame_is_one_dot_shorter() {
+        let mut memory = Memory::default();
+        memory.set(0x2001, 0x08);
+        let mut ppu = Ppu::new();
+
+        // Even frame then odd frame.
+        ppu.next(341 * 262, &mut memory, false).unwrap();
+        assert_eq!((0, 1), (ppu.scanline(), ppu.dot()));
+        ppu.next(341 * 262 - 1, &mut memory, false).unwrap();
+        assert_eq!((0, 0), (ppu.scanline(), ppu.dot()));
+    }
+
+    #[test]
+    fn pal_frames_have_same_length() {
+        let mut memory = Memory::default();
+        memory.set(0x2001, 0x08);
+        let mut ppu = Ppu::new();
+        ppu.set_region(Region::Pal);
+
+        for _ in 0..2 {
+            ppu.next(341 * 312, &mut memory, false).unwrap();
+            assert_eq!((0, 0), (ppu.scanline(), ppu.dot()));
+        }
+        assert_eq!(2, ppu.frame_count());
+    }
+
+    // Run the PPU until the vblank flag is set (line 241, dot 1)
+    fn ppu_at_vblank(memory: &mut Memory) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.line = 241;
+        ppu.cycle = 0;
+        ppu.exec_cycle(memory);
+        assert_eq!(0x80, memory.ppu_mem.peek(RegisterType::PPUSTATUS) & 0x80);
+        ppu
+    }
+
+    #[test]
+    fn nmi_at_vblank_start() {
+        let mut memory = Memory::default();
+        memory.set(0x2000, 0x80);
+        let mut ppu = Ppu::new();
+        ppu.line = 241;
+        ppu.cycle = 0;
+        assert!(!memory.nmi());
+
+        // Raised on the dot that sets the vblank flag.
+        ppu.exec_cycle(&mut memory);
+        assert_eq!((241, 1), (ppu.scanline(), ppu.dot()));
+        assert!(memory.nmi());
+    }
+
+    #[test]
+    fn read_status_on_vblank_dot_suppresses_nmi() {
+        let mut memory = Memory::default();
+        memory.set(0x2000, 0x80);
+        let mut ppu = ppu_at_vblank(&mut memory);
+
+        memory.get(0x2002);
+        ppu.next(20, &mut memory, false).unwrap();
+        assert!(!memory.nmi());
+    }
+
+    #[test]
+    fn read_status_in_race_window_suppresses_nmi() {
+        let mut memory = Memory::default();
+        memory.set(0x2000, 0x80);
+        let mut ppu = ppu_at_vblank(&mut memory);
+
+        ppu.next(2, &mut memory, false).unwrap();
+        memory.get(0x2002);
+        ppu.next(20, &mut memory, false).unwrap();
+        assert!(!memory.nmi());
+    }
+
+    #[test]
+    fn read_status_after_race_window_keeps_nmi() {
+        let mut memory = Memory::default();
+        memory.set(0x2000, 0x80);
+        let mut ppu = ppu_at_vblank(&mut memory);
+
+        ppu.next(VBLANK_RACE_DOTS as u64, &mut memory, false).unwrap();
+        assert!(memory.nmi());
+        memory.get(0x2002);
+        assert!(memory.nmi());
+    }
 }
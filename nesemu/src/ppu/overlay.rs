@@ -0,0 +1,73 @@
+// Debug layers drawn on top of the frame by the front-ends. The PPU builds
+// them at the end of each frame, only when enabled.
+//
+// Overlay buffer has one byte per pixel (256x240):
+// - 0 means nothing to draw (transparent),
+// - 1 to 4 means a grid line pixel. Value - 1 is the attribute (background
+//   palette) of the 16x16 quadrant the pixel belongs to.
+use serde_derive::{Deserialize, Serialize};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum DebugOverlay {
+    #[default]
+    Off,
+    /// 8x8 tile grid, colored with the attribute of each quadrant.
+    TileGrid,
+}
+
+/// Background palette (0-3) of the tile at (tile_row, tile_col).
+fn attribute(nametable: &[u8], tile_row: usize, tile_col: usize) -> u8 {
+    let attr_byte = nametable[0x3C0 + (tile_row / 4) * 8 + tile_col / 4];
+    let shift = ((tile_row % 4) / 2) * 4 + ((tile_col % 4) / 2) * 2;
+    (attr_byte >> shift) & 0b11
+}
+
+/// Fill `out` with the tile grid of `nametable` (0x400 bytes, attribute
+/// table included).
+pub fn tile_grid(nametable: &[u8], out: &mut [u8]) {
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            out[y * WIDTH + x] = if x % 8 == 0 || y % 8 == 0 {
+                1 + attribute(nametable, y / 8, x / 8)
+            } else {
+                0
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_with_attributes() {
+        let mut nametable = vec![0; 0x400];
+        // First attribute byte: top-left = 0, top-right = 1,
+        // bottom-left = 2, bottom-right = 3
+        nametable[0x3C0] = 0b11_10_01_00;
+
+        let mut out = vec![0; WIDTH * HEIGHT];
+        tile_grid(&nametable, &mut out);
+
+        // 32x30 grid
+        let nb_vertical_lines = (0..WIDTH).filter(|x| out[1 * WIDTH + x] != 0).count();
+        let nb_horizontal_lines = (0..HEIGHT).filter(|y| out[y * WIDTH + 1] != 0).count();
+        assert_eq!(32, nb_vertical_lines);
+        assert_eq!(30, nb_horizontal_lines);
+
+        // inside a tile
+        assert_eq!(0, out[3 * WIDTH + 3]);
+
+        // Quadrants of the first attribute byte
+        assert_eq!(1, out[0]);
+        assert_eq!(2, out[16]);
+        assert_eq!(3, out[16 * WIDTH]);
+        assert_eq!(4, out[16 * WIDTH + 16]);
+        // Next attribute byte is 0
+        assert_eq!(1, out[32]);
+    }
+}
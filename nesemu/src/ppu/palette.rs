@@ -18,10 +18,10 @@ pub fn get_bg_color(vram: &[u8], colors: &[Color; 64]) -> Color {
 
 pub fn get_color_index_bg(palette_number: u16, vram: &[u8], pixel_value: u16) -> u8 {
     if pixel_value == 0 {
-        unsafe { *vram.get_unchecked(0x00) }
+        unsafe { *vram.get_unchecked(0x00) & 0b111111 }
     } else {
         let idx = (4 * palette_number + pixel_value) as usize;
-        unsafe { *vram.get_unchecked(idx) }
+        unsafe { *vram.get_unchecked(idx) & 0b111111 }
     }
 }
 
@@ -67,16 +67,22 @@ pub fn load_palette<P: AsRef<Path>>(palette: P) -> Result<[Color; 64], std::io::
     let mut f = File::open(palette)?;
     let mut content: Vec<u8> = vec![];
     f.read_to_end(&mut content)?;
+    colors_from_bytes(&content)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
 
-    if content.len() < 3 * 64 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            String::from("There is less than 64 colors in the palette"),
+/// Palette file is 64 RGB colors, so exactly 192 bytes.
+pub fn colors_from_bytes(content: &[u8]) -> Result<[Color; 64], String> {
+    if content.len() != 3 * 64 {
+        return Err(format!(
+            "Palette should be {} bytes (64 colors), got {}",
+            3 * 64,
+            content.len()
         ));
     }
 
     let mut colors = [Color::rgb(0, 0, 0); 64];
-    for (i, chunk) in content.chunks(3).enumerate().take(64) {
+    for (i, chunk) in content.chunks(3).enumerate() {
         colors[i] = Color::rgb(chunk[0], chunk[1], chunk[2]);
     }
 
@@ -152,3 +158,14 @@ pub fn build_default_colors() -> [Color; 64] {
 
     colors
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_from_bytes_wrong_size() {
+        assert!(colors_from_bytes(&[0; 191]).is_err());
+        assert!(colors_from_bytes(&[0; 193]).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// Callback interface in the style of libretro, to plug the emulator in hosts
+// that drive the main loop themselves. The host installs the callbacks then
+// calls `run` once per frame.
+use crate::joypad::{InputAction, InputState, Player};
+use crate::nes::Nes;
+
+const ACTIONS: [InputAction; 8] = [
+    InputAction::A,
+    InputAction::B,
+    InputAction::SELECT,
+    InputAction::START,
+    InputAction::UP,
+    InputAction::DOWN,
+    InputAction::LEFT,
+    InputAction::RIGHT,
+];
+
+/// Receives the RGB frame (3 bytes per pixel), its width and its height.
+pub type VideoRefresh = Box<dyn FnMut(&[u8], usize, usize)>;
+/// Receives the mono samples produced during the frame.
+pub type AudioBatch = Box<dyn FnMut(&[i16])>;
+/// Returns true if the button is pressed.
+pub type InputStateCallback = Box<dyn FnMut(Player, InputAction) -> bool>;
+
+pub struct Core {
+    nes: Nes,
+    frame: Vec<u8>,
+    video_refresh: Option<VideoRefresh>,
+    audio_batch: Option<AudioBatch>,
+    input_state: Option<InputStateCallback>,
+}
+
+impl Core {
+    pub fn new(nes: Nes) -> Core {
+        let frame = vec![0; nes.width() * nes.height() * 3];
+        Core {
+            nes,
+            frame,
+            video_refresh: None,
+            audio_batch: None,
+            input_state: None,
+        }
+    }
+
+    pub fn set_video_refresh(&mut self, callback: VideoRefresh) {
+        self.video_refresh = Some(callback);
+    }
+
+    pub fn set_audio_batch(&mut self, callback: AudioBatch) {
+        self.audio_batch = Some(callback);
+    }
+
+    pub fn set_input_state(&mut self, callback: InputStateCallback) {
+        self.input_state = Some(callback);
+    }
+
+    pub fn nes(&mut self) -> &mut Nes {
+        &mut self.nes
+    }
+
+    /// Poll the inputs, run one frame then send the frame and the audio
+    /// samples to the host.
+    pub fn run(&mut self) -> Result<(), &'static str> {
+        if let Some(input_state) = self.input_state.as_mut() {
+            for player in &[Player::One, Player::Two] {
+                for action in &ACTIONS {
+                    let state = if input_state(*player, *action) {
+                        InputState::Pressed
+                    } else {
+                        InputState::Released
+                    };
+                    self.nes.set_button(*player, *action, state);
+                }
+            }
+        }
+
+        self.nes.run_frame()?;
+
+        if let Some(video_refresh) = self.video_refresh.as_mut() {
+            self.nes
+                .frame_rgb(&mut self.frame)
+                .map_err(|_| "Invalid frame buffer")?;
+            video_refresh(&self.frame, self.nes.width(), self.nes.height());
+        }
+
+        let samples = self.nes.audio_samples();
+        if let Some(audio_batch) = self.audio_batch.as_mut() {
+            audio_batch(&samples);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::INesFile;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn looping_nes() -> Nes {
+        // JMP $8000
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        prg_rom[0x3FFD] = 0x80;
+        let ines = INesFile::new(prg_rom, 1, vec![0; 0x2000], 1, 0, 0, 0, 0, 0, "test".to_owned());
+        Nes::new(ines).unwrap()
+    }
+
+    #[test]
+    fn run_calls_the_callbacks() {
+        let mut core = Core::new(looping_nes());
+        let frames = Rc::new(RefCell::new(vec![]));
+        let nb_samples = Rc::new(RefCell::new(0));
+        let nb_polls = Rc::new(RefCell::new(0));
+
+        let f = Rc::clone(&frames);
+        core.set_video_refresh(Box::new(move |frame, width, height| {
+            f.borrow_mut().push((frame.len(), width, height))
+        }));
+        let s = Rc::clone(&nb_samples);
+        core.set_audio_batch(Box::new(move |samples| *s.borrow_mut() += samples.len()));
+        let p = Rc::clone(&nb_polls);
+        core.set_input_state(Box::new(move |_, _| {
+            *p.borrow_mut() += 1;
+            false
+        }));
+
+        for _ in 0..2 {
+            core.run().unwrap();
+        }
+
+        assert_eq!(vec![(256 * 240 * 3, 256, 240); 2], *frames.borrow());
+        assert!(*nb_samples.borrow() > 0);
+        // 8 buttons for 2 players per frame.
+        assert_eq!(32, *nb_polls.borrow());
+    }
+}
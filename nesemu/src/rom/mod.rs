@@ -1,22 +1,20 @@
 // Read the ROM.
 //
+use crate::error::NesError;
 use crate::mapper::Mirroring;
+use serde_derive::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-fn load<P: AsRef<Path>>(filename: P) -> Result<Vec<u8>, String> {
-    File::open(filename)
-        .map_err(|err| err.to_string())
-        .and_then(|mut file| {
-            let mut contents = Vec::new();
-            file.read_to_end(&mut contents)
-                .map_err(|err| err.to_string())
-                .map(|_| contents)
-        })
+fn load<P: AsRef<Path>>(filename: P) -> Result<Vec<u8>, NesError> {
+    let mut file = File::open(filename)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
 }
 
-pub fn read<P: AsRef<Path>>(rom_path: P) -> Result<INesFile, String> {
+pub fn read<P: AsRef<Path>>(rom_path: P) -> Result<INesFile, NesError> {
     let rom_path = rom_path.as_ref();
     let rom_name = if let Some(x) = rom_path.file_stem() {
         x.to_os_string()
@@ -29,38 +27,47 @@ pub fn read<P: AsRef<Path>>(rom_path: P) -> Result<INesFile, String> {
     from_bytes(rom_name, bytes)
 }
 
-pub fn from_bytes(rom_name: String, bytes: Vec<u8>) -> Result<INesFile, String> {
-    // Check the header is big enough. Expecting 16 bytes.
-    if bytes.len() < 16 {
-        return Err(format!(
-            "ROM size is to short. Expected 16 bytes, got {}",
-            bytes.len()
-        ));
-    }
+/// Read only the header of the ROM at `rom_path`. Faster than `read` to list
+/// the mappers and sizes of many ROMs.
+pub fn read_header<P: AsRef<Path>>(rom_path: P) -> Result<Header, NesError> {
+    let file = File::open(rom_path)?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    file.take(HEADER_SIZE as u64).read_to_end(&mut bytes)?;
+    Header::parse(&bytes)
+}
 
-    // First 4 bytes are "NES" + MS-DOS EOF
-    let expected_bytes = [0x4E, 0x45, 0x53, 0x1A];
-    if &expected_bytes[..] != &bytes[0..4] {
-        return Err(String::from("ROM 4 first bytes are not $4E $45 $53 $1A"));
-    }
+pub fn from_bytes(rom_name: String, bytes: Vec<u8>) -> Result<INesFile, NesError> {
+    let header = Header::parse(&bytes)?;
+    let has_trainer = header.has_trainer();
+    let Header {
+        prg_rom_pages: prg_rom_size,
+        chr_rom_pages: chr_rom_size,
+        flags_6,
+        flags_7,
+        prg_ram_size,
+        flags_9,
+        flags_10,
+        flags_12,
+    } = header;
 
-    let prg_rom_size = bytes[4] as usize;
-    let chr_rom_size = bytes[5] as usize;
-    let flags_6 = bytes[6];
-    let flags_7 = bytes[7];
-    let prg_ram_size = bytes[8] as usize;
-    let flags_9 = bytes[9];
-    let flags_10 = bytes[10];
+    let expected_len =
+        16 + if has_trainer { 512 } else { 0 } + prg_rom_size * 16384 + chr_rom_size * 8192;
+    if bytes.len() < expected_len {
+        return Err(NesError::RomParse(format!(
+            "ROM is truncated. Expected {} bytes, got {}",
+            expected_len,
+            bytes.len()
+        )));
+    }
 
     // Trainer if present (check flag 6).
     let mut offset = 16;
-    let mut trainer = [0; 512];
-    if (flags_6 >> 2) & 1 == 1 {
-        for i in offset..offset + 512 {
-            trainer[i - offset] = bytes[i];
-        }
+    let trainer = if has_trainer {
         offset += 512;
-    }
+        Some(bytes[16..offset].to_vec())
+    } else {
+        None
+    };
 
     // then read the prg rom.
     let mut prg_rom = Vec::new();
@@ -85,10 +92,117 @@ pub fn from_bytes(rom_name: String, bytes: Vec<u8>) -> Result<INesFile, String>
         flags_7,
         flags_9,
         flags_10,
+        flags_12,
         rom_name,
+        trainer,
     })
 }
 
+const HEADER_SIZE: usize = 16;
+
+/// TV system the game was made for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+    /// Works with both.
+    Dual,
+}
+
+impl TvSystem {
+    /// Timing to emulate for this game. Dual games use NTSC.
+    pub fn region(self) -> Region {
+        match self {
+            TvSystem::Pal => Region::Pal,
+            TvSystem::Ntsc | TvSystem::Dual => Region::Ntsc,
+        }
+    }
+}
+
+/// Timing of the console.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    /// Scanlines per frame, including vblank and the pre-render line.
+    pub fn scanlines(self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            // 50 more lines of vblank.
+            Region::Pal => 312,
+        }
+    }
+}
+
+/// The 16 bytes at the start of an iNES file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub prg_rom_pages: usize, // in 16kb units
+    pub chr_rom_pages: usize, // in 8kb units
+    pub flags_6: u8,
+    pub flags_7: u8,
+    pub prg_ram_size: usize,
+    pub flags_9: u8,
+    pub flags_10: u8,
+    /// CPU/PPU timing of NES 2.0 headers.
+    pub flags_12: u8,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Result<Header, NesError> {
+        // Check the header is big enough. Expecting 16 bytes.
+        if bytes.len() < HEADER_SIZE {
+            return Err(NesError::RomParse(format!(
+                "ROM size is to short. Expected 16 bytes, got {}",
+                bytes.len()
+            )));
+        }
+
+        // First 4 bytes are "NES" + MS-DOS EOF
+        let expected_bytes = [0x4E, 0x45, 0x53, 0x1A];
+        if expected_bytes[..] != bytes[0..4] {
+            return Err(NesError::RomParse(String::from(
+                "ROM 4 first bytes are not $4E $45 $53 $1A",
+            )));
+        }
+
+        let header = Header {
+            prg_rom_pages: bytes[4] as usize,
+            chr_rom_pages: bytes[5] as usize,
+            flags_6: bytes[6],
+            flags_7: bytes[7],
+            prg_ram_size: bytes[8] as usize,
+            flags_9: bytes[9],
+            flags_10: bytes[10],
+            flags_12: bytes[12],
+        };
+
+        if header.prg_rom_pages == 0 {
+            return Err(NesError::RomParse(String::from("ROM has no PRG-ROM")));
+        }
+
+        Ok(header)
+    }
+
+    pub fn mapper_id(&self) -> u8 {
+        (self.flags_6 >> 4) | (self.flags_7 & 0xF0)
+    }
+
+    pub fn has_trainer(&self) -> bool {
+        (self.flags_6 >> 2) & 1 == 1
+    }
+
+    /// Position of the PRG-ROM in the file, after the header and the
+    /// trainer.
+    pub fn prg_rom_offset(&self) -> usize {
+        HEADER_SIZE + if self.has_trainer() { 512 } else { 0 }
+    }
+}
+
 #[derive(Debug)]
 pub struct INesFile {
     // Headers
@@ -101,7 +215,28 @@ pub struct INesFile {
     prg_ram_size: usize, // in 8kb units (value 0 infers 8KB for compatibility)
     flags_9: u8,
     flags_10: u8, // unofficial
+    flags_12: u8, // NES 2.0 only
     rom_name: String,
+    // 512 bytes to load at $7000 (flag 6, bit 2)
+    trainer: Option<Vec<u8>>,
+}
+
+// Page `page_nb` of `data`, which should contain `nb_pages` pages.
+fn get_page(
+    data: &[u8],
+    page_nb: usize,
+    nb_pages: usize,
+    page_size: usize,
+) -> Result<&[u8], String> {
+    if page_nb >= nb_pages {
+        return Err(format!(
+            "Tried to access page {}, but only have {} pages",
+            page_nb, nb_pages
+        ));
+    }
+
+    data.get(page_nb * page_size..(page_nb + 1) * page_size)
+        .ok_or_else(|| format!("Page {} is truncated", page_nb))
 }
 
 impl INesFile {
@@ -127,7 +262,9 @@ impl INesFile {
             flags_7,
             flags_9,
             flags_10,
+            flags_12: 0,
             rom_name,
+            trainer: None,
         }
     }
 
@@ -135,6 +272,11 @@ impl INesFile {
         &self.rom_name
     }
 
+    /// 512 bytes that should be loaded at $7000-$71FF, if any.
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_deref()
+    }
+
     pub fn get_mapper_id(&self) -> u8 {
         let lower_nib = self.flags_6 >> 4;
         let upper_nib = self.flags_7 & 0xF0;
@@ -146,30 +288,23 @@ impl INesFile {
         self.prg_rom_pages
     }
 
+    /// 16KB page of PRG-ROM. Pages start at 0.
     pub fn get_prg_rom(&self, page_nb: usize) -> Result<&[u8], String> {
-        if page_nb > self.prg_rom_pages {
-            return Err(format!(
-                "Tried to access page {}, but only have {} pages",
-                page_nb, self.prg_rom_pages
-            ));
-        }
-
-        Ok(&self.prg_rom[(page_nb - 1) * 16 * 1024..page_nb * 16 * 1024])
+        get_page(&self.prg_rom, page_nb, self.prg_rom_pages, 16 * 1024)
     }
 
     pub fn get_chr_rom_pages(&self) -> usize {
         self.chr_rom_size
     }
 
-    pub fn get_chr_rom(&self, page_nb: usize) -> Result<&[u8], String> {
-        if page_nb > self.chr_rom_size {
-            return Err(format!(
-                "Tried to access page {}, but only have {} pages",
-                page_nb, self.chr_rom_size
-            ));
-        }
+    /// No CHR-ROM means the pattern tables are in a RAM on the cartridge.
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_rom_size == 0
+    }
 
-        Ok(&self.chr_rom[(page_nb - 1) * 8 * 1024..page_nb * 8 * 1024])
+    /// 8KB page of CHR-ROM. Pages start at 0.
+    pub fn get_chr_rom(&self, page_nb: usize) -> Result<&[u8], String> {
+        get_page(&self.chr_rom, page_nb, self.chr_rom_size, 8 * 1024)
     }
 
     pub fn get_mirroring(&self) -> Mirroring {
@@ -179,10 +314,187 @@ impl INesFile {
             Mirroring::HORIZONTAL
         }
     }
+
+    /// NES 2.0 header (flag 7 bits 2-3 are 0b10).
+    pub fn is_nes2(&self) -> bool {
+        self.flags_7 & 0x0C == 0x08
+    }
+
+    /// Size of the PRG-RAM at $6000, in bytes. NES 2.0 headers give the
+    /// size of the RAM and of the battery-backed RAM in byte 10 (64 << n
+    /// bytes). iNES headers give it in 8KB units. When the header declares
+    /// no RAM, 8KB are assumed as many ROMs leave the field empty.
+    pub fn prg_ram_bytes(&self) -> usize {
+        let size = if self.is_nes2() {
+            let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            shift_size(self.flags_10 & 0xF) + shift_size(self.flags_10 >> 4)
+        } else {
+            self.prg_ram_size * 0x2000
+        };
+        if size == 0 {
+            0x2000
+        } else {
+            size
+        }
+    }
+
+    /// TV system from flag 9 bit 0 (PAL) and flag 10 bits 0-1 (0: NTSC,
+    /// 2: PAL, 1 or 3: dual). Flag 10 is unofficial and 0 in most ROMs, so
+    /// the PAL bit of flag 9 wins over a NTSC flag 10.
+    /// NES 2.0 headers use these bytes for sizes and give the timing in
+    /// byte 12 instead (0: NTSC, 1: PAL, 2: dual, 3: Dendy).
+    pub fn tv_system(&self) -> TvSystem {
+        if self.is_nes2() {
+            return match self.flags_12 & 0b11 {
+                0 => TvSystem::Ntsc,
+                2 => TvSystem::Dual,
+                // Dendy runs at 50Hz like PAL consoles.
+                _ => TvSystem::Pal,
+            };
+        }
+
+        match self.flags_10 & 0b11 {
+            1 | 3 => TvSystem::Dual,
+            2 => TvSystem::Pal,
+            _ if self.flags_9 & 1 == 1 => TvSystem::Pal,
+            _ => TvSystem::Ntsc,
+        }
+    }
 }
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn load_normal_rom() {}
+
+    #[test]
+    fn truncated_rom() {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0; 0x4000]);
+        assert!(from_bytes(String::from("test"), bytes).is_err());
+    }
+
+    #[test]
+    fn prg_ram_size() {
+        let ines = |prg_ram_size, flags_7, flags_10| {
+            INesFile::new(
+                vec![],
+                0,
+                vec![],
+                0,
+                prg_ram_size,
+                0,
+                flags_7,
+                0,
+                flags_10,
+                String::new(),
+            )
+        };
+        // iNES, in 8KB units. 0 means 8KB.
+        assert_eq!(0x2000, ines(0, 0, 0).prg_ram_bytes());
+        assert_eq!(0x8000, ines(4, 0, 0).prg_ram_bytes());
+        // NES 2.0, RAM and battery RAM.
+        assert_eq!(0x800, ines(0, 0x08, 0x05).prg_ram_bytes());
+        assert_eq!(0x2000, ines(0, 0x08, 0x70).prg_ram_bytes());
+        assert_eq!(0x2000, ines(0, 0x08, 0).prg_ram_bytes());
+    }
+
+    #[test]
+    fn rom_without_prg() {
+        let bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(from_bytes(String::from("test"), bytes).is_err());
+    }
+
+    #[test]
+    fn header_only() {
+        // Mapper 1, 32 PRG pages, 0 CHR page, trainer. The PRG-ROM is not in
+        // the file so `read` would fail.
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 32, 0, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0; 0x1000]);
+        let path = std::env::temp_dir().join("nesemu_test_header.nes");
+        std::fs::write(&path, &bytes).unwrap();
+
+        let header = read_header(&path).unwrap();
+        assert!(read(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(1, header.mapper_id());
+        assert_eq!(32, header.prg_rom_pages);
+        assert_eq!(0, header.chr_rom_pages);
+        assert_eq!(16 + 512, header.prg_rom_offset());
+    }
+
+    #[test]
+    fn pages_start_at_0() {
+        let prg_rom = (0..3).flat_map(|i| vec![i; 0x4000]).collect();
+        let chr_rom = (0..2).flat_map(|i| vec![i; 0x2000]).collect();
+        let ines = INesFile::new(prg_rom, 3, chr_rom, 2, 0, 0, 0, 0, 0, String::from("test"));
+
+        assert_eq!(&[0; 0x4000][..], ines.get_prg_rom(0).unwrap());
+        assert_eq!(&[2; 0x4000][..], ines.get_prg_rom(2).unwrap());
+        assert!(ines.get_prg_rom(3).is_err());
+
+        assert_eq!(&[0; 0x2000][..], ines.get_chr_rom(0).unwrap());
+        assert_eq!(&[1; 0x2000][..], ines.get_chr_rom(1).unwrap());
+        assert!(ines.get_chr_rom(2).is_err());
+    }
+
+    #[test]
+    fn truncated_page() {
+        let ines = INesFile::new(
+            vec![0; 0x4000],
+            2,
+            vec![],
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            String::from("test"),
+        );
+        assert!(ines.get_prg_rom(0).is_ok());
+        assert!(ines.get_prg_rom(1).is_err());
+    }
+
+    fn rom_with_tv_flags(flags_9: u8, flags_10: u8) -> INesFile {
+        let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, flags_9, flags_10];
+        bytes.extend(vec![0; 5 + 0x4000]);
+        from_bytes(String::from("test"), bytes).unwrap()
+    }
+
+    #[test]
+    fn tv_system() {
+        assert_eq!(TvSystem::Ntsc, rom_with_tv_flags(0, 0).tv_system());
+        assert_eq!(TvSystem::Pal, rom_with_tv_flags(1, 0).tv_system());
+        assert_eq!(TvSystem::Pal, rom_with_tv_flags(0, 2).tv_system());
+        assert_eq!(TvSystem::Dual, rom_with_tv_flags(0, 1).tv_system());
+        assert_eq!(TvSystem::Dual, rom_with_tv_flags(1, 3).tv_system());
+    }
+
+    #[test]
+    fn nes2_tv_system() {
+        // 8KB of PRG-RAM in byte 10, timing in byte 12.
+        let nes2 = |flags_12: u8| {
+            let mut bytes = vec![
+                0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0x08, 0, 0, 0x07, 0, flags_12,
+            ];
+            bytes.extend(vec![0; 3 + 0x4000]);
+            from_bytes(String::from("test"), bytes).unwrap()
+        };
+        assert_eq!(0x2000, nes2(0).prg_ram_bytes());
+        assert_eq!(TvSystem::Ntsc, nes2(0).tv_system());
+        assert_eq!(TvSystem::Pal, nes2(1).tv_system());
+        assert_eq!(TvSystem::Dual, nes2(2).tv_system());
+    }
+
+    #[test]
+    fn too_short_rom() {
+        let bytes = vec![0x4E, 0x45, 0x53, 0x1A, 1];
+        match from_bytes(String::from("test"), bytes) {
+            Err(NesError::RomParse(_)) => (),
+            other => panic!("Expected RomParse, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,88 @@
+//! Playback speed (slow motion or fast forward). The front-end still runs
+//! its loop at 60Hz and asks each time how many emulation frames to run.
+
+/// Slowest speed accepted by `Speed::set_multiplier`.
+pub const MIN_SPEED: f64 = 0.05;
+/// Fastest speed accepted by `Speed::set_multiplier`.
+pub const MAX_SPEED: f64 = 16.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Speed {
+    multiplier: f64,
+    // Fraction of emulation frame not run yet.
+    remainder: f64,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self {
+            multiplier: 1.0,
+            remainder: 0.0,
+        }
+    }
+}
+
+impl Speed {
+    /// 1.0 is the normal speed, 0.5 half speed... Clamped between
+    /// `MIN_SPEED` and `MAX_SPEED`.
+    pub fn set_multiplier(&mut self, multiplier: f64) {
+        self.multiplier = if multiplier.is_nan() {
+            1.0
+        } else {
+            multiplier.clamp(MIN_SPEED, MAX_SPEED)
+        };
+        self.remainder = 0.0;
+    }
+
+    pub fn multiplier(&self) -> f64 {
+        self.multiplier
+    }
+
+    /// Called once per frame of the front-end. Return the number of
+    /// emulation frames to run, and by how much the audio produced by these
+    /// frames should be stretched to fill one frame of the front-end.
+    pub fn next_frame(&mut self) -> (u32, f64) {
+        self.remainder += self.multiplier;
+        let frames = self.remainder.floor();
+        self.remainder -= frames;
+        (frames as u32, 1.0 / self.multiplier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(multiplier: f64, wall_frames: usize) -> Vec<u32> {
+        let mut speed = Speed::default();
+        speed.set_multiplier(multiplier);
+        (0..wall_frames)
+            .map(|_| {
+                let (frames, scaling) = speed.next_frame();
+                assert_eq!(1.0 / multiplier, scaling);
+                frames
+            })
+            .collect()
+    }
+
+    #[test]
+    fn half_speed() {
+        assert_eq!(vec![0, 1, 0, 1, 0, 1], frames(0.5, 6));
+        assert_eq!(1, frames(0.25, 8).iter().take(4).sum::<u32>());
+    }
+
+    #[test]
+    fn double_speed() {
+        assert_eq!(vec![2; 6], frames(2.0, 6));
+        assert_eq!(15, frames(1.5, 10).iter().sum::<u32>());
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut speed = Speed::default();
+        speed.set_multiplier(0.0);
+        assert_eq!(MIN_SPEED, speed.multiplier());
+        speed.set_multiplier(100.0);
+        assert_eq!(MAX_SPEED, speed.multiplier());
+    }
+}
@@ -0,0 +1,134 @@
+// Compare the frames rendered by the emulator to recorded hashes, to catch
+// rendering regressions.
+//
+// `background_frame` runs a small program built here, so it always runs.
+// `dk_title_screen` needs a ROM that is not part of the repository. By
+// default, the test looks for ../games/dk.nes (same as wasm-nes). Another
+// path can be given with NES_DK_ROM. Without the ROM, the test is skipped.
+//
+// The expected hashes are in tests/golden. When rendering changes on
+// purpose, regenerate them with:
+//
+//     UPDATE_GOLDEN=1 cargo test --test frame_hash
+//
+// and commit the new files.
+extern crate nesemu;
+
+use nesemu::nes::Nes;
+use nesemu::rom::{self, INesFile};
+use std::path::PathBuf;
+
+// Enough for the title screen to be displayed.
+const NB_FRAMES: usize = 120;
+
+fn dk_rom() -> PathBuf {
+    std::env::var("NES_DK_ROM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("../games/dk.nes"))
+}
+
+// Compare the hash of the current frame with the one in `golden_file`, or
+// record it with UPDATE_GOLDEN.
+fn check_frame_hash(nes: &Nes, golden_file: &str) {
+    let hash = format!("{:016x}", nes.frame_hash());
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::create_dir_all("tests/golden").unwrap();
+        std::fs::write(golden_file, format!("{}\n", hash)).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(golden_file).unwrap_or_else(|_| {
+        panic!(
+            "No recorded hash in {}. Frame hash is {}, run with UPDATE_GOLDEN=1 to record it",
+            golden_file, hash
+        )
+    });
+    assert_eq!(expected.trim(), hash);
+}
+
+// NROM with a program that fills the palette and the first nametable, then
+// turns on the background.
+fn background_rom() -> INesFile {
+    let program = [
+        0x78, // SEI
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB, // BPL $8001
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB, // BPL $8006
+        // Palette entry X = X
+        0xA9, 0x3F, // LDA #$3F
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00, // LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA2, 0x00, // LDX #$00
+        0x8E, 0x07, 0x20, // STX $2007
+        0xE8, // INX
+        0xE0, 0x20, // CPX #$20
+        0xD0, 0xF8, // BNE $8017
+        // Tiles and attributes of $2000-$23FF = low byte of the address
+        0xA9, 0x20, // LDA #$20
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x00, // LDA #$00
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA0, 0x04, // LDY #$04
+        0xA2, 0x00, // LDX #$00
+        0x8E, 0x07, 0x20, // STX $2007
+        0xE8, // INX
+        0xD0, 0xFA, // BNE $802D
+        0x88, // DEY
+        0xD0, 0xF5, // BNE $802B
+        // No scroll, show the background
+        0xA9, 0x00, // LDA #$00
+        0x8D, 0x05, 0x20, // STA $2005
+        0x8D, 0x05, 0x20, // STA $2005
+        0x8D, 0x00, 0x20, // STA $2000
+        0xA9, 0x0A, // LDA #$0A
+        0x8D, 0x01, 0x20, // STA $2001
+        0x4C, 0x46, 0x80, // JMP $8046
+    ];
+    let mut prg_rom = vec![0; 0x4000];
+    prg_rom[..program.len()].copy_from_slice(&program);
+    // Reset vector
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+
+    let chr_rom = (0..0x2000).map(|i| ((i * 7 + i / 16) & 0xFF) as u8).collect();
+    INesFile::new(
+        prg_rom,
+        1,
+        chr_rom,
+        1,
+        0,
+        0,
+        0,
+        0,
+        0,
+        String::from("background"),
+    )
+}
+
+#[test]
+fn background_frame() {
+    let mut nes = Nes::new(background_rom()).expect("Cannot create the NES");
+    for _ in 0..10 {
+        nes.run_frame().unwrap();
+    }
+    check_frame_hash(&nes, "tests/golden/background_frame_hash.txt");
+}
+
+#[test]
+fn dk_title_screen() {
+    let path = dk_rom();
+    if !path.exists() {
+        println!("{} not found, skipping frame hash test", path.display());
+        return;
+    }
+
+    let ines = rom::read(&path).expect("Cannot read dk.nes");
+    let mut nes = Nes::new(ines).expect("Cannot create the NES");
+    for _ in 0..NB_FRAMES {
+        nes.run_frame().unwrap();
+    }
+    check_frame_hash(&nes, "tests/golden/dk_frame_hash.txt");
+}
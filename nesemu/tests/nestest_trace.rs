@@ -0,0 +1,62 @@
+// Compare the CPU with the reference log of nestest, one instruction at a
+// time. nestest runs in automation mode (PC at $C000) so it does not need
+// a screen.
+//
+// The ROM is not part of the repository. Put it in roms/nestest.nes or set
+// NESTEST_ROM to its path. Without the ROM, the test is skipped.
+//
+// The disassembly of tests/correct.log is not compared: a line is reduced
+// to the columns of `TraceEntry` (PC, opcode, registers and CYC).
+extern crate nesemu;
+
+use nesemu::cpu::trace::TraceEntry;
+use nesemu::nes::Nes;
+use nesemu::rom;
+use std::fs;
+use std::path::PathBuf;
+
+const LOG: &str = "tests/correct.log";
+// Registers start at this column in the log.
+const REGISTERS_COLUMN: usize = 48;
+
+fn rom_path() -> PathBuf {
+    std::env::var_os("NESTEST_ROM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("roms/nestest.nes"))
+}
+
+// "C000  4C F5 C5  JMP $C5F5   A:00 ... CYC:  0" -> "C000  4C  A:00 ... CYC:  0"
+fn log_columns(line: &str) -> String {
+    format!("{}  {}  {}", &line[0..4], &line[6..8], &line[REGISTERS_COLUMN..])
+}
+
+#[test]
+fn trace_matches_nestest_log() {
+    let path = rom_path();
+    if !path.exists() {
+        println!("{} not found, skip the nestest trace", path.display());
+        return;
+    }
+
+    let ines = rom::read(&path).expect("Cannot read nestest");
+    let mut nes = Nes::with_start_pc(ines, 0xC000).unwrap();
+    let log = fs::read_to_string(LOG).expect("Cannot read the nestest log");
+
+    for (nb, line) in log.lines().enumerate() {
+        let pc = nes.cpu().get_pc();
+        let opcode = nes.memory().peek(pc as usize);
+        let entry = TraceEntry::new(nes.cpu(), opcode);
+
+        let expected = log_columns(line);
+        let actual = format!("{:#}", entry);
+        assert!(
+            expected == actual,
+            "Mismatch at line {}\nexpected: {}\nactual:   {}",
+            nb + 1,
+            expected,
+            actual
+        );
+
+        nes.tick(false).unwrap();
+    }
+}
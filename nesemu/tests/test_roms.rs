@@ -0,0 +1,126 @@
+// Run the blargg test ROMs (instr_test, ppu_vbl_nmi, apu_test...).
+//
+// ROMs are not part of the repository. To run them, put the .nes files in
+// a directory and set NES_TEST_ROMS to it:
+//
+//     NES_TEST_ROMS=path/to/roms cargo test --test test_roms
+//
+// Every .nes file in the directory is executed. Without the environment variable,
+// the test is skipped.
+//
+// These ROMs report their status at $6000:
+// - $80 while the test is running,
+// - $81 when the console should be reset,
+// - otherwise the final result code (0 is success).
+// $6001-$6003 contain $DE $B0 $61 when the status is valid, and $6004 is
+// a zero-terminated ASCII message.
+extern crate nesemu;
+
+use nesemu::nes::Nes;
+use nesemu::rom;
+use std::path::{Path, PathBuf};
+
+const STATUS_ADDR: usize = 0x6000;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_ADDR: usize = 0x6004;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// Around one minute of emulated time.
+const MAX_FRAMES: usize = 60 * 60;
+// Reset should be done at least 100ms after the request.
+const RESET_DELAY_FRAMES: usize = 10;
+
+fn has_signature(nes: &mut Nes) -> bool {
+    let memory = nes.memory();
+    (0..3).all(|i| memory.peek(STATUS_ADDR + 1 + i) == SIGNATURE[i])
+}
+
+fn read_text(nes: &mut Nes) -> String {
+    let memory = nes.memory();
+    let mut text = String::new();
+    let mut addr = TEXT_ADDR;
+    while addr < 0x8000 {
+        let c = memory.peek(addr);
+        if c == 0 {
+            break;
+        }
+        text.push(c as char);
+        addr += 1;
+    }
+    text
+}
+
+/// Run the ROM until it reports a result. Return the result code and the
+/// message written by the ROM.
+fn run_test_rom(path: &Path) -> Result<(u8, String), String> {
+    let ines = rom::read(path).map_err(|err| err.to_string())?;
+    let mut nes = Nes::new(ines).map_err(|err| err.to_string())?;
+
+    let mut started = false;
+    let mut reset_in = None;
+    for _ in 0..MAX_FRAMES {
+        nes.run_frame()?;
+
+        if let Some(frames) = reset_in {
+            if frames == 0 {
+                nes.reset();
+                reset_in = None;
+            } else {
+                reset_in = Some(frames - 1);
+            }
+            continue;
+        }
+
+        if !has_signature(&mut nes) {
+            continue;
+        }
+
+        match nes.memory().peek(STATUS_ADDR) {
+            STATUS_RUNNING => started = true,
+            STATUS_RESET => reset_in = Some(RESET_DELAY_FRAMES),
+            code if started => return Ok((code, read_text(&mut nes))),
+            _ => (),
+        }
+    }
+
+    Err(format!("Timeout. Output so far: {}", read_text(&mut nes)))
+}
+
+fn test_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = std::fs::read_dir(dir)
+        .expect("Cannot read NES_TEST_ROMS directory")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|ext| ext == "nes").unwrap_or(false))
+        .collect();
+    roms.sort();
+    roms
+}
+
+#[test]
+fn blargg_test_roms() {
+    let dir = match std::env::var("NES_TEST_ROMS") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            println!("NES_TEST_ROMS is not set, skipping test ROMs");
+            return;
+        }
+    };
+
+    let mut failures = vec![];
+    for rom in test_roms(&dir) {
+        match run_test_rom(&rom) {
+            Ok((0, _)) => println!("{} passed", rom.display()),
+            Ok((code, text)) => failures.push(format!(
+                "{} failed with code {}: {}",
+                rom.display(),
+                code,
+                text
+            )),
+            Err(e) => failures.push(format!("{} error: {}", rom.display(), e)),
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -66,7 +66,7 @@ pub fn main() {
 }
 
 fn draw(ines: rom::INesFile) {
-    let chr_rom = ines.get_chr_rom(1).unwrap();
+    let chr_rom = ines.get_chr_rom(0).unwrap();
     let sprites_left: Vec<Sprite> = (0..256).map(|i| Sprite::new(&chr_rom, i)).collect();
     let sprites_right: Vec<Sprite> = (256..512).map(|i| Sprite::new(&chr_rom, i)).collect();
 
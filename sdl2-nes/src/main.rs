@@ -3,25 +3,68 @@ use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
 use sdl2::render::WindowCanvas;
 use sdl2::EventPump;
 use std::thread;
 use std::time::{Duration, Instant};
-use tracing::trace;
+use tracing::{trace, warn};
 
 use nesemu::{
-    graphic::EmulatorInput,
+    apu::queue::{self, BufferSize, FillLevel},
+    config::Config,
+    graphic::{EmulatorInput, PixelAspect},
     joypad::{InputAction, InputState, Player},
     nes::Nes,
-    ppu::palette,
-    rom,
+    perf::PerfMonitor,
+    rom::Region,
 };
 use std::collections::HashMap;
 
 // This is the NES default
 const WIDTH: u32 = 256;
 const HEIGHT: u32 = 240;
-const CPU_CYCLES_PER_FRAME: i64 = 29_780;
+const CPU_CYCLES_PER_FRAME: u64 = 29_780;
+const DEFAULT_SCALE: u32 = 3;
+
+/// Shape of the NES pixels on screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aspect {
+    /// One NES pixel is a square.
+    Square,
+    /// NTSC TVs display pixels 8/7 wider than tall.
+    Ntsc,
+}
+
+impl Aspect {
+    fn from_name(name: &str) -> Option<Aspect> {
+        match name {
+            "square" => Some(Aspect::Square),
+            "ntsc" => Some(Aspect::Ntsc),
+            _ => None,
+        }
+    }
+
+    /// Screenshots use the same aspect as the window.
+    fn pixel_aspect(self) -> PixelAspect {
+        match self {
+            Aspect::Square => PixelAspect::Square,
+            Aspect::Ntsc => PixelAspect::Ntsc,
+        }
+    }
+}
+
+/// Size (width, height) of the window and of the destination rect of the
+/// frame for an integer scale.
+fn display_size(scale: u32, aspect: Aspect) -> (u32, u32) {
+    let width = WIDTH * scale;
+    let width = match aspect {
+        Aspect::Square => width,
+        // rounded to the nearest pixel
+        Aspect::Ntsc => (width * 8 + 3) / 7,
+    };
+    (width, HEIGHT * scale)
+}
 
 fn build_default_input_p1() -> HashMap<Keycode, InputAction> {
     let mut m = HashMap::new();
@@ -38,6 +81,8 @@ fn build_default_input_p1() -> HashMap<Keycode, InputAction> {
     m
 }
 
+// Keys of player 2 must not be in the player 1 map, otherwise a key press is
+// sent to both controllers. Only map a key to both players on purpose.
 fn build_default_input_p2() -> HashMap<Keycode, InputAction> {
     let mut m = HashMap::new();
     m.insert(Keycode::I, InputAction::UP);
@@ -53,36 +98,50 @@ fn build_default_input_p2() -> HashMap<Keycode, InputAction> {
 }
 
 pub struct Graphics {
-    pub zoom_level: i32,
+    pub zoom_level: u32,
+    // Where the frame is copied in the window.
+    dst: Rect,
     //sdl_context: Sdl,
     //video_subsystem: VideoSubsystem,
     canvas: WindowCanvas,
     event_pump: EventPump,
     audio: sdl2::audio::AudioQueue<i16>,
-    colors: [nesemu::graphic::Color; 64],
+    // With --no-sound the device stays paused and nothing is queued.
+    sound: bool,
+    // Samples to keep in the audio queue, depends on the buffer size.
+    fill_level: FillLevel,
     input_map_p1: HashMap<Keycode, InputAction>,
     input_map_p2: HashMap<Keycode, InputAction>,
 }
 
 impl Graphics {
-    pub fn new(zoom_level: i32) -> Result<Graphics, String> {
+    pub fn new(
+        zoom_level: u32,
+        aspect: Aspect,
+        audio_buffer: BufferSize,
+        sound: bool,
+    ) -> Result<Graphics, String> {
         let sdl_context = sdl2::init().map_err(|err| err.to_string())?;
         let video_subsystem = sdl_context.video().map_err(|err| err.to_string())?;
         let audio_subsystem = sdl_context.audio().unwrap();
 
+        let freq = 44100;
+        let samples = audio_buffer.samples(freq as u32)?;
         let desired_specs = sdl2::audio::AudioSpecDesired {
-            freq: Some(44100),
-            samples: Some(1024),
+            freq: Some(freq),
+            samples: Some(samples as u16),
             channels: Some(1),
         };
         let audio = audio_subsystem
             .open_queue::<i16, _>(None, &desired_specs)
             .unwrap();
-        audio.resume();
+        if sound {
+            audio.resume();
+        }
 
-        let width = WIDTH * (zoom_level as u32); //*2;
+        let (width, height) = display_size(zoom_level, aspect);
         let window = video_subsystem
-            .window("NES emulator", width, HEIGHT * (zoom_level as u32))
+            .window("NES emulator", width, height)
             .position_centered()
             .opengl()
             .build()
@@ -101,10 +160,12 @@ impl Graphics {
 
         Ok(Graphics {
             zoom_level,
+            dst: Rect::new(0, 0, width, height),
             canvas,
             audio,
+            sound,
+            fill_level: FillLevel::for_buffer(samples),
             event_pump,
-            colors: palette::build_default_colors(),
             input_map_p1: build_default_input_p1(),
             input_map_p2: build_default_input_p2(),
         })
@@ -143,6 +204,14 @@ impl Graphics {
                     keycode: Some(Keycode::F2),
                     ..
                 } => emu_events.push(EmulatorInput::SAVE),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => emu_events.push(EmulatorInput::RESET),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => emu_events.push(EmulatorInput::SCREENSHOT),
 
                 // NES INPUT
                 Event::KeyDown {
@@ -193,24 +262,98 @@ impl Graphics {
     }
 }
 
-fn run_rom(path: String) {
-    let ines = rom::read(path).unwrap();
-    let nes = Nes::new(ines).unwrap();
+// Replace the default palette if a palette file was given.
+fn apply_palette(nes: &mut Nes, palette: Option<&str>) {
+    if let Some(path) = palette {
+        if let Err(e) = nes.load_palette(path) {
+            println!("Cannot load palette {} = {}", path, e);
+        }
+    }
+}
+
+// Key names of the config file are SDL names ("Z", "Left", "Return"...).
+// Keep the default mapping if the config does not have one.
+fn key_map(
+    keys: Option<&HashMap<String, InputAction>>,
+    default: HashMap<Keycode, InputAction>,
+) -> HashMap<Keycode, InputAction> {
+    match keys {
+        Some(keys) => keys
+            .iter()
+            .filter_map(|(name, action)| match Keycode::from_name(name) {
+                Some(keycode) => Some((keycode, *action)),
+                None => {
+                    warn!("Unknown key {} in config", name);
+                    None
+                }
+            })
+            .collect(),
+        None => default,
+    }
+}
+
+// Options common to all subcommands, from the command line and the config
+// file.
+struct Options {
+    palette: Option<String>,
+    scale: u32,
+    aspect: Aspect,
+    audio_buffer: BufferSize,
+    no_sound: bool,
+    speed: f64,
+    region: Option<Region>,
+    input_map_p1: HashMap<Keycode, InputAction>,
+    input_map_p2: HashMap<Keycode, InputAction>,
+}
+
+impl Options {
+    fn graphics(&self) -> Result<Graphics, String> {
+        let mut ui = Graphics::new(self.scale, self.aspect, self.audio_buffer, !self.no_sound)?;
+        ui.input_map_p1 = self.input_map_p1.clone();
+        ui.input_map_p2 = self.input_map_p2.clone();
+        Ok(ui)
+    }
+}
+
+fn run_rom(path: String, breakpoint: Option<u16>, options: &Options) -> Result<(), String> {
+    let mut nes =
+        Nes::from_rom_file(&path).map_err(|e| format!("Cannot load ROM {} = {}", path, e))?;
+    apply_palette(&mut nes, options.palette.as_deref());
+    nes.set_pixel_aspect(options.aspect.pixel_aspect());
+    nes.set_speed(options.speed);
+    if let Some(region) = options.region {
+        nes.set_region(region);
+    }
+
+    if let Some(pc) = breakpoint {
+        nes.run_until_pc(pc)?;
+        println!("Breakpoint reached at {:04X}", pc);
+        println!("{:?}", nes.cpu());
+        nes.is_pause = true;
+    }
 
-    let ui = Graphics::new(3).unwrap();
-    main_loop(ui, nes).unwrap();
+    let ui = options.graphics()?;
+    main_loop(ui, nes)
 }
 
-fn load_state(path: String) {
-    let nes = Nes::load_state(path).unwrap();
-    let ui = Graphics::new(3).unwrap();
-    main_loop(ui, nes).unwrap();
+fn load_state(path: String, options: &Options) -> Result<(), String> {
+    let mut nes =
+        Nes::load_state(path.clone()).map_err(|e| format!("Cannot load state {} = {}", path, e))?;
+    apply_palette(&mut nes, options.palette.as_deref());
+    nes.set_pixel_aspect(options.aspect.pixel_aspect());
+    nes.set_speed(options.speed);
+    let ui = options.graphics()?;
+    main_loop(ui, nes)
 }
 
-fn main_loop(mut ui: Graphics, mut nes: Nes) -> Result<(), &'static str> {
+fn main_loop(mut ui: Graphics, mut nes: Nes) -> Result<(), String> {
+    // The device might not use the frequency we asked for.
+    nes.set_sample_rate(ui.audio.spec().freq as u32);
+
     // Fixed time stamp for input polling.
     let fixed_time_stamp = Duration::new(0, 16666667);
     let mut previous_clock = Instant::now();
+    let mut perf = PerfMonitor::new(fixed_time_stamp, 60);
     //let mut accumulator = Duration::new(0, 0);
 
     // texture to draw the pixels to the screen. Drawing pixel
@@ -232,17 +375,17 @@ fn main_loop(mut ui: Graphics, mut nes: Nes) -> Result<(), &'static str> {
             }
         })
         .unwrap();
-
+    let mut frame = vec![0; (WIDTH * HEIGHT * 3) as usize];
+    // The dimmed frame is uploaded once when pausing.
+    let mut pause_frame_ready = false;
     while nes.should_run {
-        // Update CPU and PPU (and later APU)
-        // if !is_pause {
-
-        let mut total_cycles = CPU_CYCLES_PER_FRAME;
-
+        // Update CPU, PPU and APU
         let mut now = Instant::now();
-        // hot af
-        while total_cycles > 0 {
-            total_cycles -= nes.tick(nes.is_debug)? as i64;
+        let (nb_frames, audio_scaling) = nes.frames_to_run();
+        if !nes.is_pause {
+            for _ in 0..nb_frames {
+                nes.run_cycles(CPU_CYCLES_PER_FRAME)?;
+            }
         }
         let diff = Instant::now() - now;
         now = Instant::now();
@@ -255,40 +398,56 @@ fn main_loop(mut ui: Graphics, mut nes: Nes) -> Result<(), &'static str> {
         now = Instant::now();
         trace!(msg = "Handle events", duration = ?diff);
 
-        if nes.should_display() {
+        if !nes.is_pause {
+            pause_frame_ready = false;
+        }
+
+        if nes.is_pause {
+            // Dimmed last frame while paused.
+            if !pause_frame_ready {
+                for (rgb, (r, g, b)) in frame.chunks_exact_mut(3).zip(nes.last_frame_dimmed(0.5)) {
+                    rgb.copy_from_slice(&[r, g, b]);
+                }
+                texture
+                    .update(None, &frame, WIDTH as usize * 3)
+                    .map_err(|_| "Cannot update texture")?;
+                pause_frame_ready = true;
+            }
+
+            ui.canvas.copy(&texture, None, ui.dst).unwrap();
+            ui.canvas.present();
+        } else if nes.should_display() {
+            nes.frame_rgb(&mut frame)?;
             texture
-                .with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                    for y in 0..240usize {
-                        for x in 0..256usize {
-                            let pixel = nes.get_pixel(y, x) as usize;;
-                            let color = ui.colors[pixel];
-                            let offset = y * pitch + x * 3;
-                            buffer[offset] = color.r;
-                            buffer[offset + 1] = color.g;
-                            buffer[offset + 2] = color.b;
-                        }
-                    }
-                })
-                .unwrap();
+                .update(None, &frame, WIDTH as usize * 3)
+                .map_err(|_| "Cannot update texture")?;
 
-            ui.canvas.copy(&texture, None, None).unwrap();
+            ui.canvas.copy(&texture, None, ui.dst).unwrap();
             ui.canvas.present();
         }
 
-        // Audio.
-        let samples = nes.audio_samples();
-        ui.audio.queue(&samples);
-        trace!(samples = ?samples);
+        // Audio. Keep the queue around its target size.
+        let samples = queue::stretch(&nes.audio_samples(), audio_scaling);
+        if ui.sound {
+            let queued = ui.audio.size() / std::mem::size_of::<i16>() as u32;
+            let samples = queue::apply(&samples, ui.fill_level.correction(queued));
+            ui.audio.queue(&samples);
+            trace!(samples = ?samples);
+        }
         trace!(apu = ?nes.memory().apu_mem);
 
         let diff = Instant::now() - now;
         trace!(msg = "Display", duration = ?diff);
         let dt = Instant::now() - previous_clock;
 
+        let was_over_budget = perf.is_over_budget();
+        perf.record(dt);
+        if perf.is_over_budget() && !was_over_budget {
+            warn!(msg = "Frames over budget", average = ?perf.average(), p99 = ?perf.p99());
+        }
+
         if dt < fixed_time_stamp {
             thread::sleep(fixed_time_stamp - dt);
-        } else {
-            println!("{:?}", dt);
         }
 
         previous_clock = Instant::now();
@@ -300,6 +459,48 @@ fn main_loop(mut ui: Graphics, mut nes: Nes) -> Result<(), &'static str> {
 fn main() {
     let matches = App::new("My Super Program")
         .version("1.0")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Path of a JSON config file. Command line options win over it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("palette")
+                .long("palette")
+                .help("Path of a .pal file (64 RGB colors)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .help("Size of a NES pixel on screen (default 3)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("aspect")
+                .long("aspect")
+                .help("Pixel aspect ratio. ntsc makes pixels 8/7 wider (default square)")
+                .possible_values(&["square", "ntsc"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audio-buffer")
+                .long("audio-buffer")
+                .help("Audio buffer in samples (1024) or milliseconds (20ms). Bigger adds latency")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-sound")
+                .long("no-sound")
+                .help("Do not play sound"),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .help("Playback speed. 0.25 for slow motion, 2 for fast forward (default 1.0)")
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("run")
                 .about("Run emulator with ROM file")
@@ -309,6 +510,12 @@ fn main() {
                         .help("Path of the ROM file")
                         .required(true)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("break")
+                        .long("break")
+                        .help("Run until PC reaches this address (hex) then pause")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
@@ -328,13 +535,75 @@ fn main() {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .finish();
     tracing::subscriber::set_global_default(sub).unwrap();
-    if let Some(matches) = matches.subcommand_matches("run") {
+    let file_config = matches
+        .value_of("config")
+        .map(|path| Config::load(path).expect("Cannot read config file"))
+        .unwrap_or_default();
+    let cli_config = Config {
+        palette: matches.value_of("palette").map(String::from),
+        scale: matches
+            .value_of("scale")
+            .map(|scale| scale.parse().expect("Scale should be a positive integer")),
+        audio_buffer: matches.value_of("audio-buffer").map(String::from),
+        no_sound: if matches.is_present("no-sound") {
+            Some(true)
+        } else {
+            None
+        },
+        speed: matches
+            .value_of("speed")
+            .map(|speed| speed.parse().expect("Speed should be a number")),
+        ..Config::default()
+    };
+    let config = file_config.merge(cli_config);
+    let options = Options {
+        scale: config.scale.unwrap_or(DEFAULT_SCALE).max(1),
+        aspect: matches
+            .value_of("aspect")
+            .and_then(Aspect::from_name)
+            .unwrap_or(Aspect::Square),
+        audio_buffer: config
+            .buffer_size()
+            .expect("Audio buffer should be like 1024 or 20ms"),
+        no_sound: config.no_sound.unwrap_or(false),
+        speed: config.speed.unwrap_or(1.0),
+        region: config.region,
+        input_map_p1: key_map(config.keys_p1.as_ref(), build_default_input_p1()),
+        input_map_p2: key_map(config.keys_p2.as_ref(), build_default_input_p2()),
+        palette: config.palette,
+    };
+    let result = if let Some(matches) = matches.subcommand_matches("run") {
         let rom_path = matches.value_of("input").unwrap();
-        run_rom(rom_path.to_string());
+        let breakpoint = matches.value_of("break").map(|pc| {
+            let pc = pc.trim_start_matches("0x").trim_start_matches('$');
+            u16::from_str_radix(pc, 16).expect("Breakpoint should be a hex address")
+        });
+        run_rom(rom_path.to_string(), breakpoint, &options)
     } else if let Some(matches) = matches.subcommand_matches("load") {
         let state_path = matches.value_of("input").unwrap();
-        load_state(state_path.to_string());
+        load_state(state_path.to_string(), &options)
     } else {
         panic!("Should use run or load subcommand");
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_display_size() {
+        assert_eq!((768, 720), display_size(3, Aspect::Square));
+    }
+
+    #[test]
+    fn ntsc_display_size() {
+        // 768 * 8 / 7 = 877.7
+        assert_eq!((878, 720), display_size(3, Aspect::Ntsc));
+        assert_eq!((293, 240), display_size(1, Aspect::Ntsc));
     }
 }
@@ -2,7 +2,6 @@ mod utils;
 
 use nesemu::graphic::EmulatorInput;
 use nesemu::nes::Nes;
-use nesemu::rom;
 use std::time::{Duration, Instant};
 use wasm_bindgen::prelude::*;
 
@@ -57,13 +56,20 @@ const DKKONG: &'static [u8] = include_bytes!("../../games/dk.nes");
 
 #[wasm_bindgen]
 impl NesEmulator {
+    /// Emulator with Donkey Kong loaded.
     pub fn new() -> NesEmulator {
-        let ines = rom::from_bytes("DKKONG".to_owned(), Vec::from(DKKONG)).unwrap();
         NesEmulator {
-            inner: Nes::new(ines).unwrap(),
+            inner: Nes::from_rom_bytes("DKKONG", Vec::from(DKKONG)).unwrap(),
         }
     }
 
+    /// Load the content of a .nes file (from a file upload for example).
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<NesEmulator, JsValue> {
+        let inner =
+            Nes::from_rom_bytes("rom", bytes).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(NesEmulator { inner })
+    }
+
     /// If true, the main loop should continue
     pub fn should_run(&self) -> bool {
         self.inner.should_run
@@ -92,6 +98,40 @@ impl NesEmulator {
         self.inner.height()
     }
 
+    /// Snapshot of the emulator state. Can be restored with `load_state`.
+    pub fn save_state(&self) -> Result<Vec<u8>, JsValue> {
+        self.inner
+            .serialize_state()
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// Restore a snapshot created by `save_state`.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+        // Keep the rate of the AudioContext, the state may come from
+        // another one.
+        let sample_rate = self.inner.sample_rate();
+        self.inner =
+            Nes::deserialize_state(state).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.inner.set_sample_rate(sample_rate);
+        Ok(())
+    }
+
+    /// Drain the audio samples produced since the last call. Samples are
+    /// mono, at `sample_rate()` Hz.
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        self.inner.audio_samples()
+    }
+
+    /// Sample rate of `audio_samples`, to configure the AudioContext.
+    pub fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    /// Use the sample rate of the AudioContext.
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.inner.set_sample_rate(hz);
+    }
+
     pub fn run_bunch_of_ticks(&mut self) {
         for _ in 0..29780 {
             self.tick();
@@ -104,6 +144,17 @@ impl NesEmulator {
         log(&format!("{:?}", self.inner.cpu()));
     }
 
+    /// Keep the last `capacity` instructions for `recent_trace`. 0 disables
+    /// the trace.
+    pub fn set_trace_capacity(&mut self, capacity: usize) {
+        self.inner.set_trace_capacity(capacity);
+    }
+
+    /// Last instructions executed, one per line, oldest first.
+    pub fn recent_trace(&self) -> String {
+        self.inner.recent_trace().join("\n")
+    }
+
     pub fn get_pixel(&self, row: i32, col: i32) -> Color {
         let c = self.inner.get_pixel(row, col);
         Color(c.0, c.1, c.2)